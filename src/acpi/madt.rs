@@ -10,7 +10,7 @@ use super::{find_sdt, load_table, get_sdt_signature};
 use core::intrinsics::{atomic_load, atomic_store};
 use core::sync::atomic::Ordering;
 
-use crate::device::local_apic::LOCAL_APIC;
+use crate::device::local_apic::{self, LOCAL_APIC};
 use crate::interrupt;
 use crate::start::{kstart_ap, CPU_COUNT, AP_READY};
 
@@ -75,8 +75,9 @@ impl Madt {
                             println!("        This is my local APIC");
                         } else {
                             if ap_local_apic.flags & 1 == 1 {
-                                // Increase CPU ID
-                                CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+                                // The logical CPU id is the next dense index, whatever the APIC id is
+                                let cpu_id = CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+                                local_apic::set_apic_id(cpu_id, ap_local_apic.id as u32);
 
                                 // Allocate a stack
                                 let stack_start = allocate_frames(64).expect("no more frames in acpi stack_start").start_address().get() + crate::KERNEL_OFFSET;
@@ -91,14 +92,14 @@ impl Madt {
 
                                 // Set the ap_ready to 0, volatile
                                 unsafe { atomic_store(ap_ready, 0) };
-                                unsafe { atomic_store(ap_cpu_id, ap_local_apic.id as u64) };
+                                unsafe { atomic_store(ap_cpu_id, cpu_id as u64) };
                                 unsafe { atomic_store(ap_page_table, active_table.address() as u64) };
                                 unsafe { atomic_store(ap_stack_start, stack_start as u64) };
                                 unsafe { atomic_store(ap_stack_end, stack_end as u64) };
                                 unsafe { atomic_store(ap_code, kstart_ap as u64) };
                                 AP_READY.store(false, Ordering::SeqCst);
 
-                                print!("        AP {}:", ap_local_apic.id);
+                                print!("        AP {} (CPU {}):", ap_local_apic.id, cpu_id);
 
                                 // Send INIT IPI
                                 {
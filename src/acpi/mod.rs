@@ -26,6 +26,8 @@ use self::xsdt::Xsdt;
 use self::hpet::Hpet;
 use self::rxsdt::Rxsdt;
 use self::rsdp::RSDP;
+use self::slit::Slit;
+use self::srat::Srat;
 
 use self::aml::{parse_aml_table, AmlError, AmlValue};
 
@@ -39,6 +41,8 @@ mod xsdt;
 pub mod aml;
 mod rxsdt;
 mod rsdp;
+pub mod slit;
+pub mod srat;
 
 pub fn get_sdt(sdt_address: usize, active_table: &mut ActivePageTable) -> &'static Sdt {
     {
@@ -157,6 +161,8 @@ pub unsafe fn init(active_table: &mut ActivePageTable, already_supplied_rsdps: O
         Madt::init(active_table);
         Dmar::init(active_table);
         Hpet::init(active_table);
+        Srat::init();
+        Slit::init();
         init_namespace();
     } else {
         println!("NO RSDP FOUND");
@@ -253,6 +259,8 @@ pub struct Acpi {
     pub fadt: RwLock<Option<Fadt>>,
     pub namespace: RwLock<Option<BTreeMap<String, AmlValue>>>,
     pub hpet: RwLock<Option<Hpet>>,
+    pub srat: RwLock<Option<Srat>>,
+    pub slit: RwLock<Option<Slit>>,
     pub next_ctx: RwLock<u64>,
 }
 
@@ -260,5 +268,7 @@ pub static ACPI_TABLE: Acpi = Acpi {
     fadt: RwLock::new(None),
     namespace: RwLock::new(None),
     hpet: RwLock::new(None),
+    srat: RwLock::new(None),
+    slit: RwLock::new(None),
     next_ctx: RwLock::new(0),
 };
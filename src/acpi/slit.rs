@@ -0,0 +1,60 @@
+use super::sdt::Sdt;
+use super::{ACPI_TABLE, find_sdt, load_table, get_sdt_signature};
+
+/// The System Locality Information Table
+#[derive(Clone, Copy, Debug)]
+pub struct Slit {
+    sdt: &'static Sdt,
+    /// Number of system localities, the matrix has this many rows and columns
+    pub localities: u64
+}
+
+impl Slit {
+    pub fn init() {
+        let slit_sdt = find_sdt("SLIT");
+        let slit = if slit_sdt.len() == 1 {
+            load_table(get_sdt_signature(slit_sdt[0]));
+            Slit::new(slit_sdt[0])
+        } else {
+            println!("Unable to find SLIT");
+            return;
+        };
+
+        if let Some(slit) = slit {
+            println!("  SLIT: {} localities", slit.localities);
+
+            let mut slit_t = ACPI_TABLE.slit.write();
+            *slit_t = Some(slit);
+        }
+    }
+
+    pub fn new(sdt: &'static Sdt) -> Option<Slit> {
+        if &sdt.signature == b"SLIT" && sdt.data_len() >= 8 { //Not valid if no locality count
+            let localities = unsafe { (sdt.data_address() as *const u64).read_unaligned() };
+
+            // The matrix must fit in the table
+            let entries = localities.checked_mul(localities)?;
+            if entries > (sdt.data_len() - 8) as u64 {
+                return None;
+            }
+
+            Some(Slit {
+                sdt: sdt,
+                localities: localities
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Get the relative distance between two localities, 10 meaning the same locality. 255
+    /// means that one can not be reached from the other
+    pub fn distance(&self, from: u32, to: u32) -> Option<u8> {
+        let (from, to) = (from as u64, to as u64);
+        if from < self.localities && to < self.localities {
+            Some(self.sdt.data()[8 + (from * self.localities + to) as usize])
+        } else {
+            None
+        }
+    }
+}
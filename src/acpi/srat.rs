@@ -0,0 +1,171 @@
+use core::mem;
+
+use super::sdt::Sdt;
+use super::{ACPI_TABLE, find_sdt, load_table, get_sdt_signature};
+
+/// The System Resource Affinity Table
+#[derive(Clone, Copy, Debug)]
+pub struct Srat {
+    sdt: &'static Sdt
+}
+
+impl Srat {
+    pub fn init() {
+        let srat_sdt = find_sdt("SRAT");
+        let srat = if srat_sdt.len() == 1 {
+            load_table(get_sdt_signature(srat_sdt[0]));
+            Srat::new(srat_sdt[0])
+        } else {
+            println!("Unable to find SRAT");
+            return;
+        };
+
+        if let Some(srat) = srat {
+            println!("  SRAT");
+            for srat_entry in srat.iter() {
+                println!("      {:?}", srat_entry);
+            }
+
+            let mut srat_t = ACPI_TABLE.srat.write();
+            *srat_t = Some(srat);
+        }
+    }
+
+    pub fn new(sdt: &'static Sdt) -> Option<Srat> {
+        if &sdt.signature == b"SRAT" && sdt.data_len() >= 12 { //Not valid if no reserved fields
+            Some(Srat {
+                sdt: sdt
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> SratIter {
+        SratIter {
+            sdt: self.sdt,
+            i: 12 // Skip reserved fields
+        }
+    }
+}
+
+/// SRAT Processor Local APIC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct SratLocalApic {
+    /// Bits 0-7 of the proximity domain
+    pub proximity_domain_low: u8,
+    /// Local APIC ID
+    pub apic_id: u8,
+    /// Flags. 1 means that the entry is enabled
+    pub flags: u32,
+    /// Local SAPIC EID
+    pub sapic_eid: u8,
+    /// Bits 8-31 of the proximity domain
+    pub proximity_domain_high: [u8; 3],
+    /// Clock domain
+    pub clock_domain: u32
+}
+
+impl SratLocalApic {
+    pub fn proximity_domain(&self) -> u32 {
+        self.proximity_domain_low as u32
+            | (self.proximity_domain_high[0] as u32) << 8
+            | (self.proximity_domain_high[1] as u32) << 16
+            | (self.proximity_domain_high[2] as u32) << 24
+    }
+}
+
+/// SRAT Memory Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct SratMemory {
+    /// Proximity domain
+    pub proximity_domain: u32,
+    /// reserved
+    reserved: u16,
+    /// Base address
+    pub base_address: u64,
+    /// Length in bytes
+    pub length: u64,
+    /// reserved
+    reserved2: u32,
+    /// Flags. 1 means that the entry is enabled, 2 hot pluggable, 4 non-volatile
+    pub flags: u32,
+    /// reserved
+    reserved3: u64
+}
+
+/// SRAT Processor Local x2APIC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct SratX2Apic {
+    /// reserved
+    reserved: u16,
+    /// Proximity domain
+    pub proximity_domain: u32,
+    /// Local x2APIC ID
+    pub x2apic_id: u32,
+    /// Flags. 1 means that the entry is enabled
+    pub flags: u32,
+    /// Clock domain
+    pub clock_domain: u32,
+    /// reserved
+    reserved2: u32
+}
+
+/// SRAT Entries
+#[derive(Debug)]
+pub enum SratEntry {
+    LocalApic(&'static SratLocalApic),
+    InvalidLocalApic(usize),
+    Memory(&'static SratMemory),
+    InvalidMemory(usize),
+    X2Apic(&'static SratX2Apic),
+    InvalidX2Apic(usize),
+    Unknown(u8)
+}
+
+pub struct SratIter {
+    sdt: &'static Sdt,
+    i: usize
+}
+
+impl Iterator for SratIter {
+    type Item = SratEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.i + 1 < self.sdt.data_len() {
+            let entry_type = unsafe { *(self.sdt.data_address() as *const u8).add(self.i) };
+            let entry_len = unsafe { *(self.sdt.data_address() as *const u8).add(self.i + 1) } as usize;
+
+            if entry_len >= 2 && self.i + entry_len <= self.sdt.data_len() {
+                let item = match entry_type {
+                    0 => if entry_len == mem::size_of::<SratLocalApic>() + 2 {
+                        SratEntry::LocalApic(unsafe { &*((self.sdt.data_address() + self.i + 2) as *const SratLocalApic) })
+                    } else {
+                        SratEntry::InvalidLocalApic(entry_len)
+                    },
+                    1 => if entry_len == mem::size_of::<SratMemory>() + 2 {
+                        SratEntry::Memory(unsafe { &*((self.sdt.data_address() + self.i + 2) as *const SratMemory) })
+                    } else {
+                        SratEntry::InvalidMemory(entry_len)
+                    },
+                    2 => if entry_len == mem::size_of::<SratX2Apic>() + 2 {
+                        SratEntry::X2Apic(unsafe { &*((self.sdt.data_address() + self.i + 2) as *const SratX2Apic) })
+                    } else {
+                        SratEntry::InvalidX2Apic(entry_len)
+                    },
+                    _ => SratEntry::Unknown(entry_type)
+                };
+
+                self.i += entry_len;
+
+                Some(item)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
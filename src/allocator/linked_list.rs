@@ -3,8 +3,6 @@ use core::ptr::{self, NonNull};
 use linked_list_allocator::Heap;
 use spin::Mutex;
 
-use crate::paging::ActivePageTable;
-
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
 pub struct Allocator;
@@ -15,32 +13,69 @@ impl Allocator {
     }
 }
 
+/// Give a newly mapped range at the end of the heap to the allocator
+fn extend(_offset: usize, size: usize) {
+    if let Some(ref mut heap) = *HEAP.lock() {
+        unsafe { heap.extend(size); }
+    } else {
+        panic!("__rust_allocate: heap not initialized");
+    }
+}
+
+/// Get the number of bytes allocated from the heap
+pub fn used() -> usize {
+    if let Some(ref heap) = *HEAP.lock() {
+        heap.used()
+    } else {
+        panic!("kheap: heap not initialized");
+    }
+}
+
+/// Get the size of the largest block that can be allocated without growing the heap, by trying
+/// to allocate it
+pub fn largest_free() -> Option<usize> {
+    if let Some(ref mut heap) = *HEAP.lock() {
+        let (mut low, mut high) = (0, heap.free());
+        while low < high {
+            let size = low + (high - low + 1) / 2;
+            let layout = Layout::from_size_align(size, 1).ok()?;
+            match heap.allocate_first_fit(layout) {
+                Ok(allocation) => {
+                    unsafe { heap.deallocate(allocation, layout); }
+                    low = size;
+                },
+                Err(()) => high = size - 1,
+            }
+        }
+        Some(low)
+    } else {
+        panic!("kheap: heap not initialized");
+    }
+}
+
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         loop {
-            let res = if let Some(ref mut heap) = *HEAP.lock() {
-                heap.allocate_first_fit(layout)
+            let (res, free) = if let Some(ref mut heap) = *HEAP.lock() {
+                (heap.allocate_first_fit(layout), heap.free())
             } else {
                 panic!("__rust_allocate: heap not initialized");
             };
 
             match res {
                 Err(()) => {
-                    let size = if let Some(ref heap) = *HEAP.lock() {
-                        heap.size()
-                    } else {
-                        panic!("__rust_allocate: heap not initialized");
-                    };
-
-                    super::map_heap(&mut ActivePageTable::new(), crate::KERNEL_HEAP_OFFSET + size, crate::KERNEL_HEAP_SIZE);
-
-                    if let Some(ref mut heap) = *HEAP.lock() {
-                        heap.extend(crate::KERNEL_HEAP_SIZE);
-                    } else {
-                        panic!("__rust_allocate: heap not initialized");
+                    let min = layout.size() + layout.align();
+                    if ! super::grow(min, extend) && ! super::grow_killed(min, extend) {
+                        return ptr::null_mut();
+                    }
+                },
+                Ok(allocation) => {
+                    // Keep a reserve for the allocations made while growing the heap
+                    if free < crate::KERNEL_HEAP_RESERVE {
+                        super::grow(0, extend);
                     }
+                    return allocation.as_ptr();
                 },
-                other => return other.ok().map_or(ptr::null_mut(), |allocation| allocation.as_ptr()),
             }
         }
     }
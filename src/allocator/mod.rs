@@ -1,12 +1,14 @@
-use crate::paging::{ActivePageTable, Page, VirtualAddress};
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+
+use crate::paging::{ActivePageTable, Page, VirtualAddress, PAGE_SIZE};
 use crate::paging::entry::EntryFlags;
 use crate::paging::mapper::MapperFlushAll;
 
 #[cfg(not(feature="slab"))]
-pub use self::linked_list::Allocator;
+pub use self::linked_list::{Allocator, largest_free, used};
 
 #[cfg(feature="slab")]
-pub use self::slab::Allocator;
+pub use self::slab::{Allocator, largest_free, used};
 
 #[cfg(not(feature="slab"))]
 mod linked_list;
@@ -14,17 +16,130 @@ mod linked_list;
 #[cfg(feature="slab")]
 mod slab;
 
-unsafe fn map_heap(active_table: &mut ActivePageTable, offset: usize, size: usize) {
+/// Value of `GROWER` while the heap is not being grown
+const NO_CPU: usize = usize::max_value();
+
+/// Mapped size of the heap
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Size the heap may grow to
+static MAX_SIZE: AtomicUsize = AtomicUsize::new(crate::KERNEL_HEAP_MAX_SIZE);
+/// The CPU growing the heap, if any
+static GROWER: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+/// Map the pages of a heap range, returning false if there are not enough frames. Pages that
+/// were mapped before running out are unmapped again
+unsafe fn map_heap(active_table: &mut ActivePageTable, offset: usize, size: usize) -> bool {
     let mut flush_all = MapperFlushAll::new();
 
     let heap_start_page = Page::containing_address(VirtualAddress::new(offset));
     let heap_end_page = Page::containing_address(VirtualAddress::new(offset + size-1));
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        let result = active_table.map(page, EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
-        flush_all.consume(result);
+        match active_table.try_map(page, EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE) {
+            Ok(result) => flush_all.consume(result),
+            Err(_) => {
+                for mapped in Page::range_inclusive(heap_start_page, heap_end_page) {
+                    if mapped == page {
+                        break;
+                    }
+                    flush_all.consume(active_table.unmap(mapped));
+                }
+                flush_all.flush(active_table);
+                return false;
+            }
+        }
     }
 
     flush_all.flush(active_table);
+
+    true
+}
+
+/// Map at least `min` more bytes at the end of the heap, and give them to the allocator with
+/// `extend`. A `min` of zero only refills the reserve. Returns false if the heap is at its
+/// maximum size, there are not enough frames, or the heap is already being grown by this CPU,
+/// in which case the allocation has to be served from the reserve. Returns true if the
+/// allocation should be retried
+unsafe fn grow<F: FnOnce(usize, usize)>(min: usize, extend: F) -> bool {
+    grow_to(min, extend, MAX_SIZE.load(Ordering::SeqCst))
+}
+
+/// Grow the heap past its maximum size for an allocation that failed, after marking the
+/// context it was made for to be killed. The allocation then succeeds, and the context exits
+/// when it next returns to userspace, instead of the kernel having to give up on it. Returns
+/// false for kernel contexts, or if there are no frames left
+unsafe fn grow_killed<F: FnOnce(usize, usize)>(min: usize, extend: F) -> bool {
+    crate::context::oom::kill_current() && grow_to(min, extend, MAX_SIZE.load(Ordering::SeqCst) + crate::KERNEL_HEAP_RESERVE)
+}
+
+/// Grow the heap as `grow` does, up to `max_size`
+unsafe fn grow_to<F: FnOnce(usize, usize)>(min: usize, extend: F, max_size: usize) -> bool {
+    // The frame allocator allocates from the heap while it is locked, and mapping frames would
+    // wait for it. Its allocations are served from the reserve, which is refilled later, and
+    // other CPUs retry once it is unlocked
+    if crate::memory::allocator_locked_here() {
+        return false;
+    }
+    if crate::memory::allocator_locked() {
+        if min == 0 {
+            return false;
+        }
+        while crate::memory::allocator_locked() {
+            atomic::spin_loop_hint();
+        }
+        return true;
+    }
+
+    // Refilling the reserve should not run the OOM killer
+    if min == 0 && crate::memory::free_frames() < crate::KERNEL_HEAP_SIZE / PAGE_SIZE {
+        return false;
+    }
+
+    let cpu_id = crate::cpu_id();
+    match GROWER.compare_exchange(NO_CPU, cpu_id, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => (),
+        // Mapping the frames needed memory from the heap
+        Err(grower) if grower == cpu_id => return false,
+        // Another CPU is growing the heap, so retry once it is done
+        Err(_) => {
+            while GROWER.load(Ordering::SeqCst) != NO_CPU {
+                atomic::spin_loop_hint();
+            }
+            return true;
+        }
+    }
+
+    let size = SIZE.load(Ordering::SeqCst);
+    let step = crate::KERNEL_HEAP_SIZE.max((min + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE);
+    let grown = if size + step > max_size {
+        false
+    } else if map_heap(&mut ActivePageTable::new(), crate::KERNEL_HEAP_OFFSET + size, step) {
+        extend(crate::KERNEL_HEAP_OFFSET + size, step);
+        SIZE.store(size + step, Ordering::SeqCst);
+        true
+    } else {
+        false
+    };
+
+    GROWER.store(NO_CPU, Ordering::SeqCst);
+
+    grown
+}
+
+/// Mapped size of the heap
+pub fn size() -> usize {
+    SIZE.load(Ordering::SeqCst)
+}
+
+/// Size the heap may grow to
+pub fn max_size() -> usize {
+    MAX_SIZE.load(Ordering::SeqCst)
+}
+
+/// Change the size the heap may grow to. It can not be made smaller than it already is, or
+/// larger than the space reserved for it
+pub fn set_max_size(max_size: usize) {
+    let max_size = max_size.min(crate::PML4_SIZE).max(size());
+    MAX_SIZE.store(max_size, Ordering::SeqCst);
 }
 
 pub unsafe fn init(active_table: &mut ActivePageTable) {
@@ -32,7 +147,8 @@ pub unsafe fn init(active_table: &mut ActivePageTable) {
     let size = crate::KERNEL_HEAP_SIZE;
 
     // Map heap pages
-    map_heap(active_table, offset, size);
+    assert!(map_heap(active_table, offset, size), "not enough memory for the kernel heap");
+    SIZE.store(size, Ordering::SeqCst);
 
     // Initialize global heap
     Allocator::init(offset, size);
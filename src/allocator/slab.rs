@@ -1,43 +1,108 @@
-use core::alloc::{Alloc, AllocErr, Layout};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
-use slab_allocator::Heap;
+use slab_allocator::{Heap, HeapAllocator};
 
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
+/// Number of bytes allocated from the heap, which the slab allocator does not count
+static USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of slabs, counting the linked list allocator for larger allocations
+const SLABS: usize = 8;
+
+/// Bytes left in each slab. The slab allocator does not count them either, and each slab needs
+/// a reserve of its own for the allocations made while growing the heap
+static FREE: [AtomicUsize; SLABS] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+/// Get the index of the slab serving a layout, and the bytes it takes from it
+fn slab(layout: &Layout) -> (usize, usize) {
+    match Heap::layout_to_allocator(layout) {
+        HeapAllocator::Slab64Bytes => (0, 64),
+        HeapAllocator::Slab128Bytes => (1, 128),
+        HeapAllocator::Slab256Bytes => (2, 256),
+        HeapAllocator::Slab512Bytes => (3, 512),
+        HeapAllocator::Slab1024Bytes => (4, 1024),
+        HeapAllocator::Slab2048Bytes => (5, 2048),
+        HeapAllocator::Slab4096Bytes => (6, 4096),
+        HeapAllocator::LinkedListAllocator => (7, layout.size()),
+    }
+}
+
 pub struct Allocator;
 
 impl Allocator {
     pub unsafe fn init(offset: usize, size: usize) {
         *HEAP.lock() = Some(Heap::new(offset, size));
+        // The heap is split evenly between the slabs
+        for free in FREE.iter() {
+            free.store(size / SLABS, Ordering::SeqCst);
+        }
     }
 }
 
-unsafe impl<'a> Alloc for &'a Allocator {
-    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.allocate(layout)
-        } else {
-            panic!("__rust_allocate: heap not initialized");
-        }
-    }
+/// Get the number of bytes allocated from the heap
+pub fn used() -> usize {
+    USED.load(Ordering::SeqCst)
+}
 
-    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.deallocate(ptr, layout)
-        } else {
-            panic!("__rust_deallocate: heap not initialized");
-        }
-    }
+/// The slabs do not report their free blocks
+pub fn largest_free() -> Option<usize> {
+    None
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (index, slab_size) = slab(&layout);
+        // Give the new range to the slab of this layout
+        let extend = |offset, size| {
+            if let Some(ref mut heap) = *HEAP.lock() {
+                heap.grow(offset, size, Heap::layout_to_allocator(&layout));
+                FREE[index].fetch_add(size, Ordering::SeqCst);
+            } else {
+                panic!("__rust_allocate: heap not initialized");
+            }
+        };
+
+        loop {
+            let res = if let Some(ref mut heap) = *HEAP.lock() {
+                heap.allocate(layout)
+            } else {
+                panic!("__rust_allocate: heap not initialized");
+            };
 
-    fn oom(&mut self, error: AllocErr) -> ! {
-        panic!("Out of memory: {:?}", error);
+            match res {
+                Err(_) => {
+                    let min = layout.size() + layout.align();
+                    if ! super::grow(min, extend) && ! super::grow_killed(min, extend) {
+                        return ptr::null_mut();
+                    }
+                },
+                Ok(allocation) => {
+                    USED.fetch_add(layout.size(), Ordering::SeqCst);
+                    // Keep a reserve for the allocations made while growing the heap
+                    let free = FREE[index].fetch_sub(slab_size, Ordering::SeqCst).saturating_sub(slab_size);
+                    if free < crate::KERNEL_HEAP_RESERVE / SLABS {
+                        super::grow(0, extend);
+                    }
+                    return allocation.as_ptr();
+                },
+            }
+        }
     }
 
-    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         if let Some(ref mut heap) = *HEAP.lock() {
-            heap.usable_size(layout)
+            heap.deallocate(NonNull::new_unchecked(ptr), layout);
+            USED.fetch_sub(layout.size(), Ordering::SeqCst);
+            let (index, slab_size) = slab(&layout);
+            FREE[index].fetch_add(slab_size, Ordering::SeqCst);
         } else {
-            panic!("__rust_usable_size: heap not initialized");
+            panic!("__rust_deallocate: heap not initialized");
         }
     }
 }
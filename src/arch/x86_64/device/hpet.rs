@@ -10,7 +10,7 @@ static TN_INT_ENB_CNF: u64 = 0x04;
 static CAPABILITY_OFFSET: usize = 0x00;
 static GENERAL_CONFIG_OFFSET: usize = 0x10;
 // static GENERAL_INTERRUPT_OFFSET: usize = 0x20;
-// static MAIN_COUNTER_OFFSET: usize = 0xF0;
+static MAIN_COUNTER_OFFSET: usize = 0xF0;
 // static NUM_TIMER_CAP_MASK: u64 = 0x0f00;
 static LEG_RT_CAP: u64 = 0x8000;
 static T0_CONFIG_CAPABILITY_OFFSET: usize = 0x100;
@@ -18,6 +18,27 @@ static T0_COMPARATOR_OFFSET: usize = 0x108;
 
 static PER_INT_CAP: u64 = 0x10;
 
+/// Read the main counter
+pub unsafe fn counter(hpet: &Hpet) -> u64 {
+    hpet.base_address.read_u64(MAIN_COUNTER_OFFSET)
+}
+
+/// Returns true if the main counter is running
+pub unsafe fn enabled(hpet: &Hpet) -> bool {
+    hpet.base_address.read_u64(GENERAL_CONFIG_OFFSET) & ENABLE_CNF == ENABLE_CNF
+}
+
+/// Period of the main counter, in femtoseconds
+pub unsafe fn period_fs(hpet: &Hpet) -> u64 {
+    hpet.base_address.read_u64(CAPABILITY_OFFSET) >> 32
+}
+
+/// Stop the periodic interrupt of timer 0, leaving the main counter running
+pub unsafe fn stop_timer(hpet: &mut Hpet) {
+    let t0_config_word = hpet.base_address.read_u64(T0_CONFIG_CAPABILITY_OFFSET) & ! TN_INT_ENB_CNF;
+    hpet.base_address.write_u64(T0_CONFIG_CAPABILITY_OFFSET, t0_config_word);
+}
+
 pub unsafe fn init(hpet: &mut Hpet) -> bool {
     let capability = hpet.base_address.read_u64(CAPABILITY_OFFSET);
     if capability & LEG_RT_CAP == 0 {
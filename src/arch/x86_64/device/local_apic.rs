@@ -1,4 +1,6 @@
+use alloc::vec::Vec;
 use core::sync::atomic::{self, AtomicU64};
+use spin::RwLock;
 use core::intrinsics::{volatile_load, volatile_store};
 use x86::cpuid::CpuId;
 use x86::msr::*;
@@ -31,6 +33,24 @@ struct NoFreqInfo;
 
 static BSP_APIC_ID: AtomicU64 = AtomicU64::new(0xFFFF_FFFF_FFFF_FFFF);
 
+/// The APIC id of each CPU, indexed by its logical CPU id
+static APIC_IDS: RwLock<Vec<u32>> = RwLock::new(Vec::new());
+
+/// Record the APIC id of the CPU with logical id `cpu_id`. CPUs are numbered densely from zero,
+/// as the APIC ids may have gaps
+pub fn set_apic_id(cpu_id: usize, apic_id: u32) {
+    let mut apic_ids = APIC_IDS.write();
+    if apic_ids.len() <= cpu_id {
+        apic_ids.resize(cpu_id + 1, u32::max_value());
+    }
+    apic_ids[cpu_id] = apic_id;
+}
+
+/// Get the APIC id of the CPU with logical id `cpu_id`
+pub fn apic_id(cpu_id: usize) -> Option<u32> {
+    APIC_IDS.read().get(cpu_id).cloned().filter(|&apic_id| apic_id != u32::max_value())
+}
+
 #[no_mangle]
 pub fn bsp_apic_id() -> Option<u32> {
     let value = BSP_APIC_ID.load(atomic::Ordering::SeqCst);
@@ -55,6 +75,7 @@ impl LocalApic {
 
         self.init_ap();
         BSP_APIC_ID.store(u64::from(self.id()), atomic::Ordering::SeqCst);
+        set_apic_id(0, self.id());
     }
 
     unsafe fn init_ap(&mut self) {
@@ -207,6 +228,20 @@ impl LocalApic {
             self.write(0x370, lvt_error);
         }
     }
+    /// Arm the timer to raise `vector` once, after `count` ticks of the timer clock divided by
+    /// 16. A count of zero stops the timer
+    pub unsafe fn set_oneshot(&mut self, vector: u8, count: u32) {
+        self.set_div_conf(0b0011);
+        self.set_lvt_timer(u32::from(vector) | (LvtTimerMode::OneShot as u32) << 17);
+        self.set_init_count(count);
+    }
+    /// Start the timer counting down from `u32::max_value()` without raising an interrupt, so
+    /// that its rate can be measured with `cur_count`
+    pub unsafe fn start_masked_count(&mut self) {
+        self.set_div_conf(0b0011);
+        self.set_lvt_timer(1 << 16 | (LvtTimerMode::OneShot as u32) << 17);
+        self.set_init_count(u32::max_value());
+    }
     unsafe fn setup_error_int(&mut self) {
         let vector = 49u32;
         self.set_lvt_error(vector);
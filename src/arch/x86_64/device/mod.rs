@@ -7,6 +7,8 @@ pub mod pic;
 pub mod pit;
 pub mod rtc;
 pub mod serial;
+pub mod tickless;
+pub mod tsc;
 #[cfg(feature = "acpi")]
 pub mod hpet;
 #[cfg(feature = "system76_ec_debug")]
@@ -37,14 +39,33 @@ unsafe fn init_hpet() -> bool {
 }
 
 pub unsafe fn init_noncore() {
-    if ! init_hpet() {
+    let hpet = init_hpet();
+    if ! hpet {
         pit::init();
     }
 
+    tsc::init(hpet);
+
     rtc::init();
     serial::init();
 }
 
+/// Stop the periodic timer interrupt of the BSP, raised by the HPET or the PIT
+pub unsafe fn stop_tick() {
+    #[cfg(feature = "acpi")]
+    {
+        use crate::acpi::ACPI_TABLE;
+        if let Some(ref mut hpet) = *ACPI_TABLE.hpet.write() {
+            if hpet::enabled(hpet) {
+                hpet::stop_timer(hpet);
+                return;
+            }
+        }
+    }
+
+    pit::stop();
+}
+
 pub unsafe fn init_ap() {
     local_apic::init_ap();
 }
@@ -10,6 +10,9 @@ static LOHI: u8 = 0x30;
 
 static CHAN0_DIVISOR: u16 = 2685;
 
+/// Nanoseconds between two PIT interrupts
+pub const RATE: u64 = 2_250_286;
+
 pub unsafe fn init() {
     COMMAND.write(SELECT_CHAN0 | LOHI | 5);
     CHAN0.write((CHAN0_DIVISOR & 0xFF) as u8);
@@ -17,3 +20,11 @@ pub unsafe fn init() {
 
     println!("Using PIT");
 }
+
+/// Stop the periodic interrupt. The channel is switched to one-shot mode, so it raises at most
+/// one more interrupt
+pub unsafe fn stop() {
+    COMMAND.write(SELECT_CHAN0 | LOHI);
+    CHAN0.write(0);
+    CHAN0.write(0);
+}
@@ -0,0 +1,190 @@
+//! Tickless operation
+//!
+//! The BSP starts with the periodic PIT or HPET interrupt, as it advances `time::OFFSET`. Once
+//! the local APIC timer has been calibrated against it, the BSP stops forwarding that tick to
+//! the other CPUs. Each application processor instead arms its local APIC timer one-shot, for
+//! the end of the time slice of the running context, or earlier for the next wake time of its
+//! sleeping contexts or the next `time:` timeout, which it triggers itself. An idle CPU with
+//! nothing to wake up for halts until it receives an IPI.
+//!
+//! Once time is also read from the TSC, the BSP stops the periodic interrupt and runs the same
+//! way. Without an invariant TSC it keeps the periodic interrupt, and does not halt for longer
+//! than a tick.
+//!
+//! `itimer:` does not arm any timers yet, so there are no itimer deadlines to wake up for. When
+//! it does, it has to register them with `context::timeout`, which is included here.
+
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::context::run_queue::run_queue;
+use crate::context::timeout;
+use crate::device::local_apic::LOCAL_APIC;
+use crate::device::pit;
+use crate::time;
+
+/// Interrupt vector of the local APIC timer
+const TIMER_VECTOR: u8 = 48;
+
+/// Number of PIT ticks used to calibrate the local APIC timer
+const CALIBRATION_TICKS: usize = 10;
+
+/// Local APIC timer counts per PIT tick, zero until calibrated
+static COUNTS_PER_TICK: AtomicU32 = AtomicU32::new(0);
+
+/// Number of PIT ticks seen since calibration started
+static CALIBRATION: AtomicUsize = AtomicUsize::new(0);
+
+/// The BSP stopped the periodic interrupt, and is driven by its local APIC timer
+static BSP_TICKLESS: AtomicBool = AtomicBool::new(false);
+
+/// Number of CPUs tracked in `IDLE_MASKS`, all those a local APIC can address
+const IDLE_MASK_CPUS: usize = 256;
+
+/// The CPUs halted in their idle loop that have not been sent a wakeup IPI yet
+static IDLE_MASKS: [AtomicU64; IDLE_MASK_CPUS / 64] = [
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+];
+
+/// Number of PIT ticks the local APIC timer of this CPU was armed for
+#[thread_local]
+static ARMED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Calibrate the local APIC timer, called by the BSP on every PIT tick
+pub unsafe fn calibrate() {
+    if ! cfg!(feature = "tickless") || COUNTS_PER_TICK.load(Ordering::SeqCst) != 0 {
+        return;
+    }
+
+    let tick = CALIBRATION.fetch_add(1, Ordering::SeqCst);
+    if tick == 0 {
+        LOCAL_APIC.start_masked_count();
+    } else if tick == CALIBRATION_TICKS {
+        let elapsed = u32::max_value() - LOCAL_APIC.cur_count();
+        LOCAL_APIC.set_init_count(0);
+
+        let counts = cmp::max(1, elapsed / CALIBRATION_TICKS as u32);
+        println!("Tickless: {} local APIC timer counts per tick", counts);
+        COUNTS_PER_TICK.store(counts, Ordering::SeqCst);
+    }
+}
+
+/// Returns true once the application processors no longer depend on the forwarded PIT tick
+pub fn active() -> bool {
+    cfg!(feature = "tickless") && COUNTS_PER_TICK.load(Ordering::SeqCst) != 0
+}
+
+/// Returns true if the current CPU is driven by its local APIC timer
+pub fn enabled() -> bool {
+    active() && (crate::cpu_id() != 0 || BSP_TICKLESS.load(Ordering::SeqCst))
+}
+
+/// Stop the periodic interrupt once the local APIC timer is calibrated and time no longer
+/// depends on it, called by the BSP on every PIT tick. Returns true if the BSP just became
+/// tickless, and has to arm its local APIC timer
+pub unsafe fn enter_bsp() -> bool {
+    if ! active() || ! time::has_clock_source() || BSP_TICKLESS.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    crate::device::stop_tick();
+    BSP_TICKLESS.store(true, Ordering::SeqCst);
+    true
+}
+
+/// Arm the local APIC timer of this CPU to fire after `ticks` PIT ticks
+pub unsafe fn arm(ticks: u64) {
+    let ticks = cmp::max(1, ticks);
+    let counts = cmp::min(ticks * u64::from(COUNTS_PER_TICK.load(Ordering::SeqCst)), u64::from(u32::max_value()));
+    ARMED_TICKS.store(ticks, Ordering::SeqCst);
+    LOCAL_APIC.set_oneshot(TIMER_VECTOR, counts as u32);
+}
+
+/// Make the local APIC timer of this CPU fire as soon as interrupts are enabled
+pub unsafe fn kick() {
+    if enabled() {
+        ARMED_TICKS.store(0, Ordering::SeqCst);
+        LOCAL_APIC.set_oneshot(TIMER_VECTOR, 1);
+    }
+}
+
+/// Stop the local APIC timer of this CPU
+pub unsafe fn disarm() {
+    ARMED_TICKS.store(0, Ordering::SeqCst);
+    LOCAL_APIC.set_init_count(0);
+}
+
+/// Mark this CPU as halted in its idle loop, or as running again
+pub fn set_idle(idle: bool) {
+    let cpu_id = crate::cpu_id();
+    if cpu_id >= IDLE_MASK_CPUS {
+        return;
+    }
+
+    let bit = 1 << (cpu_id % 64);
+    if idle {
+        IDLE_MASKS[cpu_id / 64].fetch_or(bit, Ordering::SeqCst);
+    } else {
+        IDLE_MASKS[cpu_id / 64].fetch_and(! bit, Ordering::SeqCst);
+    }
+}
+
+/// Take a CPU other than this one that is halted in its idle loop, to be sent a wakeup IPI. It
+/// is not returned again until it halts again, so that it is sent only one
+pub fn claim_idle_cpu() -> Option<usize> {
+    let cpu_id = crate::cpu_id();
+    for (word, mask) in IDLE_MASKS.iter().enumerate() {
+        let mut bits = mask.load(Ordering::SeqCst);
+        while bits != 0 {
+            let bit = 1 << bits.trailing_zeros();
+            bits &= ! bit;
+
+            let other = word * 64 + bit.trailing_zeros() as usize;
+            if other != cpu_id && mask.fetch_and(! bit, Ordering::SeqCst) & bit != 0 {
+                return Some(other);
+            }
+        }
+    }
+    None
+}
+
+/// Take the number of PIT ticks that the expired timer was armed for
+pub fn expired_ticks() -> u64 {
+    ARMED_TICKS.swap(0, Ordering::SeqCst)
+}
+
+/// Get the number of PIT ticks until this CPU has to wake up: for the next wake time of its
+/// sleeping contexts, or for the next `time:` timeout
+fn next_deadline_ticks() -> Option<u64> {
+    let next_wake = run_queue(crate::cpu_id()).next_wake();
+    let deadline = match (next_wake, timeout::next_deadline()) {
+        (Some(wake), Some(timeout)) => Some(cmp::min(wake, timeout)),
+        (wake, timeout) => wake.or(timeout),
+    }?;
+
+    let now = time::monotonic();
+    let now_ns = u128::from(now.0) * 1_000_000_000 + u128::from(now.1);
+    let deadline_ns = u128::from(deadline.0) * 1_000_000_000 + u128::from(deadline.1);
+    let delta = deadline_ns.saturating_sub(now_ns);
+    let ticks = (delta + u128::from(pit::RATE) - 1) / u128::from(pit::RATE);
+    Some(cmp::min(ticks, u128::from(u64::max_value())) as u64)
+}
+
+/// Arm the timer of this CPU for the end of a time slice of `ticks` PIT ticks, or for the next
+/// deadline if it is sooner
+pub unsafe fn arm_slice(ticks: u64) {
+    arm(next_deadline_ticks().map_or(ticks, |deadline| cmp::min(ticks, deadline)));
+}
+
+/// Arm the timer of an idle CPU for the next deadline, or stop it if there is nothing to wake
+/// up for
+pub unsafe fn idle() {
+    if ! enabled() {
+        return;
+    }
+
+    match next_deadline_ticks() {
+        Some(ticks) => arm(ticks),
+        None => disarm(),
+    }
+}
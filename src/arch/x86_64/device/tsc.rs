@@ -0,0 +1,121 @@
+//! Time stamp counter clock source
+//!
+//! An invariant TSC runs at a constant rate regardless of power states, so once its frequency
+//! is known it gives nanosecond resolution time without waiting for timer ticks. It is
+//! calibrated against the HPET main counter at boot if there is one, or otherwise against the
+//! PIT interrupt.
+
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86::cpuid::CpuId;
+
+use crate::device::pit;
+use crate::time;
+
+/// Number of PIT ticks used to calibrate the TSC, if there is no HPET
+const CALIBRATION_TICKS: usize = 50;
+
+/// Number of HPET counter reads after which calibration gives up, if the counter does not move
+const CALIBRATION_SPINS: usize = 100_000_000;
+
+/// The TSC is invariant and waiting to be calibrated by the PIT interrupt
+static PIT_CALIBRATION: AtomicBool = AtomicBool::new(false);
+
+/// Number of PIT ticks seen since calibration started
+static CALIBRATION: AtomicUsize = AtomicUsize::new(0);
+
+/// TSC value at the first PIT tick of the calibration
+static CALIBRATION_START: AtomicU64 = AtomicU64::new(0);
+
+fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Returns true if the TSC runs at a constant rate in all power states
+pub fn invariant() -> bool {
+    CpuId::new().get_extended_function_info().map_or(false, |info| info.has_invariant_tsc())
+}
+
+fn install(frequency: u64) {
+    println!("TSC: {} MHz", frequency / 1_000_000);
+    time::set_clock_source(read, frequency);
+}
+
+/// Calibrate the TSC against the HPET main counter by busy waiting for 10 milliseconds
+#[cfg(feature = "acpi")]
+unsafe fn calibrate_hpet() -> Option<u64> {
+    use crate::acpi::ACPI_TABLE;
+    use crate::device::hpet;
+
+    let hpet_lock = ACPI_TABLE.hpet.read();
+    let hpet = hpet_lock.as_ref()?;
+    if ! hpet::enabled(hpet) {
+        return None;
+    }
+
+    let period_fs = hpet::period_fs(hpet);
+    if period_fs == 0 {
+        return None;
+    }
+    let wait_counts = 10_000_000_000_000 / period_fs;
+
+    let hpet_start = hpet::counter(hpet);
+    let tsc_start = read();
+    let mut hpet_end = hpet_start;
+    let mut spins = 0;
+    while hpet_end.wrapping_sub(hpet_start) < wait_counts {
+        if spins >= CALIBRATION_SPINS {
+            println!("TSC: HPET counter is not running");
+            return None;
+        }
+        spins += 1;
+        hpet_end = hpet::counter(hpet);
+    }
+    let tsc_end = read();
+
+    let elapsed_fs = u128::from(hpet_end.wrapping_sub(hpet_start)) * u128::from(period_fs);
+    if elapsed_fs == 0 {
+        return None;
+    }
+    Some((u128::from(tsc_end - tsc_start) * 1_000_000_000_000_000 / elapsed_fs) as u64)
+}
+
+#[cfg(not(feature = "acpi"))]
+unsafe fn calibrate_hpet() -> Option<u64> {
+    None
+}
+
+/// Use the TSC as clock source if it is invariant. `hpet` is true if the HPET was initialized,
+/// in which case it is used for calibration. Otherwise, or if the HPET counter does not run,
+/// the TSC is calibrated against the timer interrupt
+pub unsafe fn init(hpet: bool) {
+    if ! invariant() {
+        println!("TSC: not invariant, using timer ticks");
+        return;
+    }
+
+    let frequency = if hpet { calibrate_hpet() } else { None };
+    match frequency {
+        Some(frequency) => install(frequency),
+        None => PIT_CALIBRATION.store(true, Ordering::SeqCst),
+    }
+}
+
+/// Calibrate the TSC against the PIT, called by the BSP on every PIT tick. The HPET in legacy
+/// replacement mode raises the same interrupt at the same rate
+pub unsafe fn calibrate() {
+    if ! PIT_CALIBRATION.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let tick = CALIBRATION.fetch_add(1, Ordering::SeqCst);
+    if tick == 0 {
+        CALIBRATION_START.store(read(), Ordering::SeqCst);
+    } else if tick == CALIBRATION_TICKS {
+        PIT_CALIBRATION.store(false, Ordering::SeqCst);
+
+        let elapsed = read() - CALIBRATION_START.load(Ordering::SeqCst);
+        let elapsed_ns = CALIBRATION_TICKS as u128 * u128::from(pit::RATE);
+        install((u128::from(elapsed) * 1_000_000_000 / elapsed_ns) as u64);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{
+    context::memory,
     interrupt::stack_trace,
+    paging::VirtualAddress,
     ptrace,
     syscall::flag::*,
 
@@ -133,6 +135,22 @@ interrupt_error!(protection, |stack| {
 interrupt_error!(page, |stack| {
     let cr2: usize;
     asm!("mov {}, cr2", out(reg) cr2);
+
+    // An access to a missing page may be the first access to a lazily allocated page
+    if stack.code & 0b1 == 0 && memory::lazy_fault(VirtualAddress::new(cr2)) {
+        return;
+    }
+
+    // Or an access to a page that was swapped out
+    if stack.code & 0b1 == 0 && memory::swap_fault(VirtualAddress::new(cr2)) {
+        return;
+    }
+
+    // A write to a present page may be a write to a copy-on-write page
+    if stack.code & 0b11 == 0b11 && memory::cow_fault(VirtualAddress::new(cr2)) {
+        return;
+    }
+
     println!("Page fault: {:>016X}", cr2);
     stack.dump();
     stack_trace();
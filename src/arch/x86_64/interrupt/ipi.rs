@@ -1,9 +1,7 @@
-use core::sync::atomic::Ordering;
-use x86::tlb;
-
+use crate::arch::x86_64::pcid;
 use crate::context;
 use crate::device::local_apic::LOCAL_APIC;
-use super::irq::PIT_TICKS;
+use super::irq;
 
 interrupt!(wakeup, || {
     LOCAL_APIC.eoi();
@@ -12,7 +10,8 @@ interrupt!(wakeup, || {
 interrupt!(tlb, || {
     LOCAL_APIC.eoi();
 
-    tlb::flush_all();
+    // The TLB entries of any address space may be cached, not only the loaded one
+    pcid::flush_all();
 });
 
 interrupt!(switch, || {
@@ -21,10 +20,10 @@ interrupt!(switch, || {
     let _ = context::switch();
 });
 
-interrupt!(pit, || {
+interrupt_stack!(pit, |stack| {
     LOCAL_APIC.eoi();
 
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 10 {
+    if irq::tick(stack, 1) >= context::time_slice() {
         let _ = context::switch();
     }
 });
@@ -3,8 +3,9 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use alloc::vec::Vec;
 
 use crate::{interrupt, interrupt_stack};
-use crate::context::timeout;
-use crate::device::{local_apic, ioapic, pic};
+use crate::interrupt::InterruptStack;
+use crate::context::{oom, timeout};
+use crate::device::{local_apic, ioapic, pic, tickless, tsc};
 use crate::device::serial::{COM1, COM2};
 use crate::ipi::{ipi, IpiKind, IpiTarget};
 use crate::scheme::debug::debug_input;
@@ -14,6 +15,18 @@ use crate::{context, time};
 #[thread_local]
 pub static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
 
+//ticks that interrupted user mode, resets to 0 in context::switch()
+#[thread_local]
+pub static USER_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Record a timer tick, sampling whether it interrupted user mode
+pub fn tick(stack: &InterruptStack, ticks: usize) -> usize {
+    if stack.iret.cs & 3 == 3 {
+        USER_TICKS.fetch_add(ticks, Ordering::SeqCst);
+    }
+    PIT_TICKS.fetch_add(ticks, Ordering::SeqCst)
+}
+
 // The only way to read PS2 data without race conditions is to allow a keyboard interrupt to happen
 // and then read data while reading mouse data, since keyboard data overrides mouse data and
 // reading the status register is not done atomically with reading the data. This is not possible
@@ -169,9 +182,15 @@ unsafe fn ioapic_unmask(irq: usize) {
     ioapic::unmask(irq as u8);
 }
 
-interrupt_stack!(pit_stack, |_stack| {
+interrupt_stack!(pit_stack, |stack| {
     // Saves CPU time by not sending IRQ event irq_trigger(0);
 
+    // The stopped PIT may raise one more interrupt after the BSP became tickless
+    if tickless::enabled() {
+        eoi(0);
+        return;
+    }
+
     const PIT_RATE: u64 = 2_250_286;
 
     {
@@ -183,13 +202,24 @@ interrupt_stack!(pit_stack, |_stack| {
 
     eoi(0);
 
-    // Wake up other CPUs
-    ipi(IpiKind::Pit, IpiTarget::Other);
+    tickless::calibrate();
+    tsc::calibrate();
+
+    // From now on the BSP is driven by its local APIC timer like the other CPUs
+    if tickless::enter_bsp() {
+        tickless::arm_slice(context::time_slice() as u64);
+    }
+
+    // Wake up other CPUs, unless they are driven by their own timer
+    if ! tickless::active() {
+        ipi(IpiKind::Pit, IpiTarget::Other);
+    }
 
     // Any better way of doing this?
     timeout::trigger();
+    oom::wake_victims();
 
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 10 {
+    if tick(stack, 1) >= context::time_slice() {
         let _ = context::switch();
     }
 });
@@ -283,9 +313,23 @@ interrupt!(ata2, || {
     eoi(15);
 });
 
-interrupt!(lapic_timer, || {
-    println!("Local apic timer interrupt");
+interrupt_stack!(lapic_timer, |stack| {
     lapic_eoi();
+
+    // The timer only runs on tickless CPUs, where it replaces the PIT tick
+    if tickless::enabled() {
+        timeout::trigger();
+        oom::wake_victims();
+
+        if tick(stack, tickless::expired_ticks() as usize) >= context::time_slice() {
+            let _ = context::switch();
+        } else {
+            // The timer fired for a deadline before the end of the slice. Sleeping contexts are
+            // woken up, and run once the slice ends, unless they preempt the current context
+            context::wake_sleepers();
+            tickless::arm_slice(context::time_slice().saturating_sub(PIT_TICKS.load(Ordering::SeqCst)) as u64);
+        }
+    }
 });
 
 interrupt!(lapic_error, || {
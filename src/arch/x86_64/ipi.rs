@@ -27,3 +27,29 @@ pub fn ipi(kind: IpiKind, target: IpiTarget) {
     let icr = (target as u64) << 18 | 1 << 14 | (kind as u64);
     unsafe { LOCAL_APIC.set_icr(icr) };
 }
+
+#[cfg(not(feature = "multi_core"))]
+#[inline(always)]
+pub fn ipi_cpu(_kind: IpiKind, _cpu_id: usize) {}
+
+/// Send an IPI to a single CPU, by its logical id
+#[cfg(feature = "multi_core")]
+#[inline(always)]
+pub fn ipi_cpu(kind: IpiKind, cpu_id: usize) {
+    use crate::device::local_apic::{self, LOCAL_APIC};
+
+    let apic_id = match local_apic::apic_id(cpu_id) {
+        Some(apic_id) => apic_id,
+        None => return ipi(kind, IpiTarget::Other),
+    };
+
+    let mut icr = 1 << 14 | (kind as u64);
+    unsafe {
+        if LOCAL_APIC.x2 {
+            icr |= (apic_id as u64) << 32;
+        } else {
+            icr |= (apic_id as u64) << 56;
+        }
+        LOCAL_APIC.set_icr(icr);
+    }
+}
@@ -27,6 +27,9 @@ pub mod ipi;
 /// Paging
 pub mod paging;
 
+/// Process-context identifiers
+pub mod pcid;
+
 /// Page table isolation
 pub mod pti;
 
@@ -21,6 +21,9 @@ bitflags! {
         const DIRTY =           1 << 6;//swap̿ͨλǷѡҳн
         const HUGE_PAGE =       1 << 7;//ʹ4kҳ4MĴҳ
         const GLOBAL =          1 << 8;//ȫ趨ҳǷеַռж
+        const COPY_ON_WRITE =   1 << 9;
+        const LAZY =            1 << 10;
+        const SWAPPED =         1 << 11;
         const NO_EXECUTE =      1 << 63;//ֹڴҳִд
         /*
         9-11λ OSɷʹ
@@ -32,6 +35,9 @@ bitflags! {
 
 pub const ADDRESS_MASK: usize = 0x000f_ffff_ffff_f000;
 pub const COUNTER_MASK: u64 = 0x3ff0_0000_0000_0000;
+/// Bit selecting the page attribute table entry of a huge page, which is `HUGE_PAGE` in an entry
+/// mapping a 4 KiB page
+pub const HUGE_PAT: u64 = 1 << 12;
 //ADDRESS_MASKȡַCOUNTER_MASK ȡֵ
 
 impl Entry {
@@ -74,6 +80,62 @@ impl Entry {
         }
     }
 
+    /// Check if the entry is reserved for a page that gets a frame on first access
+    pub fn is_lazy(&self) -> bool {
+        let flags = self.flags();
+        flags.contains(EntryFlags::LAZY) && ! flags.contains(EntryFlags::PRESENT)
+    }
+
+    /// Reserve the entry for a page that gets a frame on first access, keeping the flags it
+    /// will be mapped with
+    pub fn set_lazy(&mut self, flags: EntryFlags) {
+        self.0 = ((flags | EntryFlags::LAZY) - EntryFlags::PRESENT).bits() | (self.0 & COUNTER_MASK);
+    }
+
+    /// Check if the contents of the page were written out to swap
+    pub fn is_swapped(&self) -> bool {
+        let flags = self.flags();
+        flags.contains(EntryFlags::SWAPPED) && ! flags.contains(EntryFlags::PRESENT)
+    }
+
+    /// Get the swap slot holding the contents of a swapped out page
+    pub fn swap_slot(&self) -> usize {
+        (self.0 as usize & ADDRESS_MASK) >> 12
+    }
+
+    /// Mark the page as swapped out to `slot`, keeping the flags it will be mapped with again
+    pub fn set_swapped(&mut self, slot: usize, flags: EntryFlags) {
+        debug_assert!((slot << 12) & !ADDRESS_MASK == 0);
+        self.0 = ((slot << 12) as u64) | ((flags | EntryFlags::SWAPPED) - EntryFlags::PRESENT - EntryFlags::LAZY).bits() | (self.0 & COUNTER_MASK);
+    }
+
+    /// Check if the entry of a P3 or P2 table maps a huge page instead of pointing to a table
+    pub fn is_huge(&self) -> bool {
+        self.flags().contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE)
+    }
+
+    /// Get the first frame of a huge page
+    pub fn huge_frame(&self) -> Frame {
+        Frame::containing_address(PhysicalAddress::new(self.0 as usize & ADDRESS_MASK & ! (HUGE_PAT as usize)))
+    }
+
+    /// Get the flags of a huge page as the flags of a 4 KiB page, where `HUGE_PAGE` selects the
+    /// page attribute table entry
+    pub fn huge_flags(&self) -> EntryFlags {
+        let mut flags = self.flags() - EntryFlags::HUGE_PAGE;
+        if self.0 & HUGE_PAT == HUGE_PAT {
+            flags |= EntryFlags::HUGE_PAGE;
+        }
+        flags
+    }
+
+    /// Map a huge page in an entry of a P3 or P2 table. `flags` are those of a 4 KiB page
+    pub fn set_huge(&mut self, frame: Frame, flags: EntryFlags) {
+        debug_assert!(frame.start_address().get() & !ADDRESS_MASK == 0);
+        let pat = if flags.contains(EntryFlags::HUGE_PAGE) { HUGE_PAT } else { 0 };
+        self.0 = (frame.start_address().get() as u64) | (flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE).bits() | pat | (self.0 & COUNTER_MASK);
+    }
+
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         debug_assert!(frame.start_address().get() & !ADDRESS_MASK == 0);
         //assert!ڶԲʽǷΪtruedebug˵ֻڵģʽʹ
@@ -1,11 +1,14 @@
 use core::mem;
 use core::ptr::Unique;
+use x86::tlb;
 
-use crate::memory::{allocate_frames, deallocate_frames, Frame};
+use crate::context::swap;
+use crate::memory::{allocate_frames, deallocate_frames, unref_frame, Frame};
+use crate::syscall::error::{Error, ENOMEM, Result};
 
-use super::{ActivePageTable, Page, PAGE_SIZE, PhysicalAddress, VirtualAddress};
-use super::entry::EntryFlags;
-use super::table::{self, Table, Level4};
+use super::{ActivePageTable, ENTRY_COUNT, Page, PageSize, PAGE_SIZE, PhysicalAddress, VirtualAddress};
+use super::entry::{Entry, EntryFlags};
+use super::table::{self, Table, Level1, Level4};
 
 /// In order to enforce correct paging operations in the kernel, these types
 /// are returned on any mapping operation to get the code involved to specify
@@ -113,22 +116,236 @@ impl Mapper {
         MapperFlush::new(page)
     }
 
+    /// Map a page of `size` to the frames starting at `frame`. Both have to be aligned to the
+    /// size, and 1 GiB pages have to be supported by the CPU
+    pub fn map_to_size(&mut self, page: Page, frame: Frame, size: PageSize, flags: EntryFlags) -> MapperFlush {
+        assert!(page.start_address().get() % size.size() == 0 && frame.start_address().get() % size.size() == 0,
+            "{:X}: Requesting {:X} as {:?} page, which is not aligned",
+            page.start_address().get(), frame.start_address().get(), size);
+
+        match size {
+            PageSize::Small => self.map_to(page, frame, flags),
+            PageSize::Large => {
+                let p2 = self.p4_mut().next_table_create(page.p4_index())
+                    .next_table_create(page.p3_index());
+
+                assert!(p2[page.p2_index()].is_unused(),
+                    "{:X}: Set to {:X}: {:?}, requesting 2 MiB page {:X}: {:?}",
+                    page.start_address().get(),
+                    p2[page.p2_index()].address().get(), p2[page.p2_index()].flags(),
+                    frame.start_address().get(), flags);
+                p2.increment_entry_count();
+                p2[page.p2_index()].set_huge(frame, flags);
+                MapperFlush::new(page)
+            },
+            PageSize::Huge => {
+                assert!(super::has_1gib_pages(), "1 GiB pages are not supported");
+                let p3 = self.p4_mut().next_table_create(page.p4_index());
+
+                assert!(p3[page.p3_index()].is_unused(),
+                    "{:X}: Set to {:X}: {:?}, requesting 1 GiB page {:X}: {:?}",
+                    page.start_address().get(),
+                    p3[page.p3_index()].address().get(), p3[page.p3_index()].flags(),
+                    frame.start_address().get(), flags);
+                p3.increment_entry_count();
+                p3[page.p3_index()].set_huge(frame, flags);
+                MapperFlush::new(page)
+            },
+        }
+    }
+
+    /// Unmap the 2 MiB or 1 GiB page starting at `page`. Huge pages only map physical memory
+    /// that does not come from the frame allocator, so the frames are not freed
+    pub fn unmap_huge(&mut self, page: Page) -> MapperFlush {
+        let p3 = self.p4_mut().next_table_mut(page.p4_index()).expect("unmap_huge: no p3");
+        if p3[page.p3_index()].is_huge() {
+            p3.decrement_entry_count();
+            p3[page.p3_index()].set_unused();
+            return MapperFlush::new(page);
+        }
+
+        let p2_unused = {
+            let p2 = p3.next_table_mut(page.p3_index()).expect("unmap_huge: no p2");
+            assert!(p2[page.p2_index()].is_huge(), "unmap_huge({:X}): not a huge page", page.start_address().get());
+            p2.decrement_entry_count();
+            p2[page.p2_index()].set_unused();
+            p2.is_unused()
+        };
+
+        if p2_unused {
+            let p2_frame = p3[page.p3_index()].pointed_frame().expect("unmap_huge: p2_frame not found");
+            p3.decrement_entry_count();
+            p3[page.p3_index()].set_unused();
+            deallocate_frames(p2_frame, 1);
+        }
+
+        MapperFlush::new(page)
+    }
+
+    /// Replace the huge pages containing `page` with tables mapping the same frames, so that
+    /// the page can be changed on its own. Fails with `ENOMEM` if there are no frames left for
+    /// the tables, leaving the page mapped as it was
+    pub fn split_huge(&mut self, page: Page) -> Result<()> {
+        let p3 = match self.p4_mut().next_table_mut(page.p4_index()) {
+            Some(p3) => p3,
+            None => return Ok(()),
+        };
+
+        if p3[page.p3_index()].is_huge() {
+            let frame = p3[page.p3_index()].huge_frame();
+            let flags = p3[page.p3_index()].huge_flags();
+
+            let table_frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+            p3[page.p3_index()].set(table_frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE);
+            let p2 = p3.next_table_mut(page.p3_index()).expect("split_huge: no p2");
+            // The recursive mapping of the new table may still be cached as part of the huge page
+            unsafe { tlb::flush(p2 as *mut _ as usize); }
+            p2.zero();
+            for i in 0..ENTRY_COUNT {
+                let address = frame.start_address().get() + i * PageSize::Large.size();
+                p2[i].set_huge(Frame::containing_address(PhysicalAddress::new(address)), flags);
+                p2.increment_entry_count();
+            }
+        }
+
+        let p2 = match p3.next_table_mut(page.p3_index()) {
+            Some(p2) => p2,
+            None => return Ok(()),
+        };
+
+        if p2[page.p2_index()].is_huge() {
+            let frame = p2[page.p2_index()].huge_frame();
+            let flags = p2[page.p2_index()].huge_flags();
+
+            let table_frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+            p2[page.p2_index()].set(table_frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE);
+            let p1 = p2.next_table_mut(page.p2_index()).expect("split_huge: no p1");
+            // The recursive mapping of the new table may still be cached as part of the huge page
+            unsafe { tlb::flush(p1 as *mut _ as usize); }
+            p1.zero();
+            for i in 0..ENTRY_COUNT {
+                let address = frame.start_address().get() + i * PAGE_SIZE;
+                p1[i].set(Frame::containing_address(PhysicalAddress::new(address)), flags);
+                p1.increment_entry_count();
+            }
+        }
+
+        Ok(())
+    }
+
     /// Map a page to the next free frame
     pub fn map(&mut self, page: Page, flags: EntryFlags) -> MapperFlush {
         let frame = allocate_frames(1).expect("out of frames");//分配一个空闲帧
         self.map_to(page, frame, flags)//为page和frame建立映射关系
     }
 
-    /// Update flags for a page
+    /// Map a page to the next free frame, failing with `ENOMEM` if there are no frames left
+    pub fn try_map(&mut self, page: Page, flags: EntryFlags) -> Result<MapperFlush> {
+        self.p1_try_create(page).ok_or(Error::new(ENOMEM))?;
+        let frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+        Ok(self.map_to(page, frame, flags))
+    }
+
+    /// Map a page to a frame like `map_to`, failing with `ENOMEM` if there are no frames left for
+    /// its page tables
+    pub fn try_map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> Result<MapperFlush> {
+        self.p1_try_create(page).ok_or(Error::new(ENOMEM))?;
+        Ok(self.map_to(page, frame, flags))
+    }
+
+    /// Mark a page as swapped out like `map_swapped`, failing with `ENOMEM` if there are no frames
+    /// left for its page tables
+    pub fn try_map_swapped(&mut self, page: Page, slot: usize, flags: EntryFlags) -> Result<MapperFlush> {
+        self.p1_try_create(page).ok_or(Error::new(ENOMEM))?;
+        Ok(self.map_swapped(page, slot, flags))
+    }
+
+    /// Reserve a page without a frame like `map_lazy`, failing with `ENOMEM` if there are no
+    /// frames left for its page tables
+    pub fn try_map_lazy(&mut self, page: Page, flags: EntryFlags) -> Result<MapperFlush> {
+        self.p1_try_create(page).ok_or(Error::new(ENOMEM))?;
+        Ok(self.map_lazy(page, flags))
+    }
+
+    /// Get the P1 table of a page, creating the tables leading to it
+    fn p1_try_create(&mut self, page: Page) -> Option<&mut Table<Level1>> {
+        self.p4_mut().next_table_try_create(page.p4_index())?
+            .next_table_try_create(page.p3_index())?
+            .next_table_try_create(page.p2_index())
+    }
+
+    /// Reserve a page without a frame. The page fault handler allocates a zeroed frame and
+    /// maps it with `flags` when the page is first accessed
+    pub fn map_lazy(&mut self, page: Page, flags: EntryFlags) -> MapperFlush {
+        let p3 = self.p4_mut().next_table_create(page.p4_index());
+        let p2 = p3.next_table_create(page.p3_index());
+        let p1 = p2.next_table_create(page.p2_index());
+
+        assert!(p1[page.p1_index()].is_unused(),
+            "{:X}: Set to {:X}: {:?}, requesting lazy: {:?}",
+            page.start_address().get(),
+            p1[page.p1_index()].address().get(), p1[page.p1_index()].flags(),
+            flags);
+        p1.increment_entry_count();
+        p1[page.p1_index()].set_lazy(flags);
+        MapperFlush::new(page)
+    }
+
+    /// Reserve a page whose contents are in a swap slot, passing the caller's reference to the
+    /// slot on to the entry. The page fault handler reads the page back when it is accessed
+    pub fn map_swapped(&mut self, page: Page, slot: usize, flags: EntryFlags) -> MapperFlush {
+        let p3 = self.p4_mut().next_table_create(page.p4_index());
+        let p2 = p3.next_table_create(page.p3_index());
+        let p1 = p2.next_table_create(page.p2_index());
+
+        assert!(p1[page.p1_index()].is_unused(),
+            "{:X}: Set to {:X}: {:?}, requesting swap slot {}: {:?}",
+            page.start_address().get(),
+            p1[page.p1_index()].address().get(), p1[page.p1_index()].flags(),
+            slot, flags);
+        p1.increment_entry_count();
+        p1[page.p1_index()].set_swapped(slot, flags);
+        MapperFlush::new(page)
+    }
+
+    /// Replace the frame of a page with the swap slot its contents are written to, returning
+    /// the frame
+    pub fn swap_out(&mut self, page: Page, slot: usize) -> (MapperFlush, Frame) {
+        let p1 = self.p4_mut().next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .expect("swap_out: no p1");
+        let frame = p1[page.p1_index()].pointed_frame().expect("swap_out: not mapped");
+        let flags = p1[page.p1_index()].flags();
+        p1[page.p1_index()].set_swapped(slot, flags);
+        (MapperFlush::new(page), frame)
+    }
+
+    /// Update flags for a page. Pages without a frame keep waiting for their first access.
+    /// Pages that are part of a huge page have to be split first, see `try_remap`
     pub fn remap(&mut self, page: Page, flags: EntryFlags) -> MapperFlush {
         let p3 = self.p4_mut().next_table_mut(page.p4_index()).expect("failed to remap: no p3");
         let p2 = p3.next_table_mut(page.p3_index()).expect("failed to remap: no p2");
         let p1 = p2.next_table_mut(page.p2_index()).expect("failed to remap: no p1");
-        let frame = p1[page.p1_index()].pointed_frame().expect("failed to remap: not mapped");
-        p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        if p1[page.p1_index()].is_lazy() {
+            p1[page.p1_index()].set_lazy(flags);
+        } else if p1[page.p1_index()].is_swapped() {
+            let slot = p1[page.p1_index()].swap_slot();
+            p1[page.p1_index()].set_swapped(slot, flags);
+        } else {
+            let frame = p1[page.p1_index()].pointed_frame().expect("failed to remap: not mapped");
+            p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        }
         MapperFlush::new(page)
     }
 
+    /// Update flags for a page like `remap`, splitting the huge page it is part of first.
+    /// Fails with `ENOMEM` if there are no frames left for that
+    pub fn try_remap(&mut self, page: Page, flags: EntryFlags) -> Result<MapperFlush> {
+        self.split_huge(page)?;
+        Ok(self.remap(page, flags))
+    }
+
     /// Identity map a frame
     /// 该函数定义是为了重新映射内核
     pub fn identity_map(&mut self, frame: Frame, flags: EntryFlags) -> MapperFlush {
@@ -136,7 +353,9 @@ impl Mapper {
         self.map_to(page, frame, flags)
     }
 
-    fn unmap_inner(&mut self, page: Page, keep_parents: bool) -> Frame {
+    /// Unmap a page. Pages that are part of a huge page have to be split first with
+    /// `split_huge`, or be unmapped whole with `unmap_huge`
+    fn unmap_inner(&mut self, page: Page, keep_parents: bool) -> Option<Frame> {
         let frame;
 
         let p4 = self.p4_mut();
@@ -144,7 +363,12 @@ impl Mapper {
             if let Some(p2) = p3.next_table_mut(page.p3_index()) {
                 if let Some(p1) = p2.next_table_mut(page.p2_index()) {
                     frame = if let Some(frame) = p1[page.p1_index()].pointed_frame() {
-                        frame
+                        Some(frame)
+                    } else if p1[page.p1_index()].is_lazy() {
+                        None
+                    } else if p1[page.p1_index()].is_swapped() {
+                        swap::unref_slot(p1[page.p1_index()].swap_slot());
+                        None
                     } else {
                         panic!("unmap_inner({:X}): frame not found", page.start_address().get())
                     };
@@ -204,42 +428,82 @@ impl Mapper {
         frame
     }
 
-    /// Unmap a page
+    /// Unmap a page, freeing its frame unless it is still mapped by another address space
     pub fn unmap(&mut self, page: Page) -> MapperFlush {
-        let frame = self.unmap_inner(page, false);
-        deallocate_frames(frame, 1);
+        if let Some(frame) = self.unmap_inner(page, false) {
+            unref_frame(frame);
+        }
         MapperFlush::new(page)
     }
 
     /// Unmap a page, return frame without free
     pub fn unmap_return(&mut self, page: Page, keep_parents: bool) -> (MapperFlush, Frame) {
+        let frame = self.unmap_inner(page, keep_parents).expect("unmap_return: page has no frame");
+        (MapperFlush::new(page), frame)
+    }
+
+    /// Unmap a page that may not have a frame yet, return frame without free
+    pub fn unmap_take(&mut self, page: Page, keep_parents: bool) -> (MapperFlush, Option<Frame>) {
         let frame = self.unmap_inner(page, keep_parents);
         (MapperFlush::new(page), frame)
     }
 
+    /// Check if a page is mapped, or reserved to get a frame on first access, or swapped out
+    pub fn is_mapped(&self, page: Page) -> bool {
+        self.translate_page_flags(page).map_or(false, |flags| {
+            flags.intersects(EntryFlags::PRESENT | EntryFlags::LAZY | EntryFlags::SWAPPED)
+        })
+    }
+
+    /// Get the entry mapping a page, which is in a P3 or P2 table for a huge page, along with
+    /// the size of the page it maps
+    fn entry(&self, page: Page) -> Option<(&Entry, PageSize)> {
+        let p3 = self.p4().next_table(page.p4_index())?;
+        if p3[page.p3_index()].is_huge() {
+            return Some((&p3[page.p3_index()], PageSize::Huge));
+        }
+        let p2 = p3.next_table(page.p3_index())?;
+        if p2[page.p2_index()].is_huge() {
+            return Some((&p2[page.p2_index()], PageSize::Large));
+        }
+        let p1 = p2.next_table(page.p2_index())?;
+        Some((&p1[page.p1_index()], PageSize::Small))
+    }
+
+    /// Get the size of the page mapping `page`, if it is mapped
+    pub fn translate_page_size(&self, page: Page) -> Option<PageSize> {
+        self.entry(page)
+            .filter(|(entry, _size)| entry.flags().contains(EntryFlags::PRESENT))
+            .map(|(_entry, size)| size)
+    }
+
+    /// Get the swap slot holding the contents of a page, if it is swapped out
+    pub fn translate_swap_slot(&self, page: Page) -> Option<usize> {
+        self.entry(page)
+            .filter(|(entry, size)| *size == PageSize::Small && entry.is_swapped())
+            .map(|(entry, _size)| entry.swap_slot())
+    }
+
+    /// Get the frame of a page. Pages of a huge page get the frame at their offset in it
     pub fn translate_page(&self, page: Page) -> Option<Frame> {//翻译地址
-        self.p4().next_table(page.p4_index())
-            .and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
-            .and_then(|p1| p1[page.p1_index()].pointed_frame())
-        //实现逻辑：p4索引存在的前提下，如果p3地址有效，索引找到p3的下一级页表
-        //                            如果p2地址有效，索引找到p2的下一级页表
-        //                            如果p1地址有效，索引找到对应的物理帧
-        //pointed_frane函数是通过entry_flags中的present位来进行判断的
-        //pub fn pointed_frame(&self) -> Option<Frame> {
-        //     if self.flags().contains(EntryFlags::PRESENT) {//检测present是否在EntryFlags中
-        //         Some(Frame::containing_address(self.address()))//创建帧
-        //     } else {
-        //         None
-        //     }
-        // }
+        let (entry, size) = self.entry(page)?;
+        match size {
+            PageSize::Small => entry.pointed_frame(),
+            _ => {
+                let offset = (page.start_address().get() % size.size()) / PAGE_SIZE * PAGE_SIZE;
+                Some(Frame::containing_address(PhysicalAddress::new(entry.huge_frame().start_address().get() + offset)))
+            },
+        }
     }
 
+    /// Get the flags of a page. Pages of a huge page get the flags they would have as 4 KiB
+    /// pages
     pub fn translate_page_flags(&self, page: Page) -> Option<EntryFlags> {//获取帧的标志位
-        self.p4().next_table(page.p4_index())
-            .and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
-            .and_then(|p1| Some(p1[page.p1_index()].flags()))
+        let (entry, size) = self.entry(page)?;
+        match size {
+            PageSize::Small => Some(entry.flags()),
+            _ => Some(entry.huge_flags()),
+        }
     }
 
     /// Translate a virtual address to a physical one
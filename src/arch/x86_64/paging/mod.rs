@@ -3,9 +3,10 @@
 
 use core::ops::{Deref, DerefMut};
 use core::{mem, ptr};
-use spin::Mutex;
-use x86::{controlregs, msr, tlb};
+use spin::{Mutex, Once};
+use x86::{controlregs, cpuid::CpuId, msr, tlb};
 
+use crate::arch::x86_64::pcid;
 use crate::memory::{allocate_frames, Frame};
 
 use self::entry::EntryFlags;
@@ -23,6 +24,59 @@ pub const ENTRY_COUNT: usize = 512;
 /// Size of pages
 pub const PAGE_SIZE: usize = 4096;
 
+/// Size of the pages mapped by a single page table entry
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PageSize {
+    /// 4 KiB, mapped by a P1 entry
+    Small,
+    /// 2 MiB, mapped by a P2 entry
+    Large,
+    /// 1 GiB, mapped by a P3 entry
+    Huge,
+}
+
+impl PageSize {
+    /// Number of 4 KiB pages in a page of this size
+    pub fn pages(self) -> usize {
+        match self {
+            PageSize::Small => 1,
+            PageSize::Large => ENTRY_COUNT,
+            PageSize::Huge => ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+
+    /// Size in bytes
+    pub fn size(self) -> usize {
+        self.pages() * PAGE_SIZE
+    }
+
+    /// Get the largest page size that can map the start of `size` bytes at `virtual_address` to
+    /// `physical_address`
+    pub fn largest(virtual_address: usize, physical_address: usize, size: usize) -> PageSize {
+        for &page_size in [PageSize::Huge, PageSize::Large].iter() {
+            if page_size == PageSize::Huge && ! has_1gib_pages() {
+                continue;
+            }
+            if virtual_address % page_size.size() == 0
+                && physical_address % page_size.size() == 0
+                && size >= page_size.size()
+            {
+                return page_size;
+            }
+        }
+        PageSize::Small
+    }
+}
+
+static HAS_1GIB_PAGES: Once<bool> = Once::new();
+
+/// Check if the CPU can map 1 GiB pages
+pub fn has_1gib_pages() -> bool {
+    *HAS_1GIB_PAGES.call_once(|| {
+        CpuId::new().get_extended_function_info().map_or(false, |info| info.has_1gib_pages())
+    })
+}
+
 //TODO: This is a rudimentary recursive mutex used to naively fix multi_core issues, replace it!
 pub struct PageTableLock {
     cpu_id: usize,
@@ -86,6 +140,12 @@ unsafe fn init_pat() {
     );
 }
 
+/// Make read-only pages read-only in kernel mode as well, so that the kernel writing to a
+/// copy-on-write user page faults like user mode does
+unsafe fn init_write_protect() {
+    controlregs::cr0_write(controlregs::cr0() | controlregs::Cr0::CR0_WRITE_PROTECT);
+}
+
 /// Copy tdata, clear tbss, set TCB self pointer
 unsafe fn init_tcb(cpu_id: usize) -> usize {
     extern "C" {
@@ -156,6 +216,8 @@ pub unsafe fn init(
 
     init_pat();
 
+    init_write_protect();
+
     let mut active_table = ActivePageTable::new_unlocked();
 
     let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(
@@ -197,10 +259,7 @@ pub unsafe fn init(
 
         // Map all frames in kernel
         {
-            let start_frame = Frame::containing_address(PhysicalAddress::new(kernel_start));
-            let end_frame = Frame::containing_address(PhysicalAddress::new(kernel_end - 1));
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                let phys_addr = frame.start_address().get();
+            let kernel_flags = |phys_addr: usize| {
                 let virt_addr = phys_addr + crate::KERNEL_OFFSET;
 
                 macro_rules! in_section {
@@ -210,7 +269,7 @@ pub unsafe fn init(
                     };
                 }
 
-                let flags = if in_section!(text) {
+                if in_section!(text) {
                     // Remap text read-only
                     EntryFlags::PRESENT | EntryFlags::GLOBAL
                 } else if in_section!(rodata) {
@@ -234,15 +293,36 @@ pub unsafe fn init(
                 } else {
                     // Remap anything else read-only, no execute
                     EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::NO_EXECUTE
+                }
+            };
+
+            let start_frame = Frame::containing_address(PhysicalAddress::new(kernel_start));
+            let end_frame = Frame::containing_address(PhysicalAddress::new(kernel_end - 1));
+            let mut frame = start_frame;
+            while frame <= end_frame {
+                let phys_addr = frame.start_address().get();
+                let flags = kernel_flags(phys_addr);
+
+                // Use a 2 MiB page where the whole of it has the same flags
+                let remaining = (end_frame.start_address().get() - phys_addr) / PAGE_SIZE + 1;
+                let large = PageSize::Large;
+                let size = if phys_addr % large.size() == 0 && remaining >= large.pages()
+                    && (1..large.pages()).all(|i| kernel_flags(phys_addr + i * PAGE_SIZE) == flags)
+                {
+                    large
+                } else {
+                    PageSize::Small
                 };
 
-                let page = Page::containing_address(VirtualAddress::new(virt_addr));
-                let result = mapper.map_to(page, frame, flags);
+                let page = Page::containing_address(VirtualAddress::new(phys_addr + crate::KERNEL_OFFSET));
+                let result = mapper.map_to_size(page, frame.clone(), size, flags);
                 // The flush can be ignored as this is not the active table. See later active_table.switch
                 /* unsafe */
                 {
                     result.ignore();
                 }
+
+                frame = Frame::containing_address(PhysicalAddress::new(phys_addr + size.size()));
             }
         }
 
@@ -310,6 +390,8 @@ pub unsafe fn init_ap(
 
     init_pat();
 
+    init_write_protect();
+
     let mut active_table = ActivePageTable::new_unlocked();
 
     let mut new_table = InactivePageTable::from_address(bsp_table);
@@ -423,8 +505,14 @@ impl ActivePageTable {
     }
 
     pub fn flush(&mut self, page: Page) {
+        let address = page.start_address().get();
         unsafe {
-            tlb::flush(page.start_address().get());
+            // Kernel mappings are shared by all page tables, and may be cached with any PCID
+            if address >= 0xFFFF_8000_0000_0000 {
+                pcid::flush_shared(address);
+            } else {
+                tlb::flush(address);
+            }
         }
     }
 
@@ -470,13 +558,16 @@ impl ActivePageTable {
                 EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
             );
             self.flush_all();
+
+            // the table may have been loaded before, and still be tagged on any CPU
+            pcid::flush_table(unsafe { table.address() });
         }
 
         temporary_page.unmap(self);
     }
 
     pub unsafe fn address(&self) -> usize {
-        controlregs::cr3() as usize
+        controlregs::cr3() as usize & !pcid::CR3_PCID_MASK
     }
 }
 
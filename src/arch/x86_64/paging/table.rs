@@ -95,15 +95,21 @@ impl<L> Table<L> where L: HierarchicalLevel {
     }
 
     pub fn next_table_create(&mut self, index: usize) -> &mut Table<L::NextLevel> {
+        self.next_table_try_create(index).expect("no frames available")
+    }
+
+    /// Get the next table, creating it if it does not exist. Returns `None` if there is no
+    /// frame left for a new table
+    pub fn next_table_try_create(&mut self, index: usize) -> Option<&mut Table<L::NextLevel>> {
         if self.next_table(index).is_none() {
             assert!(!self[index].flags().contains(EntryFlags::HUGE_PAGE),
                     "next_table_create does not support huge pages");
-            let frame = allocate_frames(1).expect("no frames available");
+            let frame = allocate_frames(1)?;
             self.increment_entry_count();
             self[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE /* Allow users to go down the page table, implement permissions at the page level */);
             self.next_table_mut(index).unwrap().zero();
         }
-        self.next_table_mut(index).unwrap()
+        self.next_table_mut(index)
     }
 
     fn next_table_address(&self, index: usize) -> Option<usize> {
@@ -0,0 +1,165 @@
+//! Process-context identifiers
+//!
+//! Each CPU tags the TLB entries of the last few page tables it loaded with a PCID, so that
+//! switching back to one of them does not have to flush the TLB. PCID 0 is left to page tables
+//! loaded without going through this module, which are always flushed when loaded.
+//!
+//! A page table changed while not loaded may still be tagged on other CPUs. Every change bumps
+//! the generation of the table, and a CPU only keeps the TLB entries of a tagged table if its
+//! generation has not moved on since the table was tagged there.
+//!
+//! Page table isolation is not covered: `pti::map` and `pti::unmap` only switch stacks in this
+//! tree, so there is no separate user view to tag.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86::controlregs::{self, Cr4};
+use x86::cpuid::CpuId;
+use x86::tlb;
+
+/// Number of page tables each CPU keeps tagged
+pub const PCID_SLOTS: usize = 8;
+/// Bits of CR3 holding the PCID
+pub const CR3_PCID_MASK: usize = 0xFFF;
+/// Set when loading CR3 to keep the TLB entries of the new PCID
+pub const CR3_NOFLUSH: usize = 1 << 63;
+
+/// INVPCID types
+const INVPCID_ADDRESS: u64 = 0;
+const INVPCID_ALL_NON_GLOBAL: u64 = 3;
+
+/// Number of generation counters page tables are hashed into
+const GENERATIONS: usize = 16;
+
+/// Whether the INVPCID instruction is supported
+static INVPCID: AtomicBool = AtomicBool::new(false);
+
+/// Generation of the page tables hashed to each counter, bumped whenever one of them is changed
+/// while not loaded. Page tables sharing a counter are flushed along with each other
+static GENERATION: [AtomicUsize; GENERATIONS] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+/// Page table tagged with the PCID of each slot, or zero if the slot is free. Slot `n` uses
+/// PCID `n + 1`
+#[thread_local]
+static mut SLOTS: [usize; PCID_SLOTS] = [0; PCID_SLOTS];
+
+/// Slot to take over when none are free
+#[thread_local]
+static mut NEXT_SLOT: usize = 0;
+
+/// Generation of the page table in each slot when it was tagged or last flushed
+#[thread_local]
+static mut SLOT_GENERATION: [usize; PCID_SLOTS] = [0; PCID_SLOTS];
+
+/// Enable PCIDs on this CPU, if supported. The loaded page table must use PCID 0
+pub unsafe fn init() {
+    let cpuid = CpuId::new();
+    let has_pcid = cpuid.get_feature_info().map_or(false, |info| info.has_pcid());
+    let has_invpcid = cpuid.get_extended_feature_info().map_or(false, |info| info.has_invpcid());
+
+    if has_pcid {
+        controlregs::cr4_write(controlregs::cr4() | Cr4::CR4_ENABLE_PCID);
+        INVPCID.store(has_invpcid, Ordering::SeqCst);
+    }
+}
+
+/// Check if PCIDs are enabled on this CPU. This can be used before thread local storage is set up
+pub fn enabled() -> bool {
+    unsafe { controlregs::cr4() }.contains(Cr4::CR4_ENABLE_PCID)
+}
+
+unsafe fn invpcid(kind: u64, pcid: usize, address: usize) {
+    let descriptor: [u64; 2] = [pcid as u64, address as u64];
+    asm!("invpcid {}, [{}]", in(reg) kind, in(reg) &descriptor, options(nostack));
+}
+
+fn generation(table: usize) -> &'static AtomicUsize {
+    &GENERATION[(table >> 12) % GENERATIONS]
+}
+
+/// Get the value to load into CR3 to switch to `table`, tagging it with a PCID on this CPU.
+/// The TLB is only flushed if the table was not tagged already, or was changed since
+pub unsafe fn cr3(table: usize) -> usize {
+    if ! enabled() {
+        return table;
+    }
+
+    let generation = generation(table).load(Ordering::SeqCst);
+
+    if let Some(slot) = SLOTS.iter().position(|&tagged| tagged == table) {
+        if SLOT_GENERATION[slot] == generation {
+            return table | (slot + 1) | CR3_NOFLUSH;
+        }
+
+        SLOT_GENERATION[slot] = generation;
+        if controlregs::cr3() as usize & !CR3_PCID_MASK == table {
+            // The loaded table is not reloaded when switching to it, so flush it here
+            controlregs::cr3_write((table | (slot + 1)) as u64);
+        }
+        return table | (slot + 1);
+    }
+
+    let slot = match SLOTS.iter().position(|&tagged| tagged == 0) {
+        Some(slot) => slot,
+        None => {
+            let slot = NEXT_SLOT;
+            NEXT_SLOT = (slot + 1) % PCID_SLOTS;
+            slot
+        }
+    };
+    SLOTS[slot] = table;
+    SLOT_GENERATION[slot] = generation;
+
+    table | (slot + 1)
+}
+
+/// Forget all tagged page tables but the loaded one, so they are flushed when loaded again
+unsafe fn forget_others() {
+    let current = controlregs::cr3() as usize & CR3_PCID_MASK;
+    for (slot, tagged) in SLOTS.iter_mut().enumerate() {
+        if slot + 1 != current {
+            *tagged = 0;
+        }
+    }
+}
+
+/// Flush the non-global TLB entries of all PCIDs on this CPU
+pub unsafe fn flush_all() {
+    if ! enabled() {
+        tlb::flush_all();
+    } else if INVPCID.load(Ordering::Relaxed) {
+        invpcid(INVPCID_ALL_NON_GLOBAL, 0, 0);
+    } else {
+        forget_others();
+        tlb::flush_all();
+    }
+}
+
+/// Flush a page shared by all page tables, such as a kernel mapping, in all PCIDs on this CPU
+pub unsafe fn flush_shared(address: usize) {
+    tlb::flush(address);
+
+    if ! enabled() {
+        return;
+    }
+
+    if INVPCID.load(Ordering::Relaxed) {
+        for (slot, &tagged) in SLOTS.iter().enumerate() {
+            if tagged != 0 {
+                invpcid(INVPCID_ADDRESS, slot + 1, address);
+            }
+        }
+    } else {
+        forget_others();
+    }
+}
+
+/// Mark `table` as changed while not loaded, so that every CPU flushes the TLB entries tagged
+/// with its PCID before loading it again
+pub fn flush_table(table: usize) {
+    generation(table).fetch_add(1, Ordering::SeqCst);
+}
@@ -10,7 +10,7 @@ use crate::allocator;
 use crate::acpi;
 #[cfg(feature = "graphical_debug")]
 use crate::arch::x86_64::graphical_debug;
-use crate::arch::x86_64::pti;
+use crate::arch::x86_64::{pcid, pti};
 use crate::arch::x86_64::flags::*;
 use crate::device;
 use crate::gdt;
@@ -122,6 +122,9 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         // Set up GDT after paging with TLS 设置GDT在使用TLS(线程局部存储)的分页之后。
         gdt::init_paging(tcb_offset, stack_base + stack_size);
 
+        // Enable PCIDs, which needs TLS 启用PCID，需要TLS
+        pcid::init();
+
         // Set up IDT 设置IDT（中断表）
         idt::init_paging_bsp();
 
@@ -218,6 +221,9 @@ pub unsafe extern fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
         // Set up GDT with TLS
         gdt::init_paging(tcb_offset, stack_end);
 
+        // Enable PCIDs, which needs TLS
+        pcid::init();
+
         // Set up IDT for AP
         idt::init_paging_post_heap(false, cpu_id);
 
@@ -18,8 +18,16 @@
     /// Offset to kernel heap
     pub const KERNEL_HEAP_OFFSET: usize = KERNEL_OFFSET - PML4_SIZE;
     pub const KERNEL_HEAP_PML4: usize = (KERNEL_HEAP_OFFSET & PML4_MASK)/PML4_SIZE;
-    /// Size of kernel heap
+    /// Initial size of kernel heap, and the least it grows by at a time
     pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
+    /// Default size the kernel heap may grow to, changed with `KERNEL_HEAP_MAX_MB` in the environment
+    pub const KERNEL_HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // 256 MB
+    /// Free space kept in the kernel heap, so that growing it can allocate from the heap
+    pub const KERNEL_HEAP_RESERVE: usize = 256 * 1024; // 256 KB
+
+    /// Offset to frame descriptors
+    pub const KERNEL_FRAMES_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
+    pub const KERNEL_FRAMES_PML4: usize = (KERNEL_FRAMES_OFFSET & PML4_MASK)/PML4_SIZE;
 
     /// Offset to kernel percpu variables
     //TODO: Use 64-bit fs offset to enable this pub const KERNEL_PERCPU_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
@@ -2,6 +2,8 @@ use core::mem;
 use core::sync::atomic::{AtomicBool, Ordering};
 use syscall::data::FloatRegisters;
 
+use crate::arch::x86_64::pcid;
+
 /// This must be used by the kernel to ensure that context switches are done atomically
 /// Compare and exchange this to true when beginning a context switch on any CPU
 /// The `Context::switch_to` function will set it back to false, allowing other CPU's to switch
@@ -18,6 +20,8 @@ pub struct Context {
     fx: usize,
     /// Page table pointer
     cr3: usize,
+    /// Page table pointer with the PCID it is tagged with on the CPU switching to it
+    pcid_cr3: usize,
     /// RFLAGS register
     rflags: usize,
     /// RBX register
@@ -42,6 +46,7 @@ impl Context {
             loadable: false,
             fx: 0,
             cr3: 0,
+            pcid_cr3: 0,
             rflags: 0,
             rbx: 0,
             r12: 0,
@@ -105,6 +110,11 @@ impl Context {
         self.cr3 = address;
     }
 
+    /// Tag the page table with a PCID on this CPU, before switching to this context
+    pub unsafe fn tag_page_table(&mut self) {
+        self.pcid_cr3 = pcid::cr3(self.cr3);
+    }
+
     pub fn set_stack(&mut self, address: usize) {
         self.rsp = address;
     }
@@ -141,9 +151,10 @@ impl Context {
         }
 
         asm!("mov {}, cr3", out(reg) (self.cr3));
-        if next.cr3 != self.cr3 {
-            asm!("mov cr3, {}", in(reg) (next.cr3));
+        if next.pcid_cr3 & !pcid::CR3_NOFLUSH != self.cr3 {
+            asm!("mov cr3, {}", in(reg) (next.pcid_cr3));
         }
+        self.cr3 &= !pcid::CR3_PCID_MASK;
 
         asm!("pushfq ; pop {}", out(reg) (self.rflags));
         asm!("push {} ; popfq", in(reg) (next.rflags));
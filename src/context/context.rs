@@ -10,11 +10,16 @@ use spin::Mutex;
 use crate::arch::{interrupt::InterruptStack, paging::PAGE_SIZE};
 use crate::common::unique::Unique;
 use crate::context::arch;
+use crate::context::run_queue;
 use crate::context::file::{FileDescriptor, FileDescription};
-use crate::context::memory::{UserGrants, Memory, SharedMemory, Tls};
-use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::context::memory::{UserGrants, Mapping, MappingKind, Memory, SharedMemory, Tls};
+use crate::ipi::{ipi_cpu, IpiKind};
+use crate::memory::Frame;
+use crate::paging::{ActivePageTable, InactivePageTable, Page, VirtualAddress};
+use crate::paging::temporary_page::TemporaryPage;
 use crate::scheme::{SchemeNamespace, FileHandle};
 use crate::sync::WaitMap;
+use crate::time;
 use crate::syscall::data::SigAction;
 use crate::syscall::flag::{SIG_DFL, SigActionFlags};
 
@@ -32,6 +37,129 @@ pub enum Status {
     Exited(usize)
 }
 
+/// What a blocked context is waiting for, used to account the time it spends blocked without
+/// allocating when it is unblocked
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WaitReason {
+    /// Reading from a scheme or an event queue
+    Io,
+    Futex,
+    Sleep,
+    /// Waiting for a child with `waitpid` or `vfork`
+    Child,
+    /// Stopped by a signal, or in `sigreturn`
+    Signal,
+    Ptrace,
+    /// Waiting for a page to be read from or written to swap
+    Swap,
+    Other,
+}
+
+impl WaitReason {
+    /// Number of reasons, the size of `Context::wait_times`
+    pub const COUNT: usize = 8;
+
+    /// All reasons, in the order of their index in `Context::wait_times`
+    pub const ALL: [WaitReason; WaitReason::COUNT] = [
+        WaitReason::Io,
+        WaitReason::Futex,
+        WaitReason::Sleep,
+        WaitReason::Child,
+        WaitReason::Signal,
+        WaitReason::Ptrace,
+        WaitReason::Swap,
+        WaitReason::Other,
+    ];
+
+    /// Classify the status reason given to `Context::block`
+    pub fn of(reason: &str) -> WaitReason {
+        match reason {
+            "futex" => WaitReason::Futex,
+            "nanosleep" | "nanosleep spurious" => WaitReason::Sleep,
+            "vfork" => WaitReason::Child,
+            "stopped" | "sigreturn" => WaitReason::Signal,
+            "swap io" => WaitReason::Swap,
+            "aio_worker" => WaitReason::Io,
+            _ if reason.starts_with("waitpid") => WaitReason::Child,
+            _ if reason.starts_with("ptrace::") => WaitReason::Ptrace,
+            _ if reason.ends_with("::read") => WaitReason::Io,
+            _ => WaitReason::Other,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WaitReason::Io => "io",
+            WaitReason::Futex => "futex",
+            WaitReason::Sleep => "sleep",
+            WaitReason::Child => "child",
+            WaitReason::Signal => "signal",
+            WaitReason::Ptrace => "ptrace",
+            WaitReason::Swap => "swap",
+            WaitReason::Other => "other",
+        }
+    }
+}
+
+/// Scheduling policy of normal contexts
+pub const SCHED_OTHER: usize = 0;
+/// Scheduling policy of first in, first out real-time contexts
+pub const SCHED_FIFO: usize = 1;
+/// Scheduling policy of round robin real-time contexts
+pub const SCHED_RR: usize = 2;
+
+/// Lowest real-time priority
+pub const RT_PRIO_MIN: u8 = 1;
+/// Highest real-time priority
+pub const RT_PRIO_MAX: u8 = 99;
+
+/// The scheduling class of a context. Real-time contexts always run before normal contexts,
+/// and real-time contexts with a higher priority run before those with a lower priority
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SchedPolicy {
+    /// Shares the CPU with other normal contexts, weighted by the nice level
+    Normal,
+    /// Runs until it blocks or a real-time context with a higher priority becomes runnable
+    Fifo(u8),
+    /// Like `Fifo`, but shares the CPU with real-time contexts of the same priority
+    RoundRobin(u8),
+}
+
+impl SchedPolicy {
+    /// Convert a policy and priority, as used by the proc: scheme, to a scheduling policy
+    pub fn from_raw(policy: usize, priority: usize) -> Option<SchedPolicy> {
+        let rt_priority = if priority >= RT_PRIO_MIN as usize && priority <= RT_PRIO_MAX as usize {
+            Some(priority as u8)
+        } else {
+            None
+        };
+
+        match policy {
+            SCHED_OTHER if priority == 0 => Some(SchedPolicy::Normal),
+            SCHED_FIFO => rt_priority.map(SchedPolicy::Fifo),
+            SCHED_RR => rt_priority.map(SchedPolicy::RoundRobin),
+            _ => None,
+        }
+    }
+
+    /// Convert a scheduling policy to a policy and priority, as used by the proc: scheme
+    pub fn to_raw(self) -> (usize, usize) {
+        match self {
+            SchedPolicy::Normal => (SCHED_OTHER, 0),
+            SchedPolicy::Fifo(priority) => (SCHED_FIFO, priority as usize),
+            SchedPolicy::RoundRobin(priority) => (SCHED_RR, priority as usize),
+        }
+    }
+
+    /// Get the real-time priority, or `None` for normal contexts
+    pub fn rt_priority(self) -> Option<u8> {
+        match self {
+            SchedPolicy::Normal => None,
+            SchedPolicy::Fifo(priority) | SchedPolicy::RoundRobin(priority) => Some(priority),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct WaitpidKey {
     pub pid: Option<ContextId>,
@@ -108,7 +236,15 @@ pub struct ContextSnapshot {
     pub status_reason: &'static str,
     pub running: bool,
     pub cpu_id: Option<usize>,
+    pub affinity: u64,
     pub ticks: u64,
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
+    pub nvcsw: u64,
+    pub nivcsw: u64,
+    pub nice: i8,
+    pub vruntime: u64,
+    pub policy: SchedPolicy,
     pub syscall: Option<(usize, usize, usize, usize, usize, usize)>,
     // Clone fields
     //TODO: is there a faster way than allocation?
@@ -153,7 +289,15 @@ impl ContextSnapshot {
             status_reason: context.status_reason,
             running: context.running,
             cpu_id: context.cpu_id,
+            affinity: context.affinity,
             ticks: context.ticks,
+            user_ticks: context.user_ticks,
+            kernel_ticks: context.kernel_ticks,
+            nvcsw: context.nvcsw,
+            nivcsw: context.nivcsw,
+            nice: context.nice,
+            vruntime: context.vruntime,
+            policy: context.policy,
             syscall: context.syscall,
             name,
             files,
@@ -193,8 +337,32 @@ pub struct Context {
     pub running: bool,
     /// CPU ID, if locked
     pub cpu_id: Option<usize>,
+    /// Bitmask of the CPUs this context may run on
+    pub affinity: u64,
+    /// Context is in the run queue of its CPU
+    pub queued: bool,
     /// Number of timer ticks executed
     pub ticks: u64,
+    /// Number of timer ticks executed in user mode
+    pub user_ticks: u64,
+    /// Number of timer ticks executed in kernel mode
+    pub kernel_ticks: u64,
+    /// Number of voluntary context switches, where the context blocked or stopped
+    pub nvcsw: u64,
+    /// Number of involuntary context switches, where the context was preempted
+    pub nivcsw: u64,
+    /// Monotonic time in nanoseconds at which the context was blocked
+    pub blocked_since: Option<u64>,
+    /// What the context is waiting for while it is blocked
+    pub wait_reason: WaitReason,
+    /// Nanoseconds spent blocked, indexed by `WaitReason`
+    pub wait_times: [u64; WaitReason::COUNT],
+    /// Nice level, from `NICE_MIN` (highest priority) to `NICE_MAX` (lowest priority)
+    pub nice: i8,
+    /// Virtual runtime, the number of ticks executed weighted by the nice level
+    pub vruntime: u64,
+    /// Scheduling class and real-time priority
+    pub policy: SchedPolicy,
     /// Current system call
     pub syscall: Option<(usize, usize, usize, usize, usize, usize)>,
     /// Head buffer to use when system call buffers are not page aligned
@@ -229,6 +397,12 @@ pub struct Context {
     pub tls: Option<Tls>,
     /// User grants
     pub grants: Arc<Mutex<UserGrants>>,
+    /// The memory of this context is never swapped out. This is set for scheme providers, so
+    /// that reading a page back from swap never waits on a page of the swap scheme itself
+    pub resident: bool,
+    /// Frames of user memory validated by the current syscall. They are referenced until it
+    /// returns, so that they are not swapped out while the kernel accesses them
+    pub pinned: Vec<Frame>,
     /// The name of the context
     pub name: Arc<Mutex<Box<[u8]>>>,
     /// The current working directory
@@ -269,7 +443,19 @@ impl Context {
             status_reason: "",
             running: false,
             cpu_id: None,
+            affinity: !0,
+            queued: false,
             ticks: 0,
+            user_ticks: 0,
+            kernel_ticks: 0,
+            nvcsw: 0,
+            nivcsw: 0,
+            blocked_since: None,
+            wait_reason: WaitReason::Other,
+            wait_times: [0; WaitReason::COUNT],
+            nice: 0,
+            vruntime: 0,
+            policy: SchedPolicy::Normal,
             syscall: None,
             syscall_head,
             syscall_tail,
@@ -287,6 +473,8 @@ impl Context {
             sigstack: None,
             tls: None,
             grants: Arc::new(Mutex::new(UserGrants::default())),
+            resident: false,
+            pinned: Vec::new(),
             name: Arc::new(Mutex::new(Vec::new().into_boxed_slice())),
             cwd: Arc::new(Mutex::new(Vec::new())),
             files: Arc::new(Mutex::new(Vec::new())),
@@ -372,27 +560,64 @@ impl Context {
         }
     }
 
+    /// Check if the affinity mask allows this context to run on a CPU. CPUs that do not fit in
+    /// the mask are only allowed when every CPU is allowed
+    pub fn allowed_on(&self, cpu_id: usize) -> bool {
+        if cpu_id < 64 {
+            self.affinity & (1 << cpu_id) != 0
+        } else {
+            self.affinity == !0
+        }
+    }
+
     /// Block the context, and return true if it was runnable before being blocked
     pub fn block(&mut self, reason: &'static str) -> bool {
         if self.status == Status::Runnable {
             self.status = Status::Blocked;
             self.status_reason = reason;
+            self.wait_reason = WaitReason::of(reason);
+            self.blocked_since = Some(time::monotonic_ns());
             true
         } else {
             false
         }
     }
 
+    /// Stop the context with a signal. It is blocked first, so that the time it is stopped for
+    /// is accounted like any other wait
+    pub fn stop(&mut self, sig: usize) {
+        self.block("stopped");
+        self.status = Status::Stopped(sig);
+    }
+
+    /// Continue a stopped context, and return true if it was stopped before being marked
+    /// runnable
+    pub fn cont(&mut self) -> bool {
+        if let Status::Stopped(_) = self.status {
+            self.status = Status::Blocked;
+            self.unblock()
+        } else {
+            false
+        }
+    }
+
     /// Unblock context, and return true if it was blocked before being marked runnable
     pub fn unblock(&mut self) -> bool {
         if self.status == Status::Blocked {
+            if let Some(blocked_since) = self.blocked_since.take() {
+                let waited = time::monotonic_ns().saturating_sub(blocked_since);
+                self.wait_times[self.wait_reason as usize] += waited;
+            }
+
             self.status = Status::Runnable;
             self.status_reason = "";
 
+            run_queue::enqueue(self);
+
             if let Some(cpu_id) = self.cpu_id {
                if cpu_id != crate::cpu_id() {
                     // Send IPI if not on current CPU
-                    ipi(IpiKind::Wakeup, IpiTarget::Other);
+                    ipi_cpu(IpiKind::Wakeup, cpu_id);
                }
             }
 
@@ -471,4 +696,43 @@ impl Context {
             None
         }
     }
+
+    /// List the user memory of the context, sorted by address, with the number of resident,
+    /// shared, and swapped out pages of each region
+    pub fn mappings(&self) -> Vec<Mapping> {
+        let mut mappings = Vec::new();
+
+        for shared_mem in self.image.iter() {
+            shared_mem.with(|mem| {
+                mappings.push(Mapping::new(mem.start_address(), mem.size(), mem.flags(), MappingKind::Image));
+            });
+        }
+        if let Some(ref stack) = self.stack {
+            stack.with(|stack| {
+                mappings.push(Mapping::new(stack.start_address(), stack.size(), stack.flags(), MappingKind::Stack));
+            });
+        }
+        if let Some(ref sigstack) = self.sigstack {
+            mappings.push(Mapping::new(sigstack.start_address(), sigstack.size(), sigstack.flags(), MappingKind::Sigstack));
+        }
+        if let Some(ref tls) = self.tls {
+            mappings.push(Mapping::new(tls.mem.start_address(), tls.mem.size(), tls.mem.flags(), MappingKind::Tls));
+        }
+        for grant in self.grants.lock().iter() {
+            mappings.push(Mapping::from_grant(grant));
+        }
+
+        mappings.sort_by_key(|mapping| mapping.region.start_address().get());
+
+        let mut active_table = unsafe { ActivePageTable::new() };
+        let mut target_table = unsafe { InactivePageTable::from_address(self.arch.get_page_table()) };
+        let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(crate::USER_TMP_MISC_OFFSET)));
+        active_table.with(&mut target_table, &mut temporary_page, |mapper| {
+            for mapping in mappings.iter_mut() {
+                mapping.count_pages(mapper);
+            }
+        });
+
+        mappings
+    }
 }
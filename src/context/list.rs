@@ -4,12 +4,75 @@ use alloc::collections::BTreeMap;
 use core::alloc::{GlobalAlloc, Layout};
 use core::{iter, mem};
 use core::sync::atomic::Ordering;
-use crate::paging;
-use spin::RwLock;
+use crate::memory::allocate_frames;
+use crate::paging::{self, ActivePageTable, InactivePageTable, Page, VirtualAddress};
+use crate::paging::entry::EntryFlags;
+use crate::paging::temporary_page::TemporaryPage;
+use spin::{Mutex, RwLock};
 
-use crate::syscall::error::{Result, Error, EAGAIN};
+use crate::syscall::error::{Result, Error, EAGAIN, ENOMEM};
 use super::context::{Context, ContextId};
 
+/// Page table of the contexts started with `spawn_kernel`, mapping only the kernel
+static KERNEL_TABLE: Mutex<Option<usize>> = Mutex::new(None);
+
+/// Get the page table for kernel contexts, creating it the first time
+fn kernel_table() -> Result<usize> {
+    let mut table_opt = KERNEL_TABLE.lock();
+    if let Some(table) = *table_opt {
+        return Ok(table);
+    }
+
+    let frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(crate::USER_TMP_MISC_OFFSET)));
+    let mut new_table = InactivePageTable::new(frame, &mut active_table, &mut temporary_page);
+
+    // Copy kernel image, heap, and frame descriptor mappings
+    for &pml4 in [crate::KERNEL_PML4, crate::KERNEL_HEAP_PML4, crate::KERNEL_FRAMES_PML4].iter() {
+        if let Some(frame) = active_table.p4()[pml4].pointed_frame() {
+            let flags = active_table.p4()[pml4].flags();
+            active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+                mapper.p4_mut()[pml4].set(frame, flags);
+            });
+        }
+    }
+
+    // Copy percpu mapping
+    for cpu_id in 0..crate::cpu_count() {
+        extern {
+            // The starting byte of the thread data segment
+            static mut __tdata_start: u8;
+            // The ending byte of the thread BSS segment
+            static mut __tbss_end: u8;
+        }
+
+        let size = unsafe { & __tbss_end as *const _ as usize - & __tdata_start as *const _ as usize };
+
+        let start = crate::KERNEL_PERCPU_OFFSET + crate::KERNEL_PERCPU_SIZE * cpu_id;
+        let end = start + size;
+
+        let start_page = Page::containing_address(VirtualAddress::new(start));
+        let end_page = Page::containing_address(VirtualAddress::new(end - 1));
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = active_table.translate_page(page).expect("kernel percpu not mapped");
+            let mut res = Ok(());
+            active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+                res = mapper.try_map_to(page, frame, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE)
+                    // Ignore result due to operating on inactive table
+                    .map(|result| unsafe { result.ignore() });
+            });
+            // The table is leaked, as it may already hold page tables of its own
+            res?;
+        }
+    }
+
+    let table = unsafe { new_table.address() };
+    *table_opt = Some(table);
+    Ok(table)
+}
+
 /// Context list type
 pub struct ContextList {
     map: BTreeMap<ContextId, Arc<RwLock<Context>>>,
@@ -65,6 +128,9 @@ impl ContextList {
         let id = ContextId::from(self.next_id);
         self.next_id += 1;
 
+        // The scheduler queues contexts without allocating
+        super::run_queue::reserve(self.map.len() + 1);
+
         assert!(self.map.insert(id, Arc::new(RwLock::new(Context::new(id)))).is_none());
 
         Ok(self.map.get(&id).expect("Failed to insert new context. ID is out of bounds."))
@@ -95,6 +161,15 @@ impl ContextList {
         Ok(context_lock)
     }
 
+    /// Spawn a context from a function, running on a page table that maps nothing of the
+    /// userspace of the caller
+    pub fn spawn_kernel(&mut self, func: extern fn()) -> Result<&Arc<RwLock<Context>>> {
+        let table = kernel_table()?;
+        let context_lock = self.spawn(func)?;
+        context_lock.write().arch.set_page_table(table);
+        Ok(context_lock)
+    }
+
     pub fn remove(&mut self, id: ContextId) -> Option<Arc<RwLock<Context>>> {
         self.map.remove(&id)
     }
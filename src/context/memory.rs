@@ -1,9 +1,11 @@
 use alloc::collections::{BTreeSet, VecDeque};
+use alloc::vec::Vec;
 use alloc::sync::{Arc, Weak};
 use core::borrow::Borrow;
 use core::cmp::{self, Eq, Ordering, PartialEq, PartialOrd};
 use core::fmt::{self, Debug};
 use core::intrinsics;
+use core::mem;
 use core::ops::{Deref, DerefMut};
 use spin::Mutex;
 use syscall::{
@@ -13,11 +15,12 @@ use syscall::{
 
 use crate::arch::paging::PAGE_SIZE;
 use crate::context::file::FileDescriptor;
+use crate::context::{self, oom, swap};
 use crate::ipi::{ipi, IpiKind, IpiTarget};
-use crate::memory::Frame;
-use crate::paging::{ActivePageTable, InactivePageTable, Page, PageIter, PhysicalAddress, VirtualAddress};
+use crate::memory::{self, Frame};
+use crate::paging::{ActivePageTable, InactivePageTable, Page, PageIter, PageSize, PhysicalAddress, VirtualAddress};
 use crate::paging::entry::EntryFlags;
-use crate::paging::mapper::MapperFlushAll;
+use crate::paging::mapper::{Mapper, MapperFlushAll};
 use crate::paging::temporary_page::TemporaryPage;
 
 /// Round down to the nearest multiple of page size
@@ -295,7 +298,10 @@ pub struct Grant {
     region: Region,
     flags: EntryFlags,
     mapped: bool,
+    /// The frames were allocated for this grant, and are copied when it is cloned
     owned: bool,
+    /// The frames belong to another mapping, and the grant holds a reference to each of them
+    shared: bool,
     //TODO: This is probably a very heavy way to keep track of fmap'd files, perhaps move to the context?
     pub desc_opt: Option<FileDescriptor>,
 }
@@ -305,23 +311,30 @@ impl Grant {
         self.owned
     }
 
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
     /// Get a mutable reference to the region. This is unsafe, because a bad
     /// region could lead to the wrong addresses being unmapped.
     pub unsafe fn region_mut(&mut self) -> &mut Region {
         &mut self.region
     }
 
+    /// Map physical memory, using huge pages where both addresses are aligned for them
     pub fn physmap(from: PhysicalAddress, to: VirtualAddress, size: usize, flags: EntryFlags) -> Grant {
         let mut active_table = unsafe { ActivePageTable::new() };
 
         let mut flush_all = MapperFlushAll::new();
 
-        let start_page = Page::containing_address(to);
-        let end_page = Page::containing_address(VirtualAddress::new(to.get() + size - 1));
-        for page in Page::range_inclusive(start_page, end_page) {
-            let frame = Frame::containing_address(PhysicalAddress::new(page.start_address().get() - to.get() + from.get()));
-            let result = active_table.map_to(page, frame, flags);
+        let mut offset = 0;
+        while offset < size {
+            let page_size = PageSize::largest(to.get() + offset, from.get() + offset, size - offset);
+            let page = Page::containing_address(VirtualAddress::new(to.get() + offset));
+            let frame = Frame::containing_address(PhysicalAddress::new(from.get() + offset));
+            let result = active_table.map_to_size(page, frame, page_size, flags);
             flush_all.consume(result);
+            offset += page_size.size();
         }
 
         flush_all.flush(&mut active_table);
@@ -334,11 +347,13 @@ impl Grant {
             flags,
             mapped: true,
             owned: false,
+            shared: false,
             desc_opt: None,
         }
     }
 
-    pub fn map(to: VirtualAddress, size: usize, flags: EntryFlags) -> Grant {
+    /// Map anonymous memory. Frames are allocated and zeroed when each page is first accessed
+    pub fn map(to: VirtualAddress, size: usize, flags: EntryFlags) -> Result<Grant> {
         let mut active_table = unsafe { ActivePageTable::new() };
 
         let mut flush_all = MapperFlushAll::new();
@@ -346,13 +361,24 @@ impl Grant {
         let start_page = Page::containing_address(to);
         let end_page = Page::containing_address(VirtualAddress::new(to.get() + size - 1));
         for page in Page::range_inclusive(start_page, end_page) {
-            let result = active_table.map(page, flags);
-            flush_all.consume(result);
+            match active_table.try_map_lazy(page, flags) {
+                Ok(result) => flush_all.consume(result),
+                Err(err) => {
+                    // Give back the pages reserved so far
+                    for page in Page::range_inclusive(start_page, end_page).take_while(|&other| other != page) {
+                        let (result, _frame) = active_table.unmap_take(page, false);
+                        flush_all.consume(result);
+                    }
+                    flush_all.flush(&mut active_table);
+                    println!("Grant::map: out of memory mapping {} bytes at {:#x}", size, to.get());
+                    return Err(err);
+                }
+            }
         }
 
         flush_all.flush(&mut active_table);
 
-        Grant {
+        Ok(Grant {
             region: Region {
                 start: to,
                 size,
@@ -360,20 +386,95 @@ impl Grant {
             flags,
             mapped: true,
             owned: true,
+            shared: false,
             desc_opt: None,
+        })
+    }
+
+    /// Map anonymous memory that stays shared with the contexts this one is cloned into. Frames
+    /// are allocated and zeroed at once, so that every clone maps the same ones
+    pub fn map_shared(to: VirtualAddress, size: usize, flags: EntryFlags) -> Result<Grant> {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let mut flush_all = MapperFlushAll::new();
+
+        let start_page = Page::containing_address(to);
+        let end_page = Page::containing_address(VirtualAddress::new(to.get() + size - 1));
+        for page in Page::range_inclusive(start_page, end_page) {
+            // Writable until the frame is zeroed
+            match active_table.try_map(page, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE) {
+                Ok(result) => flush_all.consume(result),
+                Err(err) => {
+                    for page in Page::range_inclusive(start_page, end_page).take_while(|&other| other != page) {
+                        let result = active_table.unmap(page);
+                        flush_all.consume(result);
+                    }
+                    flush_all.flush(&mut active_table);
+                    println!("Grant::map_shared: out of memory mapping {} bytes at {:#x}", size, to.get());
+                    return Err(err);
+                }
+            }
+        }
+
+        flush_all.flush(&mut active_table);
+
+        unsafe {
+            intrinsics::write_bytes(to.get() as *mut u8, 0, size);
+        }
+
+        let mut flush_all = MapperFlushAll::new();
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            let result = active_table.remap(page, flags);
+            flush_all.consume(result);
         }
+
+        flush_all.flush(&mut active_table);
+
+        Ok(Grant {
+            region: Region {
+                start: to,
+                size,
+            },
+            flags,
+            mapped: true,
+            owned: false,
+            shared: true,
+            desc_opt: None,
+        })
     }
 
+    /// Share the frames of the active table at `from` with a new table at `to`. The grant holds a
+    /// reference to each frame, so they stay allocated until it is unmapped
     pub fn map_inactive(from: VirtualAddress, to: VirtualAddress, size: usize, flags: EntryFlags, desc_opt: Option<FileDescriptor>, new_table: &mut InactivePageTable, temporary_page: &mut TemporaryPage) -> Grant {
+        let start_page = Page::containing_address(from);
+        let end_page = Page::containing_address(VirtualAddress::new(from.get() + size - 1));
+
+        // Read swapped out pages back first, as the swap scheme may need the page table lock
+        for page in Page::range_inclusive(start_page, end_page) {
+            let swapped = unsafe { ActivePageTable::new() }.translate_swap_slot(page).is_some();
+            if swapped {
+                swap_fault(page.start_address());
+            }
+        }
+
         let mut active_table = unsafe { ActivePageTable::new() };
 
         //TODO: Do not allocate
         let mut frames = VecDeque::with_capacity(size/PAGE_SIZE);
 
-        let start_page = Page::containing_address(from);
-        let end_page = Page::containing_address(VirtualAddress::new(from.get() + size - 1));
         for page in Page::range_inclusive(start_page, end_page) {
+            // Both mappings have to see the same frame, so it can not be lazy or copied on write
+            let page_flags = active_table.translate_page_flags(page).expect("grant references unmapped memory");
+            if page_flags.contains(EntryFlags::LAZY) {
+                lazy_fault(page.start_address());
+            }
+            if page_flags.contains(EntryFlags::COPY_ON_WRITE) && flags.contains(EntryFlags::WRITABLE) {
+                cow_fault(page.start_address());
+            }
+
             let frame = active_table.translate_page(page).expect("grant references unmapped memory");
+            memory::ref_frame(&frame);
             frames.push_back(frame);
         }
 
@@ -398,12 +499,16 @@ impl Grant {
             flags,
             mapped: true,
             owned: false,
+            shared: true,
             desc_opt,
         }
     }
 
     /// This function should only be used in clone!
-    pub fn secret_clone(&self, new_start: VirtualAddress) -> Grant {
+    ///
+    /// Copy the grant to `new_start` in the same address space, failing with `ENOMEM` if frames
+    /// run out, in which case nothing is left mapped there
+    pub fn secret_clone(&self, new_start: VirtualAddress) -> Result<Grant> {
         assert!(self.mapped);
 
         let mut active_table = unsafe { ActivePageTable::new() };
@@ -412,35 +517,81 @@ impl Grant {
 
         let start_page = Page::containing_address(self.region.start);
         let end_page = Page::containing_address(VirtualAddress::new(self.region.start.get() + self.region.size - 1));
+        let mut copies = Vec::new();
+        let mut mapped_size = 0;
+        let mut res = Ok(());
         for page in Page::range_inclusive(start_page, end_page) {
             //TODO: One function to do both?
             let flags = active_table.translate_page_flags(page).expect("grant references unmapped memory");
-            let frame = active_table.translate_page(page).expect("grant references unmapped memory");
 
             let new_page = Page::containing_address(VirtualAddress::new(page.start_address().get() - self.region.start.get() + new_start.get()));
-            if self.owned {
-                let result = active_table.map(new_page, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
-                flush_all.consume(result);
+            let result = if flags.contains(EntryFlags::LAZY) {
+                // Not accessed yet, so there is nothing to copy
+                active_table.try_map_lazy(new_page, flags)
+            } else if let Some(slot) = active_table.translate_swap_slot(page) {
+                // Both mappings read their own copy back from the same slot
+                active_table.try_map_swapped(new_page, slot, flags).map(|result| {
+                    swap::ref_slot(slot);
+                    result
+                })
+            } else if self.owned {
+                active_table.try_map(new_page, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE).map(|result| {
+                    copies.push((page, new_page, flags));
+                    result
+                })
             } else {
-                let result = active_table.map_to(new_page, frame, flags);
-                flush_all.consume(result);
+                let frame = active_table.translate_page(page).expect("grant references unmapped memory");
+                if self.shared {
+                    memory::ref_frame(&frame);
+                }
+                active_table.try_map_to(new_page, frame, flags).map_err(|err| {
+                    if self.shared {
+                        let frame = active_table.translate_page(page).expect("grant references unmapped memory");
+                        memory::unref_frame(frame);
+                    }
+                    err
+                })
+            };
+
+            match result {
+                Ok(result) => flush_all.consume(result),
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
             }
+            mapped_size += PAGE_SIZE;
         }
 
         flush_all.flush(&mut active_table);
 
-        if self.owned {
-            unsafe {
-                intrinsics::copy(self.region.start.get() as *const u8, new_start.get() as *mut u8, self.region.size);
+        if let Err(err) = res {
+            // Undo the pages mapped so far like any other grant
+            if mapped_size > 0 {
+                Grant {
+                    region: Region {
+                        start: new_start,
+                        size: mapped_size,
+                    },
+                    flags: self.flags,
+                    mapped: true,
+                    owned: self.owned,
+                    shared: self.shared,
+                    desc_opt: None,
+                }.unmap();
             }
+            println!("Grant::secret_clone: out of memory copying {} bytes", self.region.size);
+            return Err(err);
+        }
 
+        if ! copies.is_empty() {
             let mut flush_all = MapperFlushAll::new();
 
-            for page in Page::range_inclusive(start_page, end_page) {
-                //TODO: One function to do both?
-                let flags = active_table.translate_page_flags(page).expect("grant references unmapped memory");
+            for (page, new_page, flags) in copies {
+                unsafe {
+                    intrinsics::copy(page.start_address().get() as *const u8, new_page.start_address().get() as *mut u8, PAGE_SIZE);
+                }
 
-                let new_page = Page::containing_address(VirtualAddress::new(page.start_address().get() - self.region.start.get() + new_start.get()));
                 let result = active_table.remap(new_page, flags);
                 flush_all.consume(result);
             }
@@ -448,7 +599,7 @@ impl Grant {
             flush_all.flush(&mut active_table);
         }
 
-        Grant {
+        Ok(Grant {
             region: Region {
                 start: new_start,
                 size: self.region.size,
@@ -456,8 +607,9 @@ impl Grant {
             flags: self.flags,
             mapped: true,
             owned: self.owned,
+            shared: self.shared,
             desc_opt: self.desc_opt.clone()
-        }
+        })
     }
 
     pub fn move_to(&mut self, new_start: VirtualAddress, new_table: &mut InactivePageTable, temporary_page: &mut TemporaryPage) {
@@ -472,12 +624,21 @@ impl Grant {
         for page in Page::range_inclusive(start_page, end_page) {
             //TODO: One function to do both?
             let flags = active_table.translate_page_flags(page).expect("grant references unmapped memory");
-            let (result, frame) = active_table.unmap_return(page, false);
+            // The reference of the old entry to a swap slot is released when it is unmapped
+            let slot_opt = active_table.translate_swap_slot(page);
+            if let Some(slot) = slot_opt {
+                swap::ref_slot(slot);
+            }
+            let (result, frame_opt) = active_table.unmap_take(page, false);
             flush_all.consume(result);
 
             active_table.with(new_table, temporary_page, |mapper| {
                 let new_page = Page::containing_address(VirtualAddress::new(page.start_address().get() - self.region.start.get() + new_start.get()));
-                let result = mapper.map_to(new_page, frame, flags);
+                let result = match (frame_opt, slot_opt) {
+                    (Some(frame), _) => mapper.map_to(new_page, frame, flags),
+                    (None, Some(slot)) => mapper.map_swapped(new_page, slot, flags),
+                    (None, None) => mapper.map_lazy(new_page, flags),
+                };
                 // Ignore result due to mapping on inactive table
                 unsafe { result.ignore(); }
             });
@@ -496,6 +657,24 @@ impl Grant {
         self.mapped = mapped;
     }
 
+    /// Get the number of pages in the huge page starting at `page`, if it ends by `end_page`
+    fn huge_pages(&self, mapper: &Mapper, page: Page, end_page: Page) -> Option<usize> {
+        if self.owned || self.shared {
+            return None;
+        }
+        let pages = match mapper.translate_page_size(page)? {
+            PageSize::Small => return None,
+            page_size => page_size.pages(),
+        };
+        let aligned = page.start_address().get() % (pages * PAGE_SIZE) == 0;
+        let last = Page::containing_address(VirtualAddress::new(page.start_address().get() + (pages - 1) * PAGE_SIZE));
+        if aligned && last <= end_page {
+            Some(pages)
+        } else {
+            None
+        }
+    }
+
     pub fn unmap(mut self) {
         assert!(self.mapped);
 
@@ -505,11 +684,24 @@ impl Grant {
 
         let start_page = Page::containing_address(self.start_address());
         let end_page = Page::containing_address(self.final_address());
+        let mut skip = 0;
         for page in Page::range_inclusive(start_page, end_page) {
-            let (result, frame) = active_table.unmap_return(page, false);
-            if self.owned {
-                //TODO: make sure this frame can be safely freed, physical use counter
-                crate::memory::deallocate_frames(frame, 1);
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+
+            // Huge pages of physmaps are unmapped whole, unless the grant only has part of them
+            if let Some(pages) = self.huge_pages(&active_table, page, end_page) {
+                let result = active_table.unmap_huge(page);
+                flush_all.consume(result);
+                skip = pages - 1;
+                continue;
+            }
+
+            let (result, frame_opt) = active_table.unmap_take(page, false);
+            if let Some(frame) = frame_opt.filter(|_| self.owned || self.shared) {
+                memory::unref_frame(frame);
             }
             flush_all.consume(result);
         }
@@ -533,11 +725,24 @@ impl Grant {
         active_table.with(new_table, temporary_page, |mapper| {
             let start_page = Page::containing_address(self.start_address());
             let end_page = Page::containing_address(self.final_address());
+            let mut skip = 0;
             for page in Page::range_inclusive(start_page, end_page) {
-                let (result, frame) = mapper.unmap_return(page, false);
-                if self.owned {
-                    //TODO: make sure this frame can be safely freed, physical use counter
-                    crate::memory::deallocate_frames(frame, 1);
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+
+                if let Some(pages) = self.huge_pages(mapper, page, end_page) {
+                    let result = mapper.unmap_huge(page);
+                    // This is not the active table, so the flush can be ignored
+                    unsafe { result.ignore(); }
+                    skip = pages - 1;
+                    continue;
+                }
+
+                let (result, frame_opt) = mapper.unmap_take(page, false);
+                if let Some(frame) = frame_opt.filter(|_| self.owned || self.shared) {
+                    memory::unref_frame(frame);
                 }
                 // This is not the active table, so the flush can be ignored
                 unsafe { result.ignore(); }
@@ -577,6 +782,7 @@ impl Grant {
             flags: self.flags,
             mapped: self.mapped,
             owned: self.owned,
+            shared: self.shared,
             desc_opt: self.desc_opt.clone(),
         });
         let after_grant = self.after(region).map(|region| Grant {
@@ -584,6 +790,7 @@ impl Grant {
             flags: self.flags,
             mapped: self.mapped,
             owned: self.owned,
+            shared: self.shared,
             desc_opt: self.desc_opt.clone(),
         });
 
@@ -631,6 +838,46 @@ impl Drop for Grant {
     }
 }
 
+/// Map anonymous memory in the current context, at `address` or anywhere if it is zero.
+/// Private mappings get their frames when first accessed, and are copied when the context is
+/// cloned. Shared mappings get their frames at once, and keep them when the context is cloned
+pub fn map_anonymous(address: usize, size: usize, flags: MapFlags) -> Result<usize> {
+    if size == 0 {
+        return Ok(0);
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+
+    let mut grants = context.grants.lock();
+
+    let region = grants.find_free_at(VirtualAddress::new(address), size, flags)?.round();
+
+    {
+        // Make sure it's *absolutely* not mapped already
+        // TODO: Keep track of all allocated memory so this isn't necessary
+
+        let active_table = unsafe { ActivePageTable::new() };
+
+        for page in region.pages() {
+            if active_table.is_mapped(page) {
+                println!("page at {:#x} was already mapped", page.start_address().get());
+                return Err(Error::new(EEXIST))
+            }
+        }
+    }
+
+    let grant = if flags.contains(MapFlags::MAP_SHARED) {
+        Grant::map_shared(region.start_address(), region.size(), entry_flags(flags))?
+    } else {
+        Grant::map(region.start_address(), region.size(), entry_flags(flags))?
+    };
+    grants.insert(grant);
+
+    Ok(region.start_address().get())
+}
+
 #[derive(Clone, Debug)]
 pub enum SharedMemory {
     Owned(Arc<Mutex<Memory>>),
@@ -652,6 +899,21 @@ impl SharedMemory {
         }
     }
 
+    /// Like `with`, but returns `None` instead of waiting if the memory is locked
+    pub fn try_with<F, T>(&self, f: F) -> Option<T> where F: FnOnce(&mut Memory) -> T {
+        match *self {
+            SharedMemory::Owned(ref memory_lock) => {
+                let mut memory = memory_lock.try_lock()?;
+                Some(f(&mut *memory))
+            },
+            SharedMemory::Borrowed(ref memory_weak) => {
+                let memory_lock = memory_weak.upgrade()?;
+                let mut memory = memory_lock.try_lock()?;
+                Some(f(&mut *memory))
+            }
+        }
+    }
+
     pub fn borrow(&self) -> SharedMemory {
         match *self {
             SharedMemory::Owned(ref memory_lock) => SharedMemory::Borrowed(Arc::downgrade(memory_lock)),
@@ -668,16 +930,55 @@ pub struct Memory {
 }
 
 impl Memory {
-    pub fn new(start: VirtualAddress, size: usize, flags: EntryFlags, clear: bool) -> Self {
+    /// Map memory, failing with `ENOMEM` if there are not enough frames
+    pub fn new(start: VirtualAddress, size: usize, flags: EntryFlags, clear: bool) -> Result<Self> {
         let mut memory = Memory {
             start,
             size,
             flags,
         };
 
-        memory.map(clear);
+        if let Err(err) = memory.map(clear) {
+            // Nothing is left mapped
+            mem::forget(memory);
+            println!("Memory::new: out of memory mapping {} bytes at {:#x}", size, start.get());
+            return Err(err);
+        }
 
-        memory
+        Ok(memory)
+    }
+
+    /// Create memory whose pages get a zeroed frame when they are first accessed
+    pub fn new_lazy(start: VirtualAddress, size: usize, flags: EntryFlags) -> Result<Self> {
+        let memory = Memory {
+            start,
+            size,
+            flags,
+        };
+
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let mut flush_all = MapperFlushAll::new();
+
+        for page in memory.pages() {
+            match active_table.try_map_lazy(page, flags) {
+                Ok(result) => flush_all.consume(result),
+                Err(err) => {
+                    for page in memory.pages().take_while(|&other| other != page) {
+                        let (result, _frame) = active_table.unmap_take(page, false);
+                        flush_all.consume(result);
+                    }
+                    flush_all.flush(&mut active_table);
+                    mem::forget(memory);
+                    println!("Memory::new_lazy: out of memory mapping {} bytes at {:#x}", size, start.get());
+                    return Err(err);
+                }
+            }
+        }
+
+        flush_all.flush(&mut active_table);
+
+        Ok(memory)
     }
 
     pub fn to_shared(self) -> SharedMemory {
@@ -702,14 +1003,25 @@ impl Memory {
         Page::range_inclusive(start_page, end_page)
     }
 
-    fn map(&mut self, clear: bool) {
+    /// Map every page to a new frame. If the frames run out, the pages mapped so far are
+    /// unmapped again
+    fn map(&mut self, clear: bool) -> Result<()> {
         let mut active_table = unsafe { ActivePageTable::new() };
 
         let mut flush_all = MapperFlushAll::new();
 
         for page in self.pages() {
-            let result = active_table.map(page, self.flags);
-            flush_all.consume(result);
+            match active_table.try_map(page, self.flags) {
+                Ok(result) => flush_all.consume(result),
+                Err(err) => {
+                    for page in self.pages().take_while(|&other| other != page) {
+                        let result = active_table.unmap(page);
+                        flush_all.consume(result);
+                    }
+                    flush_all.flush(&mut active_table);
+                    return Err(err);
+                }
+            }
         }
 
         flush_all.flush(&mut active_table);
@@ -720,6 +1032,8 @@ impl Memory {
                 intrinsics::write_bytes(self.start_address().get() as *mut u8, 0, self.size);
             }
         }
+
+        Ok(())
     }
 
     fn unmap(&mut self) {
@@ -743,12 +1057,23 @@ impl Memory {
         let mut flush_all = MapperFlushAll::new();
 
         for page in self.pages() {
-            let (result, frame) = active_table.unmap_return(page, false);
+            // Keep the flags of the entry, as copy-on-write pages are mapped read-only
+            let flags = active_table.translate_page_flags(page).unwrap_or(self.flags);
+            // The reference of the old entry to a swap slot is released when it is unmapped
+            let slot_opt = active_table.translate_swap_slot(page);
+            if let Some(slot) = slot_opt {
+                swap::ref_slot(slot);
+            }
+            let (result, frame_opt) = active_table.unmap_take(page, false);
             flush_all.consume(result);
 
             active_table.with(new_table, temporary_page, |mapper| {
                 let new_page = Page::containing_address(VirtualAddress::new(page.start_address().get() - self.start.get() + new_start.get()));
-                let result = mapper.map_to(new_page, frame, self.flags);
+                let result = match (frame_opt, slot_opt) {
+                    (Some(frame), _) => mapper.map_to(new_page, frame, flags),
+                    (None, Some(slot)) => mapper.map_swapped(new_page, slot, flags),
+                    (None, None) => mapper.map_lazy(new_page, flags),
+                };
                 // This is not the active table, so the flush can be ignored
                 unsafe { result.ignore(); }
             });
@@ -759,13 +1084,68 @@ impl Memory {
         self.start = new_start;
     }
 
+    /// Share the frames of this memory with a new mapping at `new_start`. Writable pages become
+    /// copy-on-write in both mappings, and are copied by the page fault handler on first write
+    pub fn cow_clone(&self, new_start: VirtualAddress) -> Memory {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let mut flush_all = MapperFlushAll::new();
+
+        for page in self.pages() {
+            let mut flags = active_table.translate_page_flags(page).expect("cow_clone: page not mapped");
+            let new_page = Page::containing_address(VirtualAddress::new(page.start_address().get() - self.start.get() + new_start.get()));
+            if flags.contains(EntryFlags::LAZY) {
+                // Not accessed yet, so both mappings can get their own frame on first access
+                let result = active_table.map_lazy(new_page, flags);
+                flush_all.consume(result);
+                continue;
+            }
+            if let Some(slot) = active_table.translate_swap_slot(page) {
+                // Both mappings read their own copy back from the same slot
+                swap::ref_slot(slot);
+                let result = active_table.map_swapped(new_page, slot, flags);
+                flush_all.consume(result);
+                continue;
+            }
+
+            let frame = active_table.translate_page(page).expect("cow_clone: page not mapped");
+            if flags.contains(EntryFlags::WRITABLE) {
+                flags = (flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE;
+                let result = active_table.remap(page, flags);
+                flush_all.consume(result);
+            }
+
+            memory::ref_frame(&frame);
+            let result = active_table.map_to(new_page, frame, flags);
+            flush_all.consume(result);
+        }
+
+        flush_all.flush(&mut active_table);
+
+        // Threads sharing this address space may still have writable entries cached
+        ipi(IpiKind::Tlb, IpiTarget::Other);
+
+        Memory {
+            start: new_start,
+            size: self.size,
+            flags: self.flags,
+        }
+    }
+
     pub fn remap(&mut self, new_flags: EntryFlags) {
         let mut active_table = unsafe { ActivePageTable::new() };
 
         let mut flush_all = MapperFlushAll::new();
 
         for page in self.pages() {
-            let result = active_table.remap(page, new_flags);
+            // Copy-on-write pages stay read-only until they are written to
+            let cow = active_table.translate_page_flags(page).map_or(false, |flags| flags.contains(EntryFlags::COPY_ON_WRITE));
+            let flags = if cow && new_flags.contains(EntryFlags::WRITABLE) {
+                (new_flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE
+            } else {
+                new_flags
+            };
+            let result = active_table.remap(page, flags);
             flush_all.consume(result);
         }
 
@@ -774,7 +1154,9 @@ impl Memory {
         self.flags = new_flags;
     }
 
-    pub fn resize(&mut self, new_size: usize, clear: bool) {
+    /// Grow or shrink the memory, failing with `ENOMEM` if frames run out while growing, in
+    /// which case its size is unchanged
+    pub fn resize(&mut self, new_size: usize, clear: bool) -> Result<()> {
         let mut active_table = unsafe { ActivePageTable::new() };
 
         //TODO: Calculate page changes to minimize operations
@@ -783,10 +1165,26 @@ impl Memory {
 
             let start_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size));
             let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + new_size - 1));
+            let mut mapped = Vec::new();
             for page in Page::range_inclusive(start_page, end_page) {
-                if active_table.translate_page(page).is_none() {
-                    let result = active_table.map(page, self.flags);
-                    flush_all.consume(result);
+                if ! active_table.is_mapped(page) {
+                    match active_table.try_map(page, self.flags) {
+                        Ok(result) => {
+                            flush_all.consume(result);
+                            mapped.push(page);
+                        },
+                        Err(err) => {
+                            for page in mapped {
+                                let (result, frame_opt) = active_table.unmap_take(page, false);
+                                if let Some(frame) = frame_opt {
+                                    memory::unref_frame(frame);
+                                }
+                                flush_all.consume(result);
+                            }
+                            flush_all.flush(&mut active_table);
+                            return Err(err);
+                        }
+                    }
                 }
             }
 
@@ -803,7 +1201,7 @@ impl Memory {
             let start_page = Page::containing_address(VirtualAddress::new(self.start.get() + new_size));
             let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size - 1));
             for page in Page::range_inclusive(start_page, end_page) {
-                if active_table.translate_page(page).is_some() {
+                if active_table.is_mapped(page) {
                     let result = active_table.unmap(page);
                     flush_all.consume(result);
                 }
@@ -813,6 +1211,7 @@ impl Memory {
         }
 
         self.size = new_size;
+        Ok(())
     }
 }
 
@@ -822,6 +1221,296 @@ impl Drop for Memory {
     }
 }
 
+/// Serializes the resolution of page faults, so that threads sharing an address space do not
+/// populate or copy the same page twice
+static FAULT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Resolve an access to a lazily allocated page of the active page table, giving it a zeroed
+/// frame. Returns false if the page is not lazily allocated, in which case the fault is a real
+/// page fault. If there is no frame left, the OOM killer is run and the access is retried once
+/// a victim was killed or pages were swapped out
+pub fn lazy_fault(address: VirtualAddress) -> bool {
+    let guard = FAULT_LOCK.lock();
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let page = Page::containing_address(address);
+    let flags = match active_table.translate_page_flags(page) {
+        Some(flags) if flags.contains(EntryFlags::LAZY) && ! flags.contains(EntryFlags::PRESENT) => flags,
+        Some(flags) if flags.contains(EntryFlags::PRESENT) => {
+            // Another CPU populated the page while this one used a stale entry
+            active_table.flush(page);
+            return true;
+        },
+        _ => return false,
+    };
+
+    let frame = match memory::allocate_frames(1) {
+        Some(frame) => frame,
+        None => {
+            drop(active_table);
+            drop(guard);
+            return fault_out_of_memory();
+        }
+    };
+
+    let (result, _frame) = active_table.unmap_take(page, true);
+    result.flush(&mut active_table);
+
+    // Map the page writable to clear it, as the kernel cannot write to read-only pages
+    let result = active_table.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+    result.flush(&mut active_table);
+
+    unsafe {
+        intrinsics::write_bytes(page.start_address().get() as *mut u8, 0, PAGE_SIZE);
+    }
+
+    let result = active_table.remap(page, flags - EntryFlags::LAZY);
+    result.flush(&mut active_table);
+
+    true
+}
+
+/// Resolve a write to a copy-on-write page of the active page table, giving it a private
+/// writable frame. Returns false if the page is not copy-on-write, in which case the write
+/// fault is a real protection fault. If there is no frame left, the OOM killer is run and the
+/// write is retried once a victim was killed or pages were swapped out
+pub fn cow_fault(address: VirtualAddress) -> bool {
+    let guard = FAULT_LOCK.lock();
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let page = Page::containing_address(address);
+    let flags = match active_table.translate_page_flags(page) {
+        Some(flags) if flags.contains(EntryFlags::PRESENT) => flags,
+        _ => return false,
+    };
+
+    if ! flags.contains(EntryFlags::COPY_ON_WRITE) {
+        // Another CPU may have resolved the fault while this one used a stale entry
+        if flags.contains(EntryFlags::WRITABLE) {
+            active_table.flush(page);
+            return true;
+        }
+        return false;
+    }
+
+    let new_flags = (flags - EntryFlags::COPY_ON_WRITE) | EntryFlags::WRITABLE;
+    let frame = active_table.translate_page(page).expect("cow_fault: page not mapped");
+    if memory::frame_refcount(&frame) > 1 {
+        let new_frame = match memory::allocate_frames(1) {
+            Some(new_frame) => new_frame,
+            None => {
+                drop(active_table);
+                drop(guard);
+                return fault_out_of_memory();
+            }
+        };
+
+        let mut data = vec![0u8; PAGE_SIZE].into_boxed_slice();
+        unsafe {
+            intrinsics::copy(page.start_address().get() as *const u8, data.as_mut_ptr(), PAGE_SIZE);
+        }
+
+        let (result, frame) = active_table.unmap_return(page, true);
+        result.flush(&mut active_table);
+        memory::unref_frame(frame);
+
+        let result = active_table.map_to(page, new_frame, new_flags);
+        result.flush(&mut active_table);
+
+        unsafe {
+            intrinsics::copy(data.as_ptr(), page.start_address().get() as *mut u8, PAGE_SIZE);
+        }
+    } else {
+        // The other mappings are gone, so the frame can be written in place
+        let result = active_table.remap(page, new_flags);
+        result.flush(&mut active_table);
+    }
+
+    // Other threads of this address space may have the shared frame cached
+    ipi(IpiKind::Tlb, IpiTarget::Other);
+
+    true
+}
+
+/// Read a swapped out page of the active page table back from swap. Returns false if the page
+/// is not swapped out, in which case the fault is a real page fault, or if it can not be read
+pub fn swap_fault(address: VirtualAddress) -> bool {
+    let page = Page::containing_address(address);
+
+    let slot = {
+        let _guard = FAULT_LOCK.lock();
+
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        if active_table.translate_page_flags(page).map_or(false, |flags| flags.contains(EntryFlags::PRESENT)) {
+            // Another CPU read the page back while this one used a stale entry
+            active_table.flush(page);
+            return true;
+        }
+
+        match active_table.translate_swap_slot(page) {
+            Some(slot) => slot,
+            None => return false,
+        }
+    };
+
+    // The swap scheme has to run to read the page, so no locks are held meanwhile
+    let mut data = swap::SwapPage::new();
+    if let Err(err) = swap::read_slot(slot, &mut data) {
+        println!("swap_fault: failed to read slot {}: {}", slot, err);
+        return false;
+    }
+
+    let frame = match memory::allocate_frames(1) {
+        Some(frame) => frame,
+        None => return fault_out_of_memory(),
+    };
+
+    let _guard = FAULT_LOCK.lock();
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    // Another thread may have read the page back, or unmapped it, meanwhile
+    let flags = match active_table.translate_page_flags(page) {
+        Some(flags) if active_table.translate_swap_slot(page) == Some(slot) => flags,
+        _ => {
+            memory::deallocate_frames(frame, 1);
+            return true;
+        }
+    };
+
+    // Unmapping the entry releases its reference to the slot
+    let (result, _frame) = active_table.unmap_take(page, true);
+    result.flush(&mut active_table);
+
+    // Map the page writable to fill it, as the kernel cannot write to read-only pages
+    let result = active_table.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+    result.flush(&mut active_table);
+
+    unsafe {
+        intrinsics::copy(data.0.as_ptr(), page.start_address().get() as *mut u8, PAGE_SIZE);
+    }
+
+    // Mark the page accessed, so that the next scan does not evict it again right away
+    let result = active_table.remap(page, (flags - EntryFlags::SWAPPED) | EntryFlags::ACCESSED);
+    result.flush(&mut active_table);
+
+    true
+}
+
+/// Handle running out of frames in a page fault handler. Returns true if the access should be
+/// retried, after letting the OOM victim or the swap daemon run
+fn fault_out_of_memory() -> bool {
+    if oom::out_of_memory() {
+        unsafe { context::switch(); }
+        true
+    } else {
+        false
+    }
+}
+
+/// What a region of an address space is used for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MappingKind {
+    Image,
+    Stack,
+    Sigstack,
+    Tls,
+    /// Anonymous memory
+    Grant,
+    /// Memory shared with another address space
+    Shared,
+    /// A file mapped with `fmap`
+    Fmap,
+    /// Physical memory mapped with `physmap`
+    Physmap,
+}
+
+impl MappingKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            MappingKind::Image => "image",
+            MappingKind::Stack => "stack",
+            MappingKind::Sigstack => "sigstack",
+            MappingKind::Tls => "tls",
+            MappingKind::Grant => "grant",
+            MappingKind::Shared => "shared",
+            MappingKind::Fmap => "fmap",
+            MappingKind::Physmap => "physmap",
+        }
+    }
+}
+
+/// A region of a user address space, with the number of its pages that are resident, resident
+/// and shared with another mapping, and swapped out
+#[derive(Debug)]
+pub struct Mapping {
+    pub region: Region,
+    pub flags: EntryFlags,
+    pub kind: MappingKind,
+    pub resident: usize,
+    pub shared: usize,
+    pub swapped: usize,
+}
+
+impl Mapping {
+    pub fn new(start: VirtualAddress, size: usize, flags: EntryFlags, kind: MappingKind) -> Self {
+        Mapping {
+            region: Region::new(start, size),
+            flags,
+            kind,
+            resident: 0,
+            shared: 0,
+            swapped: 0,
+        }
+    }
+
+    pub fn from_grant(grant: &Grant) -> Self {
+        let kind = if grant.desc_opt.is_some() {
+            MappingKind::Fmap
+        } else if grant.is_owned() {
+            MappingKind::Grant
+        } else if grant.is_shared() {
+            MappingKind::Shared
+        } else {
+            MappingKind::Physmap
+        };
+        Mapping::new(grant.start_address(), grant.size(), grant.flags(), kind)
+    }
+
+    /// Count the pages of the region in a page table
+    pub fn count_pages(&mut self, mapper: &Mapper) {
+        self.resident = 0;
+        self.shared = 0;
+        self.swapped = 0;
+
+        if self.region.is_empty() {
+            return;
+        }
+
+        let start_page = Page::containing_address(self.region.start_address());
+        let end_page = Page::containing_address(self.region.final_address());
+        for page in Page::range_inclusive(start_page, end_page) {
+            match mapper.translate_page_flags(page) {
+                Some(flags) if flags.contains(EntryFlags::PRESENT) => {
+                    self.resident += 1;
+                    if let Some(frame) = mapper.translate_page(page) {
+                        if memory::frame_refcount(&frame) > 1 {
+                            self.shared += 1;
+                        }
+                    }
+                },
+                Some(flags) if flags.contains(EntryFlags::SWAPPED) => {
+                    self.swapped += 1;
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Tls {
     pub master: VirtualAddress,
@@ -6,9 +6,10 @@ use core::alloc::{GlobalAlloc, Layout};
 use core::sync::atomic::Ordering;
 use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-pub use self::context::{Context, ContextId, ContextSnapshot, Status, WaitpidKey};
+pub use self::context::{Context, ContextId, ContextSnapshot, SchedPolicy, Status, WaitReason, WaitpidKey};
+pub use self::context::{RT_PRIO_MAX, RT_PRIO_MIN, SCHED_FIFO, SCHED_OTHER, SCHED_RR};
 pub use self::list::ContextList;
-pub use self::switch::switch;
+pub use self::switch::{switch, nice_weight, preempt, time_slice, wake_sleepers, NICE_MAX, NICE_MIN};
 
 #[path = "arch/x86_64.rs"]
 mod arch;
@@ -22,15 +23,24 @@ mod list;
 /// Context switch function
 mod switch;
 
+/// Per-CPU run queues
+pub mod run_queue;
+
 /// File struct - defines a scheme and a file number
 pub mod file;
 
 /// Memory struct - contains a set of pages for a context
 pub mod memory;
 
+/// Out of memory handling
+pub mod oom;
+
 /// Signal handling
 pub mod signal;
 
+/// Swapping out user memory
+pub mod swap;
+
 /// Timeout handling
 pub mod timeout;
 
@@ -60,6 +70,7 @@ pub fn init() {
     context.status = Status::Runnable;
     context.running = true;
     context.cpu_id = Some(crate::cpu_id());
+    run_queue::run_queue(crate::cpu_id()).set_idle(context.id);
     CONTEXT_ID.store(context.id, Ordering::SeqCst);
 }
 
@@ -75,6 +86,11 @@ pub fn contexts() -> RwLockReadGuard<'static, ContextList> {
     CONTEXTS.call_once(init_contexts).read()
 }
 
+/// Get the global schemes list, const, or `None` if it is locked for writing
+pub fn try_contexts() -> Option<RwLockReadGuard<'static, ContextList>> {
+    CONTEXTS.call_once(init_contexts).try_read()
+}
+
 /// Get the global schemes list, mutable
 pub fn contexts_mut() -> RwLockWriteGuard<'static, ContextList> {
     CONTEXTS.call_once(init_contexts).write()
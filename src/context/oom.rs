@@ -0,0 +1,222 @@
+//! # Out of memory handling
+//! When physical memory runs out, the process using the most memory is killed so that the
+//! memory it frees lets everything else continue. Memory can run out while any lock is held,
+//! including the run queue locks, so `out_of_memory` only takes `try_` locks, skips contexts
+//! that are locked, and neither allocates nor wakes anything. It records the victim, which
+//! `wake_victims` signals and unblocks on the next timer tick. The victim reports itself with
+//! `report` once it is exiting, where printing cannot deadlock. While pages can still be
+//! swapped out, nothing is killed.
+
+use alloc::sync::Arc;
+use core::str;
+use spin::Mutex;
+
+use crate::context::{self, contexts, swap, Context, ContextId, Status};
+use crate::context::memory::UserGrants;
+use crate::syscall::flag::SIGKILL;
+
+/// The address space of the victim that has not been signalled yet
+static VICTIM: Mutex<Option<Arc<Mutex<UserGrants>>>> = Mutex::new(None);
+/// The context killed last and its memory use in bytes, if known, until it reports itself
+static KILLED: Mutex<Option<(ContextId, Option<usize>)>> = Mutex::new(None);
+
+/// Estimate the memory used by a context in bytes, from its image, stacks, TLS, and owned
+/// grants. Returns `None` if part of it is locked
+fn score(context: &Context) -> Option<usize> {
+    let mut size = 0;
+    for shared_mem in context.image.iter() {
+        size += shared_mem.try_with(|mem| mem.size())?;
+    }
+    if let Some(ref stack) = context.stack {
+        size += stack.try_with(|stack| stack.size())?;
+    }
+    if let Some(ref sigstack) = context.sigstack {
+        size += sigstack.size();
+    }
+    if let Some(ref tls) = context.tls {
+        size += tls.mem.size();
+    }
+    for grant in context.grants.try_lock()?.iter() {
+        if grant.is_owned() {
+            size += grant.size();
+        }
+    }
+    Some(size)
+}
+
+/// Pick a victim by its memory use, to be killed with the other threads sharing its address
+/// space by `wake_victims`. Returns true if memory is going to be freed, either by this victim
+/// or by one that was picked before and has not exited yet
+pub fn out_of_memory() -> bool {
+    // Nothing has to be killed while the swap daemon can still evict pages
+    if swap::reclaiming() {
+        return true;
+    }
+
+    let mut victim_grants = match VICTIM.try_lock() {
+        Some(victim_grants) => victim_grants,
+        None => return true,
+    };
+    if victim_grants.is_some() {
+        return true;
+    }
+
+    let contexts = match context::try_contexts() {
+        Some(contexts) => contexts,
+        None => return false,
+    };
+
+    let mut victim: Option<(ContextId, usize)> = None;
+    for (id, context_lock) in contexts.iter() {
+        // Never kill the kernel or init
+        if id.into() <= 2 {
+            continue;
+        }
+
+        let context = match context_lock.try_read() {
+            Some(context) => context,
+            None => continue,
+        };
+
+        // Kernel contexts have no image, and exited contexts have already freed theirs
+        if context.image.is_empty() {
+            continue;
+        }
+        if let Status::Exited(_) = context.status {
+            continue;
+        }
+
+        // A previous victim frees its memory as soon as it runs
+        if context.pending.contains(&(SIGKILL as u8)) {
+            return true;
+        }
+
+        if let Some(score) = score(&context) {
+            if victim.map_or(true, |(_, victim_score)| score > victim_score) {
+                victim = Some((*id, score));
+            }
+        }
+    }
+
+    let (victim_id, victim_score) = match victim {
+        Some(victim) => victim,
+        None => return false,
+    };
+
+    match contexts.get(victim_id).and_then(|context_lock| context_lock.try_read()) {
+        Some(context) => {
+            if let Some(mut killed) = KILLED.try_lock() {
+                *killed = Some((victim_id, Some(victim_score)));
+            }
+            *victim_grants = Some(Arc::clone(&context.grants));
+            true
+        },
+        None => false,
+    }
+}
+
+/// Send SIGKILL to the threads of the victim picked by `out_of_memory`, unblocking them so
+/// that they exit. Called from the timer interrupt, outside of any allocation
+pub fn wake_victims() {
+    let mut victim_grants = match VICTIM.try_lock() {
+        Some(victim_grants) => victim_grants,
+        None => return,
+    };
+    let grants = match victim_grants.take() {
+        Some(grants) => grants,
+        None => return,
+    };
+    let contexts = match context::try_contexts() {
+        Some(contexts) => contexts,
+        None => {
+            // Try again on the next tick
+            *victim_grants = Some(grants);
+            return;
+        }
+    };
+
+    let mut done = true;
+    for (_id, context_lock) in contexts.iter() {
+        // The interrupted code may hold the lock of any context
+        let mut context = match context_lock.try_write() {
+            Some(context) => context,
+            None => {
+                done = false;
+                continue;
+            }
+        };
+        let exited = if let Status::Exited(_) = context.status { true } else { false };
+        if Arc::ptr_eq(&context.grants, &grants) && ! exited && ! context.pending.contains(&(SIGKILL as u8)) {
+            context.pending.push_back(SIGKILL as u8);
+            if context.status == Status::Blocked {
+                context.unblock();
+            }
+        }
+    }
+
+    if ! done {
+        // Try the skipped threads again on the next tick
+        *victim_grants = Some(grants);
+    }
+}
+
+/// Mark the current context with a pending SIGKILL when a kernel heap allocation made for it
+/// cannot be served, so that it exits when it next returns to userspace. Like `out_of_memory`,
+/// this only takes `try_` locks and does not allocate. Returns false for kernel contexts, or if
+/// the context is locked
+pub fn kill_current() -> bool {
+    let contexts = match context::try_contexts() {
+        Some(contexts) => contexts,
+        None => return false,
+    };
+    let mut context = match contexts.current().and_then(|context_lock| context_lock.try_write()) {
+        Some(context) => context,
+        None => return false,
+    };
+
+    if context.image.is_empty() {
+        return false;
+    }
+    if context.pending.contains(&(SIGKILL as u8)) {
+        return true;
+    }
+    // Growing the signal queue would allocate
+    if context.pending.len() >= context.pending.capacity() {
+        return false;
+    }
+
+    context.pending.push_back(SIGKILL as u8);
+    if let Some(mut killed) = KILLED.try_lock() {
+        *killed = Some((context.id, None));
+    }
+    true
+}
+
+/// Print why the current context was killed, if it was killed for running out of memory.
+/// Called by the context itself on its way to exit, while it holds no locks
+pub fn report() {
+    let contexts = contexts();
+    let context_lock = match contexts.current() {
+        Some(context_lock) => context_lock,
+        None => return,
+    };
+    let context = context_lock.read();
+
+    let score = {
+        let mut killed = KILLED.lock();
+        match *killed {
+            Some((id, score)) if id == context.id => {
+                *killed = None;
+                score
+            },
+            _ => return,
+        }
+    };
+
+    let name = context.name.lock();
+    let name = str::from_utf8(&name).unwrap_or("?");
+    match score {
+        Some(score) => println!("Out of memory: killed {} ({}) using {} KB", context.id.into(), name, score / 1024),
+        None => println!("Out of memory: killed {} ({})", context.id.into(), name),
+    }
+}
@@ -0,0 +1,437 @@
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use spin::{Mutex, MutexGuard, Once};
+
+use crate::context::{Context, ContextId, ContextList, Status};
+use crate::context::switch;
+use crate::device::tickless;
+use crate::ipi::{ipi_cpu, IpiKind};
+
+/// Virtual runtime credit given to contexts waking up from sleep, so that they run soon but
+/// cannot monopolize the CPU after sleeping for a long time
+pub const SLEEPER_CREDIT: u64 = 10;
+
+/// Length of the real-time watchdog period, in PIT ticks
+const RT_PERIOD_TICKS: u64 = 400;
+
+/// Number of PIT ticks real-time contexts may use in each watchdog period. The rest of the
+/// period is left to normal contexts, so that a spinning real-time context cannot lock out the
+/// system
+const RT_RUNTIME_TICKS: u64 = 380;
+
+/// Entries kept in order in a vector, with the first entry at the end so that it is removed
+/// without moving the others. Capacity for every context is reserved with `reserve` when the
+/// context is created, so that queueing never allocates inside `switch`
+struct OrderedQueue<T> {
+    entries: Vec<T>,
+}
+
+impl<T: Copy + Ord> OrderedQueue<T> {
+    fn new() -> Self {
+        OrderedQueue {
+            entries: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Make room for `count` more entries
+    fn reserve(&mut self, count: usize) {
+        self.entries.reserve_exact(count);
+    }
+
+    /// Iterate the entries from first to last
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter().rev()
+    }
+
+    fn first(&self) -> Option<T> {
+        self.entries.last().copied()
+    }
+
+    fn insert(&mut self, entry: T) {
+        debug_assert!(self.entries.len() < self.entries.capacity(), "run queue: capacity not reserved");
+        let index = match self.entries.binary_search_by(|other| entry.cmp(other)) {
+            Ok(index) | Err(index) => index,
+        };
+        self.entries.insert(index, entry);
+    }
+
+    fn pop_first(&mut self) -> Option<T> {
+        self.entries.pop()
+    }
+
+    /// Remove the first entry, in order, that matches `predicate`
+    fn remove_first<F: Fn(&T) -> bool>(&mut self, predicate: F) -> Option<T> {
+        let index = self.entries.iter().rposition(predicate)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Remove the last entry, in order, that matches `predicate`
+    fn remove_last<F: Fn(&T) -> bool>(&mut self, predicate: F) -> Option<T> {
+        let index = self.entries.iter().position(predicate)?;
+        Some(self.entries.remove(index))
+    }
+}
+
+/// The contexts owned by a CPU
+pub struct RunQueue {
+    /// Runnable real-time contexts, ordered by priority and then by time of queueing
+    realtime: OrderedQueue<(Reverse<u8>, u64, ContextId)>,
+    /// Sequence number of the next real-time context to be queued
+    next_seq: u64,
+    /// Runnable normal contexts, ordered by virtual runtime
+    runnable: OrderedQueue<(u64, ContextId)>,
+    /// Sleeping contexts, ordered by wake time
+    sleeping: OrderedQueue<((u64, u64), ContextId)>,
+    /// Contexts waiting for their signal state to be restored by another context
+    restore: Vec<ContextId>,
+    /// The idle context of this CPU, which is never migrated
+    idle: Option<ContextId>,
+    /// Smallest virtual runtime that was picked from this queue
+    pub min_vruntime: u64,
+    /// Number of contexts moved to this CPU
+    pub migrations_in: u64,
+    /// Number of contexts moved away from this CPU
+    pub migrations_out: u64,
+    /// Number of contexts taken from other CPUs while this CPU was idle
+    pub steals: u64,
+    /// Priority of the running context, if it is a real-time context
+    pub running_rt: Option<u8>,
+    /// Number of PIT ticks elapsed in the current watchdog period
+    period_ticks: u64,
+    /// Number of PIT ticks used by real-time contexts in the current watchdog period
+    rt_ticks: u64,
+    /// Real-time contexts used up their runtime, and will not be picked until the next period
+    pub rt_throttled: bool,
+    /// Number of watchdog periods in which real-time contexts were throttled
+    pub rt_throttles: u64,
+}
+
+impl RunQueue {
+    fn new() -> RunQueue {
+        RunQueue {
+            realtime: OrderedQueue::new(),
+            next_seq: 0,
+            runnable: OrderedQueue::new(),
+            sleeping: OrderedQueue::new(),
+            restore: Vec::new(),
+            idle: None,
+            min_vruntime: 0,
+            migrations_in: 0,
+            migrations_out: 0,
+            steals: 0,
+            running_rt: None,
+            period_ticks: 0,
+            rt_ticks: 0,
+            rt_throttled: false,
+            rt_throttles: 0,
+        }
+    }
+
+    /// Number of runnable contexts in the queue
+    pub fn len(&self) -> usize {
+        self.realtime.len() + self.runnable.len()
+    }
+
+    /// Number of runnable real-time contexts in the queue
+    pub fn realtime(&self) -> usize {
+        self.realtime.len()
+    }
+
+    /// Number of sleeping contexts in the queue
+    pub fn sleeping(&self) -> usize {
+        self.sleeping.len()
+    }
+
+    /// Number of runnable normal contexts in the queue that could be moved to another CPU
+    pub fn load(&self) -> usize {
+        let idle = self.idle;
+        self.runnable.iter().filter(|key| Some(key.1) != idle).count()
+    }
+
+    /// Make room for `count` more contexts in each queue
+    fn reserve(&mut self, count: usize) {
+        self.realtime.reserve(count);
+        self.runnable.reserve(count);
+        self.sleeping.reserve(count);
+        self.restore.reserve_exact(count);
+    }
+
+    /// Set the idle context of this CPU
+    pub fn set_idle(&mut self, id: ContextId) {
+        self.idle = Some(id);
+    }
+
+    /// Remove the next context to run: the real-time context with the highest priority, or the
+    /// normal context with the lowest virtual runtime. If `min_priority` is set, only real-time
+    /// contexts with at least that priority are picked. Real-time contexts are skipped while
+    /// throttled
+    pub fn pop(&mut self, min_priority: Option<u8>) -> Option<ContextId> {
+        if ! self.rt_throttled {
+            if let Some(key) = self.realtime.first() {
+                if min_priority.map_or(true, |min_priority| (key.0).0 >= min_priority) {
+                    self.realtime.pop_first();
+                    return Some(key.2);
+                }
+            }
+
+            if min_priority.is_some() {
+                return None;
+            }
+        }
+
+        self.runnable.pop_first().map(|key| key.1)
+    }
+
+    /// Account for ticks spent on this CPU, throttling real-time contexts if they used up their
+    /// runtime in the current watchdog period
+    pub fn charge(&mut self, ticks: u64, realtime: bool) {
+        self.period_ticks += ticks;
+        if self.period_ticks >= RT_PERIOD_TICKS {
+            self.period_ticks = 0;
+            self.rt_ticks = 0;
+            self.rt_throttled = false;
+        } else if realtime {
+            self.rt_ticks += ticks;
+            if self.rt_ticks >= RT_RUNTIME_TICKS && ! self.rt_throttled {
+                self.rt_throttled = true;
+                self.rt_throttles += 1;
+            }
+        }
+    }
+
+    /// Remove the normal context with the highest virtual runtime that is not the idle context,
+    /// as it is the one that would wait the longest on this CPU. Real-time contexts are not
+    /// migrated
+    fn pop_migratable(&mut self) -> Option<ContextId> {
+        let idle = self.idle;
+        self.runnable.remove_last(|key| Some(key.1) != idle).map(|key| key.1)
+    }
+
+    /// Add a sleeping context, to be woken up at `wake`. An earlier entry for the context is
+    /// replaced, so that each context has at most one
+    pub fn sleep(&mut self, wake: (u64, u64), id: ContextId) {
+        self.sleeping.remove_first(|key| key.1 == id);
+        self.sleeping.insert((wake, id));
+    }
+
+    /// Get the earliest wake time of the sleeping contexts
+    pub fn next_wake(&self) -> Option<(u64, u64)> {
+        self.sleeping.first().map(|key| key.0)
+    }
+
+    /// Remove the sleeping context with the earliest wake time, if it is at or before `time`
+    pub fn pop_expired(&mut self, time: (u64, u64)) -> Option<((u64, u64), ContextId)> {
+        match self.sleeping.first() {
+            Some(key) if key.0 <= time => self.sleeping.pop_first(),
+            _ => None,
+        }
+    }
+
+    /// Add a context that has to be restored from a signal handler
+    pub fn push_restore(&mut self, id: ContextId) {
+        debug_assert!(self.restore.len() < self.restore.capacity(), "run queue: capacity not reserved");
+        self.restore.push(id);
+    }
+
+    /// Number of contexts that have to be restored from a signal handler
+    pub fn restores(&self) -> usize {
+        self.restore.len()
+    }
+
+    /// Take a context that has to be restored from a signal handler
+    pub fn pop_restore(&mut self) -> Option<ContextId> {
+        self.restore.pop()
+    }
+}
+
+static RUN_QUEUES: Once<Vec<Mutex<RunQueue>>> = Once::new();
+
+fn init_run_queues() -> Vec<Mutex<RunQueue>> {
+    (0..crate::cpu_count()).map(|_| Mutex::new(RunQueue::new())).collect()
+}
+
+/// Get the run queue of a CPU, by its logical id. These are numbered densely from zero, so that
+/// every CPU has a queue of its own
+pub fn run_queue(cpu_id: usize) -> MutexGuard<'static, RunQueue> {
+    let run_queues = RUN_QUEUES.call_once(init_run_queues);
+    run_queues[cpu_id].lock()
+}
+
+/// Make room in the run queue of every CPU for `count` more contexts, so that queueing them
+/// does not allocate. Called when a context is created, before the scheduler can see it
+pub fn reserve(count: usize) {
+    for cpu_id in 0..crate::cpu_count() {
+        run_queue(cpu_id).reserve(count);
+    }
+}
+
+/// Find a CPU the context is allowed to run on, starting the search at `hint`
+pub fn select_cpu(context: &Context, hint: usize) -> usize {
+    let cpu_count = crate::cpu_count();
+    for i in 0..cpu_count {
+        let cpu_id = (hint + i) % cpu_count;
+        if context.allowed_on(cpu_id) {
+            return cpu_id;
+        }
+    }
+    // The affinity mask is checked against the CPU count when it is set
+    hint % cpu_count
+}
+
+/// Add a context to the run queue of its CPU, if it can be run and is not already queued.
+/// Contexts that are not yet owned by a CPU, or not allowed on their CPU, are taken by the
+/// current CPU if possible
+pub fn enqueue(context: &mut Context) {
+    if context.queued || context.running || context.ptrace_stop || context.status != Status::Runnable {
+        return;
+    }
+
+    let cpu_id = match context.cpu_id {
+        Some(cpu_id) if context.allowed_on(cpu_id) => cpu_id,
+        _ => {
+            let cpu_id = select_cpu(context, crate::cpu_id());
+            context.cpu_id = Some(cpu_id);
+            cpu_id
+        }
+    };
+
+    let mut run_queue = run_queue(cpu_id);
+    if let Some(priority) = context.policy.rt_priority() {
+        let seq = run_queue.next_seq;
+        run_queue.next_seq += 1;
+        run_queue.realtime.insert((Reverse(priority), seq, context.id));
+        context.queued = true;
+
+        // Preempt the running context if this one has a higher priority
+        if ! run_queue.rt_throttled && run_queue.running_rt.map_or(true, |running| priority > running) {
+            drop(run_queue);
+            if cpu_id == crate::cpu_id() {
+                switch::preempt();
+            } else {
+                ipi_cpu(IpiKind::Switch, cpu_id);
+            }
+        }
+    } else {
+        if context.vruntime + SLEEPER_CREDIT < run_queue.min_vruntime {
+            context.vruntime = run_queue.min_vruntime - SLEEPER_CREDIT;
+        }
+        run_queue.runnable.insert((context.vruntime, context.id));
+        context.queued = true;
+
+        // Wake up a remote idle CPU so that it can steal the waiting context
+        if run_queue.len() > 1 {
+            drop(run_queue);
+            if let Some(idle_cpu) = tickless::claim_idle_cpu() {
+                ipi_cpu(IpiKind::Wakeup, idle_cpu);
+            }
+        }
+    }
+}
+
+/// Remove a queued context from the run queue of its CPU, so that it can be queued again after
+/// its scheduling parameters changed
+pub fn dequeue(context: &mut Context) {
+    if ! context.queued {
+        return;
+    }
+
+    if let Some(cpu_id) = context.cpu_id {
+        let id = context.id;
+        let mut run_queue = run_queue(cpu_id);
+        run_queue.realtime.remove_first(|key| key.2 == id);
+        run_queue.runnable.remove_first(|key| key.1 == id);
+    }
+    context.queued = false;
+}
+
+/// Move one runnable context from the run queue of `from_cpu` to the run queue of `to_cpu`,
+/// returning true if a context was moved
+///
+/// The run queue locks must not be held when calling this
+pub fn migrate(contexts: &ContextList, from_cpu: usize, to_cpu: usize) -> bool {
+    let (id, from_min_vruntime) = {
+        let mut run_queue = run_queue(from_cpu);
+        match run_queue.pop_migratable() {
+            Some(id) => (id, run_queue.min_vruntime),
+            None => return false,
+        }
+    };
+
+    let context_lock = match contexts.get(id) {
+        Some(context_lock) => context_lock,
+        None => return false,
+    };
+
+    let mut context = context_lock.write();
+    context.queued = false;
+
+    // Contexts without their own kernel stack run on the stack of their CPU, so they must stay
+    if context.running || context.cpu_id != Some(from_cpu) || context.kstack.is_none() || ! context.allowed_on(to_cpu) {
+        enqueue(&mut context);
+        return false;
+    }
+
+    // Keep the virtual runtime relative to the run queue it is placed in
+    let to_min_vruntime = run_queue(to_cpu).min_vruntime;
+    context.vruntime = context.vruntime.saturating_sub(from_min_vruntime) + to_min_vruntime;
+    context.cpu_id = Some(to_cpu);
+    enqueue(&mut context);
+
+    run_queue(from_cpu).migrations_out += 1;
+    run_queue(to_cpu).migrations_in += 1;
+
+    true
+}
+
+/// Find the CPU with the most runnable contexts, other than `cpu_id`
+fn busiest(cpu_id: usize) -> Option<(usize, usize)> {
+    let mut busiest = None;
+    for other_id in 0..crate::cpu_count() {
+        if other_id == cpu_id {
+            continue;
+        }
+
+        let load = run_queue(other_id).load();
+        if busiest.map_or(true, |(_, busiest_load)| load > busiest_load) {
+            busiest = Some((other_id, load));
+        }
+    }
+    busiest
+}
+
+/// Pull runnable contexts from the busiest CPU until both have a similar load
+pub fn balance(contexts: &ContextList, cpu_id: usize) {
+    let (busiest_id, busiest_load) = match busiest(cpu_id) {
+        Some(busiest) => busiest,
+        None => return,
+    };
+
+    let load = run_queue(cpu_id).load();
+    if busiest_load > load + 1 {
+        for _ in 0..(busiest_load - load) / 2 {
+            if ! migrate(contexts, busiest_id, cpu_id) {
+                break;
+            }
+        }
+    }
+}
+
+/// Take a runnable context from the busiest CPU, used when this CPU has nothing to run.
+/// Returns true if a context was taken
+pub fn steal(contexts: &ContextList, cpu_id: usize) -> bool {
+    match busiest(cpu_id) {
+        Some((busiest_id, busiest_load)) if busiest_load > 0 => {
+            if migrate(contexts, busiest_id, cpu_id) {
+                run_queue(cpu_id).steals += 1;
+                true
+            } else {
+                false
+            }
+        },
+        _ => false,
+    }
+}
@@ -3,7 +3,7 @@ use core::mem;
 use syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_SIGNAL, SIG_DFL, SIG_IGN, SIGCHLD, SIGCONT, SIGKILL, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU};
 use syscall::ptrace_event;
 
-use crate::context::{contexts, switch, Status, WaitpidKey};
+use crate::context::{contexts, oom, switch, Status, WaitpidKey};
 use crate::start::usermode;
 use crate::ptrace;
 
@@ -74,7 +74,7 @@ pub extern "C" fn signal_handler(sig: usize) {
                     let (pid, pgid, ppid) = {
                         let context_lock = contexts.current().expect("context::signal_handler not inside of context");
                         let mut context = context_lock.write();
-                        context.status = Status::Stopped(sig);
+                        context.stop(sig);
                         (context.id, context.pgid, context.ppid)
                     };
 
@@ -97,6 +97,9 @@ pub extern "C" fn signal_handler(sig: usize) {
             },
             _ => {
                 // println!("Exit {}", sig);
+                if sig == SIGKILL {
+                    oom::report();
+                }
                 crate::syscall::exit(sig);
             }
         }
@@ -0,0 +1,503 @@
+//! # Swap
+//! Anonymous user pages that were not accessed for a while are written out to a swap file,
+//! provided by a userspace scheme and registered by writing its path to `memory:swap`. The
+//! page table entry of a swapped out page keeps its flags and the slot of the swap file
+//! holding it, and the page fault handler reads it back on the next access.
+//!
+//! Pages are evicted by the `swapd` kernel context when free frames run low. Each scan clears
+//! the accessed bit of the pages it passes, and evicts those that were not accessed since the
+//! previous scan. Only pages that are mapped once and not pinned are evicted, and contexts
+//! providing a scheme are skipped, as the swap scheme may depend on them.
+//!
+//! The frame of an evicted page is kept in the swap cache until it was written to the swap
+//! file, so that it can be read back at any time. The cache has a fixed size, so evicting takes
+//! no memory from the kernel heap.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once, RwLock};
+
+use crate::context::{self, Status};
+use crate::context::file::FileDescription;
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::memory::{self, Frame, FrameFlags, PAGE_SIZE};
+use crate::paging::{ActivePageTable, InactivePageTable, Page, VirtualAddress};
+use crate::paging::entry::EntryFlags;
+use crate::paging::temporary_page::TemporaryPage;
+use crate::scheme;
+use crate::sync::WaitCondition;
+use crate::syscall::data::{Stat, TimeSpec};
+use crate::syscall::error::*;
+use crate::syscall::flag::SEEK_SET;
+use crate::syscall::scheme::Scheme;
+
+/// Number of pages evicted before they are written out
+const EVICT_BATCH: usize = 64;
+
+/// Number of evicted pages kept until they are written out
+const CACHE_SIZE: usize = 2 * EVICT_BATCH;
+
+/// Time between scans, in milliseconds
+const SCAN_INTERVAL_MS: i32 = 100;
+
+/// The contents of a page, aligned so that the buffer fills the pages given to the swap scheme
+#[repr(C, align(4096))]
+pub struct SwapPage(pub [u8; PAGE_SIZE]);
+
+impl SwapPage {
+    pub fn new() -> Box<SwapPage> {
+        Box::new(SwapPage([0; PAGE_SIZE]))
+    }
+}
+
+struct Swap {
+    /// The swap file
+    description: Arc<RwLock<FileDescription>>,
+    /// Number of page table entries referring to each slot, zero if it is free
+    slots: Vec<u32>,
+    /// Number of free slots
+    free: usize,
+    /// Slot to start looking for a free one at
+    next: usize,
+    /// Slots and frames of evicted pages that are not written to the swap file yet. Its
+    /// capacity is `CACHE_SIZE`, and it never grows past it
+    cache: Vec<(usize, Frame)>,
+}
+
+impl Swap {
+    /// Get the cached frame of a slot, with a new reference so that it can be accessed without
+    /// holding the swap lock
+    fn cached(&self, slot: usize) -> Option<Frame> {
+        let &(_, ref frame) = self.cache.iter().find(|&&(cached, _)| cached == slot)?;
+        memory::ref_frame(frame);
+        Some(Frame::containing_address(frame.start_address()))
+    }
+}
+
+static SWAP: Mutex<Option<Swap>> = Mutex::new(None);
+
+/// Set while a seek and transfer on the swap file are in progress
+static IO_BUSY: Mutex<bool> = Mutex::new(false);
+
+/// Waited on for the swap file to be free
+static IO_CONDITION: Once<WaitCondition> = Once::new();
+
+/// The last scan under memory pressure did not evict anything
+static STALLED: AtomicBool = AtomicBool::new(false);
+
+/// Use a file as swap. It is read and written a page at a time, so its size should be a multiple
+/// of the page size
+pub fn swap_on(description: Arc<RwLock<FileDescription>>) -> Result<()> {
+    let (scheme_id, number) = {
+        let description = description.read();
+        (description.scheme, description.number)
+    };
+    let scheme = {
+        let schemes = scheme::schemes();
+        let scheme = schemes.get(scheme_id).ok_or(Error::new(EBADF))?;
+        Arc::clone(&scheme)
+    };
+
+    let mut stat = Stat::default();
+    scheme.fstat(number, &mut stat)?;
+    let count = stat.st_size as usize / PAGE_SIZE;
+    if count == 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    // Allocated before locking, as the OOM killer checks the swap lock
+    let slots = vec![0; count];
+    let cache = Vec::with_capacity(CACHE_SIZE);
+    {
+        let mut swap = SWAP.lock();
+        if swap.is_some() {
+            return Err(Error::new(EBUSY));
+        }
+        *swap = Some(Swap {
+            description,
+            slots,
+            free: count,
+            next: 0,
+            cache,
+        });
+    }
+
+    println!("Swap: {} KB", count * PAGE_SIZE / 1024);
+
+    let mut contexts = context::contexts_mut();
+    // The swap daemon edits user page tables, so it must not run on one
+    let context_lock = contexts.spawn_kernel(swapd)?;
+    let mut context = context_lock.write();
+    *context.name.lock() = "[swapd]".as_bytes().to_vec().into_boxed_slice();
+    context.status = Status::Runnable;
+    context::run_queue::enqueue(&mut context);
+
+    Ok(())
+}
+
+/// Get the number of slots of the swap file, the number of used slots, and the number of pages
+/// waiting in the swap cache, if swap is enabled
+pub fn stats() -> Option<(usize, usize, usize)> {
+    SWAP.lock().as_ref().map(|swap| (swap.slots.len(), swap.slots.len() - swap.free, swap.cache.len()))
+}
+
+/// Check if evicting pages can still free memory, in which case running out of memory is
+/// temporary. This is called by the OOM killer, so it does not wait for the swap lock, and
+/// takes a held lock to mean that pages are being evicted
+pub fn reclaiming() -> bool {
+    if STALLED.load(Ordering::SeqCst) {
+        return false;
+    }
+    match SWAP.try_lock() {
+        Some(swap) => swap.as_ref().map_or(false, |swap| swap.free > 0),
+        None => true,
+    }
+}
+
+fn allocate_slot() -> Option<usize> {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut()?;
+    if swap.free == 0 {
+        return None;
+    }
+
+    let count = swap.slots.len();
+    for i in 0..count {
+        let slot = (swap.next + i) % count;
+        if swap.slots[slot] == 0 {
+            swap.slots[slot] = 1;
+            swap.free -= 1;
+            swap.next = (slot + 1) % count;
+            return Some(slot);
+        }
+    }
+    None
+}
+
+/// Add a reference to a slot, for a new page table entry referring to it
+pub fn ref_slot(slot: usize) {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut().expect("swap slot referenced without swap");
+    swap.slots[slot] += 1;
+}
+
+/// Remove a reference to a slot, freeing it if it was the last one
+pub fn unref_slot(slot: usize) {
+    let cached = {
+        let mut guard = SWAP.lock();
+        let swap = guard.as_mut().expect("swap slot released without swap");
+        assert!(swap.slots[slot] > 0, "swap slot {} released while free", slot);
+        swap.slots[slot] -= 1;
+        if swap.slots[slot] == 0 {
+            swap.free += 1;
+            swap.cache.iter().position(|&(cached, _)| cached == slot).map(|index| swap.cache.swap_remove(index).1)
+        } else {
+            None
+        }
+    };
+
+    if let Some(frame) = cached {
+        memory::unref_frame(frame);
+    }
+}
+
+fn io_condition() -> &'static WaitCondition {
+    IO_CONDITION.call_once(WaitCondition::new)
+}
+
+/// Serializes the seeks and transfers on the swap file, until dropped
+struct IoGuard;
+
+impl Drop for IoGuard {
+    fn drop(&mut self) {
+        *IO_BUSY.lock() = false;
+        io_condition().notify();
+    }
+}
+
+/// Wait for the swap file to be free. The swap scheme may need to run before it is, so this
+/// blocks instead of spinning
+fn io_lock() -> IoGuard {
+    loop {
+        let mut busy = IO_BUSY.lock();
+        if ! *busy {
+            *busy = true;
+            return IoGuard;
+        }
+        io_condition().wait(busy, "swap io");
+    }
+}
+
+/// Read or write a slot of the swap file
+fn transfer<F: FnOnce(&(dyn Scheme + Send + Sync), usize) -> Result<usize>>(slot: usize, f: F) -> Result<()> {
+    let description = {
+        let guard = SWAP.lock();
+        let swap = guard.as_ref().ok_or(Error::new(ENODEV))?;
+        Arc::clone(&swap.description)
+    };
+    let (scheme_id, number) = {
+        let description = description.read();
+        (description.scheme, description.number)
+    };
+    let scheme = {
+        let schemes = scheme::schemes();
+        let scheme = schemes.get(scheme_id).ok_or(Error::new(EBADF))?;
+        Arc::clone(&scheme)
+    };
+
+    let _guard = io_lock();
+    scheme.seek(number, (slot * PAGE_SIZE) as isize, SEEK_SET)?;
+    if f(&*scheme, number)? == PAGE_SIZE {
+        Ok(())
+    } else {
+        Err(Error::new(EIO))
+    }
+}
+
+/// Read the contents of a swapped out page
+pub fn read_slot(slot: usize, page: &mut SwapPage) -> Result<()> {
+    let cached = SWAP.lock().as_ref().and_then(|swap| swap.cached(slot));
+    if let Some(frame) = cached {
+        let mut active_table = unsafe { ActivePageTable::new() };
+        let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(crate::USER_TMP_MISC_OFFSET)));
+        let address = temporary_page.map(Frame::containing_address(frame.start_address()), EntryFlags::PRESENT | EntryFlags::NO_EXECUTE, &mut active_table);
+        unsafe {
+            (address.get() as *const [u8; PAGE_SIZE]).copy_to_nonoverlapping(&mut page.0, 1);
+        }
+        temporary_page.unmap(&mut active_table);
+        drop(active_table);
+
+        memory::unref_frame(frame);
+        return Ok(());
+    }
+
+    transfer(slot, |scheme, number| scheme.read(number, &mut page.0))
+}
+
+/// Write the pages in the swap cache to the swap file, straight from their frames. Pages that
+/// fail to be written stay in the cache. Only the swap daemon calls this, on its own page table
+fn write_cache() {
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(crate::USER_TMP_MISC_OFFSET)));
+
+    loop {
+        // Written pages are removed, so the first one left is always the next
+        let (slot, frame) = match SWAP.lock().as_ref().and_then(|swap| {
+            swap.cache.first().map(|&(slot, _)| (slot, swap.cached(slot)))
+        }) {
+            Some((slot, Some(frame))) => (slot, frame),
+            _ => return,
+        };
+        let start_address = frame.start_address();
+
+        // The swap scheme has to run to write the page, so the page table lock is not held
+        // meanwhile. This page table is not used by any other context
+        let address = {
+            let mut active_table = unsafe { ActivePageTable::new() };
+            temporary_page.map(Frame::containing_address(start_address), EntryFlags::PRESENT | EntryFlags::NO_EXECUTE, &mut active_table)
+        };
+        let data = unsafe { &*(address.get() as *const [u8; PAGE_SIZE]) };
+        let result = transfer(slot, |scheme, number| scheme.write(number, data));
+        {
+            let mut active_table = unsafe { ActivePageTable::new() };
+            temporary_page.unmap(&mut active_table);
+        }
+
+        // The slot may have been freed while it was written, and the frame is only released
+        // once both the cache and this reference are gone
+        let written = match *SWAP.lock() {
+            Some(ref mut swap) if result.is_ok() => swap.cache.iter().position(|&(cached, ref other)| {
+                cached == slot && other.start_address() == start_address
+            }).map(|index| swap.cache.swap_remove(index).1),
+            _ => None,
+        };
+        if let Some(written) = written {
+            memory::unref_frame(written);
+        }
+        memory::unref_frame(frame);
+
+        if let Err(err) = result {
+            println!("swap: failed to write slot {}: {}", slot, err);
+            return;
+        }
+    }
+}
+
+/// Collect the anonymous memory of every address space that may be swapped, by page table
+fn swappable() -> Vec<(usize, Vec<(VirtualAddress, usize)>)> {
+    let mut spaces: Vec<(usize, Vec<(VirtualAddress, usize)>)> = Vec::new();
+    let mut resident = Vec::new();
+
+    let contexts = context::contexts();
+    for (_id, context_lock) in contexts.iter() {
+        let context = context_lock.read();
+
+        // Kernel contexts have no image, and exited contexts have freed theirs
+        if context.image.is_empty() {
+            continue;
+        }
+        if let Status::Exited(_) = context.status {
+            continue;
+        }
+
+        let table = context.arch.get_page_table();
+        if context.resident {
+            resident.push(table);
+            continue;
+        }
+
+        let index = match spaces.iter().position(|space| space.0 == table) {
+            Some(index) => index,
+            None => {
+                spaces.push((table, Vec::new()));
+                spaces.len() - 1
+            }
+        };
+        let ranges = &mut spaces[index].1;
+
+        for shared_mem in context.image.iter() {
+            if let Some(range) = shared_mem.try_with(|mem| (mem.start_address(), mem.size())) {
+                ranges.push(range);
+            }
+        }
+        if let Some(ref stack) = context.stack {
+            if let Some(range) = stack.try_with(|stack| (stack.start_address(), stack.size())) {
+                ranges.push(range);
+            }
+        }
+        if let Some(ref sigstack) = context.sigstack {
+            ranges.push((sigstack.start_address(), sigstack.size()));
+        }
+        if let Some(ref tls) = context.tls {
+            ranges.push((tls.mem.start_address(), tls.mem.size()));
+        }
+        for grant in context.grants.lock().iter() {
+            if grant.is_owned() {
+                ranges.push((grant.start_address(), grant.size()));
+            }
+        }
+    }
+
+    spaces.retain(|space| ! resident.contains(&space.0));
+    spaces
+}
+
+/// Evict up to `count` pages that were not accessed since the last scan, returning the number
+/// of pages evicted. No more pages are evicted than there is room for in the swap cache
+fn evict(count: usize) -> usize {
+    let count = match *SWAP.lock() {
+        Some(ref swap) => cmp::min(count, CACHE_SIZE - swap.cache.len()),
+        None => return 0,
+    };
+    let mut evicted = 0;
+
+    for (table, ranges) in swappable() {
+        if evicted >= count {
+            break;
+        }
+
+        let mut active_table = unsafe { ActivePageTable::new() };
+        // The swap daemon runs on its own kernel page table, so every user page table is inactive
+        if table == unsafe { active_table.address() } {
+            continue;
+        }
+        let mut inactive_table = unsafe { InactivePageTable::from_address(table) };
+        let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(crate::USER_TMP_MISC_OFFSET)));
+
+        let mut victims = Vec::new();
+        active_table.with(&mut inactive_table, &mut temporary_page, |mapper| {
+            for &(start, size) in ranges.iter() {
+                let start_page = Page::containing_address(start);
+                let end_page = Page::containing_address(VirtualAddress::new(start.get() + size - 1));
+                for page in Page::range_inclusive(start_page, end_page) {
+                    if evicted + victims.len() >= count {
+                        return;
+                    }
+
+                    let flags = match mapper.translate_page_flags(page) {
+                        Some(flags) if flags.contains(EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE) => flags,
+                        _ => continue,
+                    };
+
+                    if flags.contains(EntryFlags::ACCESSED) {
+                        // Give the page until the next scan to be accessed again
+                        let result = mapper.remap(page, flags - EntryFlags::ACCESSED);
+                        // This is not the active table, so the flush can be ignored
+                        unsafe { result.ignore(); }
+                        continue;
+                    }
+
+                    // Pages shared with other mappings, or pinned for DMA, stay resident
+                    let frame = match mapper.translate_page(page) {
+                        Some(frame) => frame,
+                        None => continue,
+                    };
+                    match memory::descriptor(&frame) {
+                        Some(descriptor) if descriptor.refcount() == 1
+                            && ! descriptor.flags().intersects(FrameFlags::PINNED | FrameFlags::RESERVED) => (),
+                        _ => continue,
+                    }
+
+                    let slot = match allocate_slot() {
+                        Some(slot) => slot,
+                        None => return,
+                    };
+                    let (result, frame) = mapper.swap_out(page, slot);
+                    // This is not the active table, so the flush can be ignored
+                    unsafe { result.ignore(); }
+                    victims.push((frame, slot));
+                }
+            }
+        });
+
+        // Other CPUs may run this address space, and must see both the swapped out entries and
+        // the cleared accessed bits
+        ipi(IpiKind::Tlb, IpiTarget::Other);
+
+        // The page table lock is still held, so the pages can not be read back before they
+        // are in the cache. Only the swap daemon adds to the cache, so there is room for them
+        if let Some(ref mut swap) = *SWAP.lock() {
+            for (frame, slot) in victims {
+                swap.cache.push((slot, frame));
+                evicted += 1;
+            }
+        }
+    }
+
+    evicted
+}
+
+/// The swap daemon, evicting pages while free frames are low
+extern fn swapd() {
+    loop {
+        let total = memory::free_frames() + memory::used_frames();
+        let low = total / 32;
+        let high = total / 16;
+
+        if memory::free_frames() < low {
+            // The first scan may only clear accessed bits
+            let mut idle_scans = 0;
+            while memory::free_frames() < high && idle_scans < 2 {
+                if evict(EVICT_BATCH) > 0 {
+                    idle_scans = 0;
+                    write_cache();
+                } else {
+                    idle_scans += 1;
+                }
+            }
+            STALLED.store(idle_scans >= 2, Ordering::SeqCst);
+        } else {
+            STALLED.store(false, Ordering::SeqCst);
+        }
+
+        // Retry pages that failed to be written
+        write_cache();
+
+        let _ = crate::syscall::nanosleep(&TimeSpec {
+            tv_sec: 0,
+            tv_nsec: SCAN_INTERVAL_MS * 1_000_000,
+        }, None);
+    }
+}
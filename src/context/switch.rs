@@ -1,64 +1,114 @@
-use core::sync::atomic::Ordering;
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+use crate::context::run_queue::{self, run_queue};
 use crate::context::signal::signal_handler;
-use crate::context::{arch, contexts, Context, Status, CONTEXT_ID};
+use crate::context::{arch, contexts, Context, ContextList, SchedPolicy, Status, CONTEXT_ID};
+use crate::device::tickless;
 use crate::gdt;
-use crate::interrupt::irq::PIT_TICKS;
+use crate::interrupt::irq::{PIT_TICKS, USER_TICKS};
 use crate::interrupt;
 use crate::ptrace;
 use crate::time;
 
-unsafe fn update(context: &mut Context, cpu_id: usize) {
-    // Take ownership if not already owned
-    if context.cpu_id == None {
-        context.cpu_id = Some(cpu_id);
-        // println!("{}: take {} {}", cpu_id, context.id, ::core::str::from_utf8_unchecked(&context.name.lock()));
-    }
+/// Highest priority nice level
+pub const NICE_MIN: i8 = -20;
 
-    // Restore from signal, must only be done from another context to avoid overwriting the stack!
-    if context.ksig_restore && ! context.running {
-        let was_singlestep = ptrace::regs_for(context).map(|s| s.is_singlestep()).unwrap_or(false);
+/// Lowest priority nice level
+pub const NICE_MAX: i8 = 19;
 
-        let ksig = context.ksig.take().expect("context::switch: ksig not set with ksig_restore");
-        context.arch = ksig.0;
+/// Weight of a context with a nice level of 0
+const NICE_0_WEIGHT: u64 = 1024;
 
-        if let Some(ref mut kfx) = context.kfx {
-            kfx.clone_from_slice(&ksig.1.expect("context::switch: ksig kfx not set with ksig_restore"));
-        } else {
-            panic!("context::switch: kfx not set with ksig_restore");
-        }
+/// Weight of each nice level, starting at `NICE_MIN`. Each level is roughly 1.25 times the next,
+/// so that a change of one nice level is a change of about 10% in CPU time
+const NICE_WEIGHTS: [u64; 40] = [
+    /* -20 */ 88761, 71755, 56483, 46273, 36291,
+    /* -15 */ 29154, 23254, 18705, 14949, 11916,
+    /* -10 */ 9548, 7620, 6100, 4904, 3906,
+    /*  -5 */ 3121, 2501, 1991, 1586, 1277,
+    /*   0 */ 1024, 820, 655, 526, 423,
+    /*   5 */ 335, 272, 215, 172, 137,
+    /*  10 */ 110, 87, 70, 56, 45,
+    /*  15 */ 36, 29, 23, 18, 15,
+];
 
-        if let Some(ref mut kstack) = context.kstack {
-            kstack.clone_from_slice(&ksig.2.expect("context::switch: ksig kstack not set with ksig_restore"));
-        } else {
-            panic!("context::switch: kstack not set with ksig_restore");
-        }
+/// Number of PIT ticks in the time slice of a context with a nice level of 0
+const BASE_SLICE_TICKS: u64 = 10;
 
-        context.ksig_restore = false;
+/// Maximum number of PIT ticks in a time slice
+const MAX_SLICE_TICKS: u64 = 100;
 
-        // Keep singlestep flag across jumps
-        if let Some(regs) = ptrace::regs_for_mut(context) {
-            regs.set_singlestep(was_singlestep);
-        }
+/// Number of PIT ticks the current context may run before being preempted
+#[thread_local]
+static SLICE_TICKS: AtomicUsize = AtomicUsize::new(BASE_SLICE_TICKS as usize);
+
+/// A context with a higher priority than the current one was queued on this CPU
+#[thread_local]
+static PREEMPT: AtomicBool = AtomicBool::new(false);
+
+/// Number of PIT ticks between two load balancing passes on a CPU
+const BALANCE_INTERVAL_TICKS: usize = 100;
+
+/// Number of PIT ticks since the last load balancing pass on this CPU
+#[thread_local]
+static BALANCE_TICKS: AtomicUsize = AtomicUsize::new(0);
 
-        context.unblock();
+/// Get the scheduling weight of a nice level
+pub fn nice_weight(nice: i8) -> u64 {
+    let nice = if nice < NICE_MIN {
+        NICE_MIN
+    } else if nice > NICE_MAX {
+        NICE_MAX
+    } else {
+        nice
+    };
+    NICE_WEIGHTS[(nice - NICE_MIN) as usize]
+}
+
+/// Get the number of PIT ticks the current context may run before it is preempted
+pub fn time_slice() -> usize {
+    if PREEMPT.load(Ordering::SeqCst) {
+        0
+    } else {
+        SLICE_TICKS.load(Ordering::SeqCst)
     }
+}
+
+/// Preempt the current context on the next PIT tick, or right away on tickless CPUs
+pub fn preempt() {
+    PREEMPT.store(true, Ordering::SeqCst);
+    unsafe { tickless::kick(); }
+}
 
-    // Unblock when there are pending signals
-    if context.status == Status::Blocked && !context.pending.is_empty() {
-        context.unblock();
+/// Restore a context from a signal handler, must only be done from another context to avoid
+/// overwriting the stack!
+unsafe fn restore(context: &mut Context) {
+    let was_singlestep = ptrace::regs_for(context).map(|s| s.is_singlestep()).unwrap_or(false);
+
+    let ksig = context.ksig.take().expect("context::switch: ksig not set with ksig_restore");
+    context.arch = ksig.0;
+
+    if let Some(ref mut kfx) = context.kfx {
+        kfx.clone_from_slice(&ksig.1.expect("context::switch: ksig kfx not set with ksig_restore"));
+    } else {
+        panic!("context::switch: kfx not set with ksig_restore");
+    }
+
+    if let Some(ref mut kstack) = context.kstack {
+        kstack.clone_from_slice(&ksig.2.expect("context::switch: ksig kstack not set with ksig_restore"));
+    } else {
+        panic!("context::switch: kstack not set with ksig_restore");
     }
 
-    // Wake from sleep
-    if context.status == Status::Blocked && context.wake.is_some() {
-        let wake = context.wake.expect("context::switch: wake not set");
+    context.ksig_restore = false;
 
-        let current = time::monotonic();
-        if current.0 > wake.0 || (current.0 == wake.0 && current.1 >= wake.1) {
-            context.wake = None;
-            context.unblock();
-        }
+    // Keep singlestep flag across jumps
+    if let Some(regs) = ptrace::regs_for_mut(context) {
+        regs.set_singlestep(was_singlestep);
     }
+
+    context.unblock();
 }
 
 unsafe fn runnable(context: &Context, cpu_id: usize) -> bool {
@@ -66,6 +116,63 @@ unsafe fn runnable(context: &Context, cpu_id: usize) -> bool {
     !context.running && !context.ptrace_stop && context.status == Status::Runnable && context.cpu_id == Some(cpu_id)
 }
 
+/// Wake up the sleeping contexts of a CPU whose wake time has passed, at most as many as were
+/// sleeping when starting, as unblocking takes the run queue lock
+fn wake_expired(contexts: &ContextList, cpu_id: usize) {
+    let now = time::monotonic();
+    let sleeping = run_queue(cpu_id).sleeping();
+    for _ in 0..sleeping {
+        let (wake, id) = match run_queue(cpu_id).pop_expired(now) {
+            Some(expired) => expired,
+            None => break,
+        };
+        if let Some(context_lock) = contexts.get(id) {
+            let mut context = context_lock.write();
+            // The wake time may have been changed or cleared after the context was parked
+            if context.status == Status::Blocked && context.wake == Some(wake) {
+                context.wake = None;
+                context.unblock();
+            }
+        }
+    }
+}
+
+/// Wake up the sleeping contexts of this CPU whose wake time has passed, without switching.
+/// Called from the timer interrupt of tickless CPUs, and skipped if the interrupted code holds
+/// the context list
+pub fn wake_sleepers() {
+    if let Some(contexts) = super::try_contexts() {
+        wake_expired(&contexts, crate::cpu_id());
+    }
+}
+
+/// Pick the next context from the run queue of this CPU, see `RunQueue::pop`. Entries for
+/// contexts that were blocked, stopped, or moved while queued are dropped, as they are queued
+/// again when they become runnable
+unsafe fn pick(contexts: &ContextList, cpu_id: usize, min_priority: Option<u8>) -> *mut Context {
+    use core::ops::DerefMut;
+
+    loop {
+        let id = match run_queue(cpu_id).pop(min_priority) {
+            Some(id) => id,
+            None => return 0 as *mut Context,
+        };
+
+        let context_lock = match contexts.get(id) {
+            Some(context_lock) => context_lock,
+            None => continue,
+        };
+
+        let mut context = context_lock.write();
+        context.queued = false;
+        if context.cpu_id != Some(cpu_id) {
+            run_queue::enqueue(&mut context);
+        } else if runnable(&context, cpu_id) {
+            return context.deref_mut() as *mut Context;
+        }
+    }
+}
+
 /// Switch to the next context
 ///
 /// # Safety
@@ -76,6 +183,8 @@ pub unsafe fn switch() -> bool {
 
     //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
     let ticks = PIT_TICKS.swap(0, Ordering::SeqCst);
+    let user_ticks = cmp::min(USER_TICKS.swap(0, Ordering::SeqCst), ticks);
+    PREEMPT.store(false, Ordering::SeqCst);
 
     // Set the global lock to avoid the unsafe operations below from causing issues
     while arch::CONTEXT_SWITCH_LOCK.compare_and_swap(false, true, Ordering::SeqCst) {
@@ -85,7 +194,7 @@ pub unsafe fn switch() -> bool {
     let cpu_id = crate::cpu_id();
 
     let from_ptr;
-    let mut to_ptr = 0 as *mut Context;
+    let to_ptr;
     let mut to_sig = None;
     {
         let contexts = contexts();
@@ -95,40 +204,86 @@ pub unsafe fn switch() -> bool {
                 .expect("context::switch: not inside of context");
             let mut context = context_lock.write();
             context.ticks += ticks as u64 + 1; // Always round ticks up
+            context.user_ticks += user_ticks as u64;
+            context.kernel_ticks += (ticks - user_ticks) as u64 + 1;
+            context.vruntime += (ticks as u64 + 1) * NICE_0_WEIGHT / nice_weight(context.nice);
             from_ptr = context.deref_mut() as *mut Context;
         }
 
-        for (_pid, context_lock) in contexts.iter() {
-            let mut context = context_lock.write();
-            update(&mut context, cpu_id);
+        // Throttle real-time contexts that used up their runtime, counted in sys:sched
+        run_queue(cpu_id).charge(ticks as u64 + 1, (*from_ptr).policy != SchedPolicy::Normal);
+
+        // A signal sent while the context was still running, just before it blocked, found it
+        // runnable and did not unblock it, so it is unblocked here to handle the signal.
+        // Stopped and ptrace-stopped contexts are resumed by SIGCONT and the tracer instead
+        if (*from_ptr).status == Status::Blocked && ! (*from_ptr).pending.is_empty() {
+            (*from_ptr).unblock();
         }
 
-        for (pid, context_lock) in contexts.iter() {
-            if *pid > (*from_ptr).id {
+        // A runnable real-time context keeps running unless a real-time context with a higher
+        // priority, or the same priority for round robin, is runnable
+        let min_priority = if (*from_ptr).status == Status::Runnable && ! (*from_ptr).ptrace_stop && ! run_queue(cpu_id).rt_throttled {
+            match (*from_ptr).policy {
+                SchedPolicy::Normal => None,
+                SchedPolicy::Fifo(priority) => Some(priority + 1),
+                SchedPolicy::RoundRobin(priority) => Some(priority),
+            }
+        } else {
+            None
+        };
+
+        // Restore contexts that returned from a signal handler. The run queue lock is not held
+        // while they are restored, and contexts are taken one at a time so nothing is allocated
+        let restores = run_queue(cpu_id).restores();
+        for _ in 0..restores {
+            let id = match run_queue(cpu_id).pop_restore() {
+                Some(id) => id,
+                None => break,
+            };
+            if let Some(context_lock) = contexts.get(id) {
                 let mut context = context_lock.write();
-                if runnable(&mut context, cpu_id) {
-                    to_ptr = context.deref_mut() as *mut Context;
-                    if (*to_ptr).ksig.is_none() {
-                        to_sig = context.pending.pop_front();
-                    }
-                    break;
+                if context.ksig_restore && ! context.running {
+                    restore(&mut context);
                 }
             }
         }
 
-        if to_ptr as usize == 0 {
-            for (pid, context_lock) in contexts.iter() {
-                if *pid < (*from_ptr).id {
-                    let mut context = context_lock.write();
-                    if runnable(&mut context, cpu_id) {
-                        to_ptr = context.deref_mut() as *mut Context;
-                        if (*to_ptr).ksig.is_none() {
-                            to_sig = context.pending.pop_front();
-                        }
-                        break;
-                    }
+        wake_expired(&contexts, cpu_id);
+
+        // Periodically pull contexts from busier CPUs
+        if BALANCE_TICKS.fetch_add(ticks + 1, Ordering::SeqCst) >= BALANCE_INTERVAL_TICKS {
+            BALANCE_TICKS.store(0, Ordering::SeqCst);
+            run_queue::balance(&contexts, cpu_id);
+        }
+
+        to_ptr = pick(&contexts, cpu_id, min_priority);
+
+        // Nothing to run here, try to take work from another CPU
+        if to_ptr as usize == 0 && min_priority.is_none() && run_queue::steal(&contexts, cpu_id) {
+            to_ptr = pick(&contexts, cpu_id, None);
+        }
+
+        if to_ptr as usize != 0 {
+            if (*to_ptr).ksig.is_none() {
+                to_sig = (*to_ptr).pending.pop_front();
+            }
+
+            {
+                let mut run_queue = run_queue(cpu_id);
+                run_queue.running_rt = (*to_ptr).policy.rt_priority();
+                if run_queue.running_rt.is_none() && (*to_ptr).vruntime > run_queue.min_vruntime {
+                    run_queue.min_vruntime = (*to_ptr).vruntime;
                 }
             }
+
+            // Give higher priority contexts a longer time slice. Real-time contexts get the base
+            // time slice, and are only switched away from if there is something better to run
+            let slice = if (*to_ptr).policy == SchedPolicy::Normal {
+                BASE_SLICE_TICKS * nice_weight((*to_ptr).nice) / NICE_0_WEIGHT
+            } else {
+                BASE_SLICE_TICKS
+            };
+            SLICE_TICKS.store(cmp::max(1, cmp::min(slice, MAX_SLICE_TICKS)) as usize, Ordering::SeqCst);
         }
     };
 
@@ -141,6 +296,28 @@ pub unsafe fn switch() -> bool {
         }
         gdt::set_tcb((*to_ptr).id.into());
         CONTEXT_ID.store((*to_ptr).id, Ordering::SeqCst);
+
+        if (*from_ptr).status == Status::Runnable && ! (*from_ptr).ptrace_stop {
+            (*from_ptr).nivcsw += 1;
+        } else {
+            (*from_ptr).nvcsw += 1;
+        }
+
+        // Queue the previous context again, or park it until it is woken up
+        if (*from_ptr).status == Status::Blocked {
+            if let Some(wake) = (*from_ptr).wake {
+                run_queue(cpu_id).sleep(wake, (*from_ptr).id);
+            }
+        }
+        if (*from_ptr).ksig_restore {
+            run_queue(cpu_id).push_restore((*from_ptr).id);
+        }
+        run_queue::enqueue(&mut *from_ptr);
+    }
+
+    // Tickless CPUs preempt the next context with their local APIC timer
+    if tickless::enabled() {
+        tickless::arm_slice(time_slice() as u64);
     }
 
     if to_ptr as usize == 0 {
@@ -162,6 +339,7 @@ pub unsafe fn switch() -> bool {
             (*to_ptr).arch.signal_stack(signal_handler, sig);
         }
 
+        (*to_ptr).arch.tag_page_table();
         (*from_ptr).arch.switch_to(&mut (*to_ptr).arch);
 
         true
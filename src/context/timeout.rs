@@ -39,8 +39,35 @@ pub fn register(scheme_id: SchemeId, event_id: usize, clock: usize, time: TimeSp
     });
 }
 
+/// Get the earliest monotonic time at which a timeout expires, if any are registered. If the
+/// registry is locked, the current time is returned, so that the caller checks again soon
+pub fn next_deadline() -> Option<(u64, u64)> {
+    let registry = match REGISTRY.call_once(init_registry).try_lock() {
+        Some(registry) => registry,
+        None => return Some(time::monotonic()),
+    };
+
+    let mono = time::monotonic();
+    let real = time::realtime();
+    let ns = |time: (u64, u64)| time.0 * 1_000_000_000 + time.1;
+
+    registry.iter().map(|timeout| {
+        let deadline = match timeout.clock {
+            // Realtime deadlines are as far from the current monotonic time as from realtime
+            CLOCK_REALTIME => ns(timeout.time).saturating_sub(ns(real)) + ns(mono),
+            _ => ns(timeout.time),
+        };
+        (deadline / 1_000_000_000, deadline % 1_000_000_000)
+    }).min()
+}
+
+/// Trigger the expired timeouts. Called from the timer interrupts, which may have interrupted
+/// a CPU holding the registry, in which case they are triggered on the next tick
 pub fn trigger() {
-    let mut registry = registry();
+    let mut registry = match REGISTRY.call_once(init_registry).try_lock() {
+        Some(registry) => registry,
+        None => return,
+    };
 
     let mono = time::monotonic();
     let real = time::realtime();
@@ -211,12 +211,25 @@ pub fn kmain(cpus: usize, env: &'static [u8]) -> ! {
     info!("BSP: {:?} {}", pid, cpus);
     info!("Env: {:?}", ::core::str::from_utf8(env));
 
+    // The size the kernel heap may grow to can be changed from the environment
+    for var in env.split(|b| *b == b'\n') {
+        let prefix = b"KERNEL_HEAP_MAX_MB=";
+        if var.starts_with(prefix) {
+            match ::core::str::from_utf8(&var[prefix.len()..]).ok().and_then(|mb| mb.parse::<usize>().ok()) {
+                Some(mb) => allocator::set_max_size(mb * 1024 * 1024),
+                None => info!("Invalid KERNEL_HEAP_MAX_MB: {:?}", ::core::str::from_utf8(var)),
+            }
+        }
+    }
+    info!("Kernel heap: {} KB, at most {} KB", allocator::size() / 1024, allocator::max_size() / 1024);
+
     match context::contexts_mut().spawn(userspace_init) {
         Ok(context_lock) => {
             let mut context = context_lock.write();
             context.rns = SchemeNamespace::from(1);
             context.ens = SchemeNamespace::from(1);
             context.status = context::Status::Runnable;
+            context::run_queue::enqueue(&mut context);
         },
         Err(err) => {
             panic!("failed to spawn userspace_init: {:?}", err);
@@ -229,8 +242,13 @@ pub fn kmain(cpus: usize, env: &'static [u8]) -> ! {
             if context::switch() {
                 interrupt::enable_and_nop();
             } else {
+                // Only wake up for the next sleeping context, once the BSP is tickless too
+                device::tickless::idle();
+
                 // 启用中断，然后停止CPU（以节省电源），直到下一个中断真正被触发。
+                device::tickless::set_idle(true);
                 interrupt::enable_and_halt();
+                device::tickless::set_idle(false);
             }
         }
     }
@@ -253,8 +271,13 @@ pub fn kmain_ap(id: usize) -> ! {
                 if context::switch() {
                     interrupt::enable_and_nop();
                 } else {
+                    // Only wake up for the next sleeping context, if the CPU is tickless
+                    device::tickless::idle();
+
                     // Enable interrupts, then halt CPU (to save power) until the next interrupt is actually fired.
+                    device::tickless::set_idle(true);
                     interrupt::enable_and_halt();
+                    device::tickless::set_idle(false);
                 }
             }
         }
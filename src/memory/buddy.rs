@@ -0,0 +1,380 @@
+//! # Buddy frame allocator
+//! Free frames are kept in blocks of `2^order` frames, aligned to their size. Allocation splits
+//! the smallest block that fits, and deallocation merges a block with its buddy for as long as
+//! the buddy is free, so both take O(log n) time and free memory does not fragment over time.
+//!
+//! Each NUMA node has its own free lists, and allocations are served from the node of the
+//! current CPU first.
+//!
+//! The descriptor of every frame is marked when it is allocated or freed.
+//!
+//! The free lists are linked through the descriptors of the first frame of each block, so they
+//! never allocate from the kernel heap, which itself needs frames to grow. Until the descriptors
+//! are mapped frames are handed out by the bump allocator. Its remaining frames are moved into
+//! the buddy allocator by `set_noncore`.
+
+use alloc::vec::Vec;
+use core::cmp;
+
+use super::bump::BumpAllocator;
+use super::descriptor::{descriptor, FrameDescriptor, NOT_LISTED};
+use super::numa::Topology;
+use super::{Frame, FrameAllocator, MemoryAreaIter, MEMORY_AREA_FREE, PAGE_SIZE, PhysicalAddress};
+
+use syscall::{PartialAllocStrategy, PhysallocFlags};
+
+/// Largest block order, blocks of 2^18 frames are 1 GiB
+pub const MAX_ORDER: usize = 18;
+
+/// First frame number above the 32-bit physical address space
+const SPACE_32_END: usize = 0x1_0000_0000 / PAGE_SIZE;
+
+/// Ends a free list
+const NONE: usize = usize::max_value();
+
+/// Get the smallest order of a block holding `count` frames
+fn order_for(count: usize) -> usize {
+    count.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Get the descriptor linking a free block into its free list. Every frame of usable memory has
+/// one
+fn link(number: usize) -> &'static FrameDescriptor {
+    descriptor(&Frame { number }).expect("buddy: free frame without a descriptor")
+}
+
+/// A range of physical memory with its own free lists
+pub struct Zone {
+    /// Identifies the free lists of the zone in the frame descriptors
+    id: usize,
+    /// First free block of each order, by first frame number, or `NONE`
+    heads: [usize; MAX_ORDER + 1],
+    /// Number of free blocks of each order
+    blocks: [usize; MAX_ORDER + 1],
+    /// Number of free frames
+    free_frames: usize,
+}
+
+impl Zone {
+    fn new(id: usize) -> Zone {
+        Zone {
+            id,
+            heads: [NONE; MAX_ORDER + 1],
+            blocks: [0; MAX_ORDER + 1],
+            free_frames: 0,
+        }
+    }
+
+    /// Number of free frames in the zone
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of free blocks of each order
+    pub fn free_blocks(&self) -> impl Iterator<Item = usize> + '_ {
+        self.blocks.iter().cloned()
+    }
+
+    /// Tag of the free list of `order` in the frame descriptors
+    fn list(&self, order: usize) -> usize {
+        self.id * (MAX_ORDER + 1) + order
+    }
+
+    /// Check if a free block of `order` starts at `number`
+    fn listed(&self, number: usize, order: usize) -> bool {
+        descriptor(&Frame { number }).map_or(false, |descriptor| descriptor.list() == self.list(order))
+    }
+
+    /// Add a block to the free list of its order, without merging it
+    fn push(&mut self, number: usize, order: usize) {
+        let head = self.heads[order];
+        link(number).set_list(self.list(order), NONE, head);
+        if head != NONE {
+            link(head).set_prev(number);
+        }
+        self.heads[order] = number;
+        self.blocks[order] += 1;
+    }
+
+    /// Remove a block from the free list of its order
+    fn remove(&mut self, number: usize, order: usize) {
+        let descriptor = link(number);
+        let (prev, next) = descriptor.links();
+        if prev == NONE {
+            self.heads[order] = next;
+        } else {
+            link(prev).set_next(next);
+        }
+        if next != NONE {
+            link(next).set_prev(prev);
+        }
+        descriptor.set_list(NOT_LISTED, NONE, NONE);
+        self.blocks[order] -= 1;
+    }
+
+    /// Add a free block, merging it with its buddy while possible
+    fn insert(&mut self, mut number: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = number ^ (1 << order);
+            if ! self.listed(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            number = cmp::min(number, buddy);
+            order += 1;
+        }
+        self.push(number, order);
+    }
+
+    /// Add a range of free frames, split into the largest aligned blocks
+    fn free_range(&mut self, mut number: usize, mut count: usize) {
+        self.free_frames += count;
+        while count > 0 {
+            let mut order = cmp::min(number.trailing_zeros() as usize, MAX_ORDER);
+            while 1 << order > count {
+                order -= 1;
+            }
+            self.insert(number, order);
+            number += 1 << order;
+            count -= 1 << order;
+        }
+    }
+
+    /// Number of frames in the largest free block
+    fn largest(&self) -> usize {
+        self.blocks.iter().rposition(|&blocks| blocks > 0).map_or(0, |order| 1 << order)
+    }
+
+    /// Allocate `count` contiguous frames, returning the first frame number
+    fn allocate(&mut self, count: usize) -> Option<usize> {
+        let order = order_for(count);
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let found = (order..=MAX_ORDER).find(|&found| self.heads[found] != NONE)?;
+        let number = self.heads[found];
+        self.remove(number, found);
+
+        // Give back the upper halves of the block until it has the requested order
+        for split in (order..found).rev() {
+            self.push(number + (1 << split), split);
+        }
+        self.free_frames -= 1 << order;
+
+        // Give back the frames past the requested count
+        let excess = (1 << order) - count;
+        if excess > 0 {
+            self.free_range(number + count, excess);
+        }
+
+        Some(number)
+    }
+}
+
+/// Allocate `count` frames from a zone. With `partial_alloc`, fall back to the largest free
+/// block if it holds at least `min` frames
+fn allocate_in(zone: &mut Zone, count: usize, partial_alloc: bool, min: usize) -> Option<(Frame, usize)> {
+    if let Some(number) = zone.allocate(count) {
+        return Some((Frame { number }, count));
+    }
+
+    if partial_alloc {
+        let size = cmp::min(zone.largest(), count);
+        if size > 0 && size >= min {
+            return zone.allocate(size).map(|number| (Frame { number }, size));
+        }
+    }
+
+    None
+}
+
+/// The memory of one NUMA node
+pub struct Node {
+    /// Frames below 4 GiB, used for `SPACE_32` allocations and when the normal zone is empty
+    dma32: Zone,
+    /// Frames above 4 GiB
+    normal: Zone,
+    /// Number of usable frames
+    total_frames: usize,
+    /// Nodes to allocate from for CPUs on this node, nearest first
+    fallback: Vec<usize>,
+}
+
+impl Node {
+    fn new(id: usize, fallback: Vec<usize>) -> Node {
+        Node {
+            dma32: Zone::new(id * 2),
+            normal: Zone::new(id * 2 + 1),
+            total_frames: 0,
+            fallback,
+        }
+    }
+
+    /// Get the zones, lowest addresses first
+    pub fn zones(&self) -> [(&'static str, &Zone); 2] {
+        [("DMA32", &self.dma32), ("Normal", &self.normal)]
+    }
+
+    /// Number of free frames on the node
+    pub fn free_frames(&self) -> usize {
+        self.dma32.free_frames + self.normal.free_frames
+    }
+
+    /// Number of used frames on the node
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames()
+    }
+
+    /// Add a range of free frames to the zones it belongs to
+    fn free_range(&mut self, number: usize, count: usize) {
+        let end = number + count;
+        if number < SPACE_32_END {
+            let dma32_end = cmp::min(end, SPACE_32_END);
+            self.dma32.free_range(number, dma32_end - number);
+        }
+        if end > SPACE_32_END {
+            let normal_start = cmp::max(number, SPACE_32_END);
+            self.normal.free_range(normal_start, end - normal_start);
+        }
+    }
+
+    fn allocate(&mut self, count: usize, space32: bool, partial_alloc: bool, min: usize) -> Option<(Frame, usize)> {
+        // Keep the frames below 4 GiB for the allocations that need them
+        if ! space32 {
+            if let Some(result) = allocate_in(&mut self.normal, count, partial_alloc, min) {
+                return Some(result);
+            }
+        }
+        allocate_in(&mut self.dma32, count, partial_alloc, min)
+    }
+}
+
+pub struct BuddyAllocator {
+    /// Allocator used until the kernel heap is available
+    boot: BumpAllocator,
+    /// Nodes and CPUs, read from the ACPI tables by `set_noncore`
+    topology: Topology,
+    /// Free lists of each node
+    nodes: Vec<Node>,
+    /// The free frames of the bump allocator were moved to the nodes
+    noncore: bool,
+    /// Number of usable frames
+    total_frames: usize,
+}
+
+impl BuddyAllocator {
+    pub fn new(boot: BumpAllocator) -> Self {
+        Self {
+            boot,
+            topology: Topology::single(),
+            nodes: Vec::new(),
+            noncore: false,
+            total_frames: 0,
+        }
+    }
+
+    /// Get the NUMA topology
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// Get the nodes, empty until `set_noncore`
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Add a range of free frames to the nodes it belongs to
+    fn free_range(&mut self, number: usize, count: usize) {
+        for number in number..number + count {
+            if let Some(descriptor) = descriptor(&Frame { number }) {
+                descriptor.free();
+            }
+        }
+
+        let nodes = &mut self.nodes;
+        self.topology.split(number, count, |node, number, count| {
+            nodes[node].free_range(number, count);
+        });
+    }
+}
+
+impl FrameAllocator for BuddyAllocator {
+    fn set_noncore(&mut self, noncore: bool) {
+        if noncore && ! self.noncore {
+            self.topology = Topology::detect();
+            self.nodes = (0..self.topology.nodes()).map(|node| {
+                Node::new(node, self.topology.fallback(node))
+            }).collect();
+
+            {
+                let nodes = &mut self.nodes;
+                for area in MemoryAreaIter::new(MEMORY_AREA_FREE) {
+                    let start = Frame::containing_address(PhysicalAddress::new(area.base_addr as usize));
+                    let end = Frame::containing_address(PhysicalAddress::new((area.base_addr + area.length - 1) as usize));
+                    self.topology.split(start.number, end.number + 1 - start.number, |node, _number, count| {
+                        nodes[node].total_frames += count;
+                    });
+                }
+            }
+
+            self.total_frames = self.boot.free_frames() + self.boot.used_frames();
+            for (frame, count) in self.boot.free_ranges() {
+                self.free_range(frame.number, count);
+            }
+            self.noncore = true;
+        }
+    }
+
+    fn free_frames(&self) -> usize {
+        if self.noncore {
+            self.nodes.iter().map(|node| node.free_frames()).sum()
+        } else {
+            self.boot.free_frames()
+        }
+    }
+
+    fn used_frames(&self) -> usize {
+        if self.noncore {
+            self.total_frames - self.free_frames()
+        } else {
+            self.boot.used_frames()
+        }
+    }
+
+    fn allocate_frames3(&mut self, count: usize, flags: PhysallocFlags, strategy: Option<PartialAllocStrategy>, min: usize) -> Option<(Frame, usize)> {
+        if ! self.noncore {
+            return self.boot.allocate_frames3(count, flags, strategy, min);
+        }
+        if count == 0 {
+            return None;
+        }
+
+        let space32 = flags.contains(PhysallocFlags::SPACE_32);
+        let partial_alloc = flags.contains(PhysallocFlags::PARTIAL_ALLOC);
+
+        // Prefer the node of the current CPU, then the nearest others
+        let local = self.topology.cpu_node(crate::cpu_id());
+        for i in 0..self.nodes[local].fallback.len() {
+            let node = self.nodes[local].fallback[i];
+            if let Some((frame, count)) = self.nodes[node].allocate(count, space32, partial_alloc, min) {
+                let owner = crate::context::context_id().into();
+                for number in frame.number..frame.number + count {
+                    if let Some(descriptor) = descriptor(&Frame { number }) {
+                        descriptor.allocate(owner);
+                    }
+                }
+                return Some((frame, count));
+            }
+        }
+        None
+    }
+
+    fn deallocate_frames(&mut self, frame: Frame, count: usize) {
+        if self.noncore {
+            self.free_range(frame.number, count);
+        } else {
+            self.boot.deallocate_frames(frame, count);
+        }
+    }
+}
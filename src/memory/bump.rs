@@ -1,6 +1,8 @@
 //! # Bump frame allocator
 //! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/allocating-frames.html)
 
+use alloc::vec::Vec;
+
 use crate::paging::PhysicalAddress;
 use super::{Frame, FrameAllocator, MemoryArea, MemoryAreaIter};
 
@@ -44,6 +46,35 @@ impl BumpAllocator {
     }
 }
 
+impl BumpAllocator {
+    /// Get the ranges of frames that were never allocated, as the first frame and the number
+    /// of frames
+    pub fn free_ranges(&self) -> Vec<(Frame, usize)> {
+        let mut ranges = Vec::new();
+
+        for area in self.areas.clone() {
+            let area_start = Frame::containing_address(PhysicalAddress::new(area.base_addr as usize));
+            let area_end = Frame::containing_address(PhysicalAddress::new((area.base_addr + area.length - 1) as usize));
+
+            let start = if area_start < self.next_free_frame { self.next_free_frame.number } else { area_start.number };
+            let end = area_end.number + 1;
+
+            // Leave out the kernel
+            let mut push = |start: usize, end: usize| if start < end {
+                ranges.push((Frame { number: start }, end - start));
+            };
+            if start <= self.kernel_end.number && end > self.kernel_start.number {
+                push(start, self.kernel_start.number);
+                push(self.kernel_end.number + 1, end);
+            } else {
+                push(start, end);
+            }
+        }
+
+        ranges
+    }
+}
+
 impl FrameAllocator for BumpAllocator {
     #[allow(unused)]
     fn set_noncore(&mut self, noncore: bool) {}
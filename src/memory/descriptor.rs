@@ -0,0 +1,189 @@
+//! # Frame descriptors
+//! Every frame of usable memory has a descriptor with its reference count, flags, and the
+//! context it was allocated for. The descriptors are an array indexed by frame number, mapped
+//! at `KERNEL_FRAMES_OFFSET` so that looking one up takes no locks.
+//!
+//! A frame is referenced once by whoever allocated it, and once more by every additional
+//! address space mapping it. Frames outside of usable memory, like those of devices, are never
+//! freed through `unref_frame`. Those past the end of usable memory have no descriptor, and
+//! those in holes below it are marked `RESERVED`.
+//!
+//! The frame allocator links its free blocks through the descriptors of their first frames, so
+//! that its free lists take no memory from the kernel heap.
+
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::paging::{ActivePageTable, Page, VirtualAddress};
+use crate::paging::entry::EntryFlags;
+use crate::paging::mapper::MapperFlushAll;
+
+use super::{Frame, MemoryAreaIter, MEMORY_AREA_FREE, PhysicalAddress};
+
+bitflags! {
+    pub struct FrameFlags: usize {
+        /// The frame must stay resident at its physical address, for example for DMA
+        const PINNED = 1 << 0;
+        /// The frame is not usable memory
+        const RESERVED = 1 << 1;
+    }
+}
+
+/// Free list tag of a frame that does not start a free block
+pub const NOT_LISTED: usize = usize::max_value();
+
+pub struct FrameDescriptor {
+    refcount: AtomicUsize,
+    flags: AtomicUsize,
+    owner: AtomicUsize,
+    /// Free list of the block starting at this frame, or `NOT_LISTED`
+    list: AtomicUsize,
+    /// Previous and next block in that free list
+    prev: AtomicUsize,
+    next: AtomicUsize,
+}
+
+impl FrameDescriptor {
+    /// Number of references to the frame, zero if it is free
+    pub fn refcount(&self) -> usize {
+        self.refcount.load(Ordering::SeqCst)
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_truncate(self.flags.load(Ordering::SeqCst))
+    }
+
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::SeqCst);
+    }
+
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(! flags.bits(), Ordering::SeqCst);
+    }
+
+    /// The context the frame was allocated for. This is only a hint, the frame may since have
+    /// been shared with or moved to other contexts
+    pub fn owner(&self) -> usize {
+        self.owner.load(Ordering::SeqCst)
+    }
+
+    pub fn set_owner(&self, owner: usize) {
+        self.owner.store(owner, Ordering::SeqCst);
+    }
+
+    /// Add a reference
+    pub fn add_ref(&self) {
+        self.refcount.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Remove a reference, returning true if it was the last one
+    pub fn remove_ref(&self) -> bool {
+        let old = self.refcount.fetch_sub(1, Ordering::SeqCst);
+        assert!(old > 0, "frame descriptor: reference removed from a free frame");
+        old == 1
+    }
+
+    /// Mark the frame as allocated for `owner`, with a single reference
+    pub(super) fn allocate(&self, owner: usize) {
+        debug_assert_eq!(self.refcount(), 0, "frame descriptor: free frame has references");
+        self.flags.store(0, Ordering::SeqCst);
+        self.owner.store(owner, Ordering::SeqCst);
+        self.refcount.store(1, Ordering::SeqCst);
+    }
+
+    /// Free list of the block starting at this frame, or `NOT_LISTED`
+    pub(super) fn list(&self) -> usize {
+        self.list.load(Ordering::SeqCst)
+    }
+
+    /// Previous and next block in the free list of this frame
+    pub(super) fn links(&self) -> (usize, usize) {
+        (self.prev.load(Ordering::SeqCst), self.next.load(Ordering::SeqCst))
+    }
+
+    pub(super) fn set_prev(&self, prev: usize) {
+        self.prev.store(prev, Ordering::SeqCst);
+    }
+
+    pub(super) fn set_next(&self, next: usize) {
+        self.next.store(next, Ordering::SeqCst);
+    }
+
+    /// Add the block starting at this frame to free list `list`, between `prev` and `next`
+    pub(super) fn set_list(&self, list: usize, prev: usize, next: usize) {
+        self.prev.store(prev, Ordering::SeqCst);
+        self.next.store(next, Ordering::SeqCst);
+        self.list.store(list, Ordering::SeqCst);
+    }
+
+    /// Mark the frame as free
+    pub(super) fn free(&self) {
+        self.refcount.store(0, Ordering::SeqCst);
+        self.flags.store(0, Ordering::SeqCst);
+        self.owner.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Number of descriptors, zero until `init`
+static DESCRIPTOR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Map the descriptor array. Every frame of usable memory starts out allocated, the frame
+/// allocator marks its free frames when it takes them over
+pub unsafe fn init() {
+    let count = MemoryAreaIter::new(MEMORY_AREA_FREE).map(|area| {
+        Frame::containing_address(PhysicalAddress::new((area.base_addr + area.length - 1) as usize)).number + 1
+    }).max().unwrap_or(0);
+    if count == 0 {
+        return;
+    }
+
+    let size = count * mem::size_of::<FrameDescriptor>();
+    {
+        let mut active_table = ActivePageTable::new();
+
+        let mut flush_all = MapperFlushAll::new();
+
+        let start_page = Page::containing_address(VirtualAddress::new(crate::KERNEL_FRAMES_OFFSET));
+        let end_page = Page::containing_address(VirtualAddress::new(crate::KERNEL_FRAMES_OFFSET + size - 1));
+        for page in Page::range_inclusive(start_page, end_page) {
+            let result = active_table.map(page, EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+            flush_all.consume(result);
+        }
+
+        flush_all.flush(&mut active_table);
+    }
+
+    let descriptors = crate::KERNEL_FRAMES_OFFSET as *mut FrameDescriptor;
+    for i in 0..count {
+        ptr::write(descriptors.add(i), FrameDescriptor {
+            refcount: AtomicUsize::new(1),
+            flags: AtomicUsize::new(FrameFlags::RESERVED.bits()),
+            owner: AtomicUsize::new(0),
+            list: AtomicUsize::new(NOT_LISTED),
+            prev: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+        });
+    }
+
+    for area in MemoryAreaIter::new(MEMORY_AREA_FREE) {
+        let start_frame = Frame::containing_address(PhysicalAddress::new(area.base_addr as usize));
+        let end_frame = Frame::containing_address(PhysicalAddress::new((area.base_addr + area.length - 1) as usize));
+        for number in start_frame.number..=end_frame.number {
+            (*descriptors.add(number)).remove_flags(FrameFlags::RESERVED);
+        }
+    }
+
+    DESCRIPTOR_COUNT.store(count, Ordering::SeqCst);
+
+    println!("Frame descriptors: {} frames, {} KB", count, (size + 1023) / 1024);
+}
+
+/// Get the descriptor of a frame, if it is not past the end of usable memory
+pub fn descriptor(frame: &Frame) -> Option<&'static FrameDescriptor> {
+    if frame.number < DESCRIPTOR_COUNT.load(Ordering::SeqCst) {
+        Some(unsafe { &*(crate::KERNEL_FRAMES_OFFSET as *const FrameDescriptor).add(frame.number) })
+    } else {
+        None
+    }
+}
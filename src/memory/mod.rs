@@ -5,13 +5,19 @@ use crate::log::info;
 pub use crate::paging::{PAGE_SIZE, PhysicalAddress};
 
 use self::bump::BumpAllocator;
-use self::recycle::RecycleAllocator;
+use self::buddy::BuddyAllocator;
+pub use self::descriptor::{descriptor, FrameDescriptor, FrameFlags};
 
-use spin::Mutex;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
 use syscall::{PartialAllocStrategy, PhysallocFlags};
 
+pub mod buddy;
 pub mod bump;
-pub mod recycle;
+pub mod descriptor;
+pub mod numa;
 
 /// The current memory map. It's size is maxed out to 512 entries, due to it being
 /// from 0x500 to 0x5000 (800 is the absolute total)
@@ -68,7 +74,41 @@ impl Iterator for MemoryAreaIter {
     }
 }
 
-static ALLOCATOR: Mutex<Option<RecycleAllocator<BumpAllocator>>> = Mutex::new(None);
+static ALLOCATOR: Mutex<Option<BuddyAllocator>> = Mutex::new(None);
+
+/// Value of `ALLOCATOR_CPU` while no CPU holds the frame allocator
+const NO_CPU: usize = usize::max_value();
+/// The CPU holding the frame allocator
+static ALLOCATOR_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+/// The locked frame allocator, recording the CPU that holds it
+struct AllocatorGuard(MutexGuard<'static, Option<BuddyAllocator>>);
+
+impl Deref for AllocatorGuard {
+    type Target = Option<BuddyAllocator>;
+
+    fn deref(&self) -> &Option<BuddyAllocator> {
+        &self.0
+    }
+}
+
+impl DerefMut for AllocatorGuard {
+    fn deref_mut(&mut self) -> &mut Option<BuddyAllocator> {
+        &mut self.0
+    }
+}
+
+impl Drop for AllocatorGuard {
+    fn drop(&mut self) {
+        ALLOCATOR_CPU.store(NO_CPU, Ordering::SeqCst);
+    }
+}
+
+fn lock_allocator() -> AllocatorGuard {
+    let guard = ALLOCATOR.lock();
+    ALLOCATOR_CPU.store(crate::cpu_id(), Ordering::SeqCst);
+    AllocatorGuard(guard)
+}
 
 /// Init memory module
 /// Must be called once, and only once,
@@ -81,23 +121,37 @@ pub unsafe fn init(kernel_start: usize, kernel_end: usize) {
         }
     }
 
-    *ALLOCATOR.lock() = Some(RecycleAllocator::new(BumpAllocator::new(kernel_start, kernel_end, MemoryAreaIter::new(MEMORY_AREA_FREE))));
+    *ALLOCATOR.lock() = Some(BuddyAllocator::new(BumpAllocator::new(kernel_start, kernel_end, MemoryAreaIter::new(MEMORY_AREA_FREE))));
     //获取一个互斥锁，阻塞当前线程，直到申请一个分配器，参数是内核的开始到结束，内存类型是可用（free）。
 }
 
 /// Init memory module after core
 /// Must be called once, and only once,
 pub unsafe fn init_noncore() {
-    if let Some(ref mut allocator) = *ALLOCATOR.lock() {//阻塞直到得到一个ALLOCATOR锁可用，获得它的的引用。
+    descriptor::init();
+
+    if let Some(ref mut allocator) = *lock_allocator() {//阻塞直到得到一个ALLOCATOR锁可用，获得它的的引用。
         allocator.set_noncore(true)//设置noncore为true
     } else {
         panic!("frame allocator not initialized");
     }
 }
 
+/// Check if the frame allocator is locked. It allocates from the kernel heap while locked, so
+/// the heap does not grow meanwhile
+pub fn allocator_locked() -> bool {
+    ALLOCATOR.try_lock().is_none()
+}
+
+/// Check if the frame allocator is locked by this CPU, which can not wait for it. The owner is
+/// only set while the lock is held, and is cleared before it is released
+pub fn allocator_locked_here() -> bool {
+    ALLOCATOR_CPU.load(Ordering::SeqCst) == crate::cpu_id()
+}
+
 /// Get the number of frames available 返回可用帧的数量
 pub fn free_frames() -> usize {
-    if let Some(ref allocator) = *ALLOCATOR.lock() {
+    if let Some(ref allocator) = *lock_allocator() {
         allocator.free_frames()
     } else {
         panic!("frame allocator not initialized");
@@ -106,23 +160,44 @@ pub fn free_frames() -> usize {
 
 /// Get the number of frames used 返回使用帧的数量
 pub fn used_frames() -> usize {
-    if let Some(ref allocator) = *ALLOCATOR.lock() {
+    if let Some(ref allocator) = *lock_allocator() {
         allocator.used_frames()
     } else {
         panic!("frame allocator not initialized");
     }
 }
 
+/// Get the proximity domain, free frames, used frames, and distances to the other nodes of
+/// each NUMA node
+pub fn numa_nodes() -> Vec<(u32, usize, usize, Vec<u8>)> {
+    if let Some(ref allocator) = *lock_allocator() {
+        let topology = allocator.topology();
+        allocator.nodes().iter().enumerate().map(|(i, node)| {
+            let distances = (0..topology.nodes()).map(|other| topology.distance(i, other)).collect();
+            (topology.domain(i), node.free_frames(), node.used_frames(), distances)
+        }).collect()
+    } else {
+        panic!("frame allocator not initialized");
+    }
+}
+
 /// Allocate a range of frames 分配一定数量的帧
 pub fn allocate_frames(count: usize) -> Option<Frame> {
-    if let Some(ref mut allocator) = *ALLOCATOR.lock() {
+    let frame = if let Some(ref mut allocator) = *lock_allocator() {
         allocator.allocate_frames(count)
     } else {
         panic!("frame allocator not initialized");
+    };
+
+    // Kill a process to get memory back, unless the frames were only too fragmented
+    if frame.is_none() && free_frames() < count {
+        crate::context::oom::out_of_memory();
     }
+
+    frame
 }
 pub fn allocate_frames_complex(count: usize, flags: PhysallocFlags, strategy: Option<PartialAllocStrategy>, min: usize) -> Option<(Frame, usize)> {
-    if let Some(ref mut allocator) = *ALLOCATOR.lock() {
+    if let Some(ref mut allocator) = *lock_allocator() {
         allocator.allocate_frames3(count, flags, strategy, min)
     } else {
         panic!("frame allocator not initialized");
@@ -131,13 +206,40 @@ pub fn allocate_frames_complex(count: usize, flags: PhysallocFlags, strategy: Op
 
 /// Deallocate a range of frames frame 释放一定数量的帧
 pub fn deallocate_frames(frame: Frame, count: usize) {
-    if let Some(ref mut allocator) = *ALLOCATOR.lock() {
+    if let Some(ref mut allocator) = *lock_allocator() {
         allocator.deallocate_frames(frame, count)
     } else {
         panic!("frame allocator not initialized");
     }
 }
 
+/// Get the number of references to a frame. Frames without a descriptor, past the end of
+/// usable memory, are not counted and may be mapped anywhere, so they are always reported as
+/// shared, and copy-on-write mappings of them are copied instead of taken over
+pub fn frame_refcount(frame: &Frame) -> usize {
+    descriptor(frame).map_or(usize::max_value(), |descriptor| descriptor.refcount())
+}
+
+/// Add a reference to a frame that is being mapped by another address space. Like
+/// `unref_frame`, this ignores frames outside of usable memory, so their references stay balanced
+pub fn ref_frame(frame: &Frame) {
+    if let Some(descriptor) = descriptor(frame) {
+        if ! descriptor.flags().contains(FrameFlags::RESERVED) {
+            descriptor.add_ref();
+        }
+    }
+}
+
+/// Remove a reference to a frame, deallocating it when the last reference is gone. Frames
+/// outside of usable memory are never deallocated
+pub fn unref_frame(frame: Frame) {
+    if let Some(descriptor) = descriptor(&frame) {
+        if ! descriptor.flags().contains(FrameFlags::RESERVED) && descriptor.remove_ref() {
+            deallocate_frames(frame, 1);
+        }
+    }
+}
+
 /// A frame, allocated by the frame allocator.
 /// Do not add more derives, or make anything `pub`!
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -0,0 +1,166 @@
+//! # NUMA topology
+//! Nodes are the proximity domains of the ACPI SRAT, numbered in the order they first appear.
+//! Distances between nodes come from the ACPI SLIT. Without an SRAT, all memory and CPUs
+//! belong to a single node.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp;
+
+#[cfg(feature = "acpi")]
+use super::PAGE_SIZE;
+
+/// Distance from a node to itself
+pub const LOCAL_DISTANCE: u8 = 10;
+
+/// Distance between nodes when the SLIT does not say
+pub const REMOTE_DISTANCE: u8 = 20;
+
+pub struct Topology {
+    /// Proximity domain of each node
+    domains: Vec<u32>,
+    /// Memory of each node as the first frame, the frame after the last, and the node
+    ranges: Vec<(usize, usize, usize)>,
+    /// Node of each CPU, by CPU id
+    cpus: BTreeMap<usize, usize>,
+    /// Distance between each pair of nodes
+    distances: Vec<u8>,
+}
+
+impl Topology {
+    /// A single node holding all memory and CPUs
+    pub fn single() -> Topology {
+        Topology {
+            domains: vec![0],
+            ranges: Vec::new(),
+            cpus: BTreeMap::new(),
+            distances: vec![LOCAL_DISTANCE],
+        }
+    }
+
+    /// Read the topology from the ACPI tables
+    #[cfg(feature = "acpi")]
+    pub fn detect() -> Topology {
+        use crate::acpi::ACPI_TABLE;
+        use crate::acpi::srat::SratEntry;
+        use core::mem;
+        use crate::device::local_apic;
+
+        let mut topology = Topology {
+            domains: Vec::new(),
+            ranges: Vec::new(),
+            cpus: BTreeMap::new(),
+            distances: Vec::new(),
+        };
+
+        if let Some(ref srat) = *ACPI_TABLE.srat.read() {
+            for entry in srat.iter() {
+                match entry {
+                    SratEntry::LocalApic(cpu) => if cpu.flags & 1 == 1 {
+                        let node = topology.node_for_domain(cpu.proximity_domain());
+                        topology.cpus.insert(cpu.apic_id as usize, node);
+                    },
+                    SratEntry::X2Apic(cpu) => if cpu.flags & 1 == 1 {
+                        let node = topology.node_for_domain(cpu.proximity_domain);
+                        topology.cpus.insert(cpu.x2apic_id as usize, node);
+                    },
+                    SratEntry::Memory(memory) => if memory.flags & 1 == 1 {
+                        let start = ((memory.base_address + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64) as usize;
+                        let end = ((memory.base_address + memory.length) / PAGE_SIZE as u64) as usize;
+                        if start < end {
+                            let node = topology.node_for_domain(memory.proximity_domain);
+                            topology.ranges.push((start, end, node));
+                        }
+                    },
+                    _ => ()
+                }
+            }
+        }
+
+        if topology.domains.is_empty() {
+            return Topology::single();
+        }
+
+        topology.ranges.sort();
+
+        // The table has local APIC ids, which are mapped to the dense logical ids of the CPUs
+        let apic_cpus = mem::replace(&mut topology.cpus, BTreeMap::new());
+        let mut cpu_id = 0;
+        while let Some(apic_id) = local_apic::apic_id(cpu_id) {
+            if let Some(&node) = apic_cpus.get(&(apic_id as usize)) {
+                topology.cpus.insert(cpu_id, node);
+            }
+            cpu_id += 1;
+        }
+
+        let slit = ACPI_TABLE.slit.read();
+        for &from in topology.domains.iter() {
+            for &to in topology.domains.iter() {
+                let distance = slit.as_ref().and_then(|slit| slit.distance(from, to));
+                topology.distances.push(distance.unwrap_or(if from == to { LOCAL_DISTANCE } else { REMOTE_DISTANCE }));
+            }
+        }
+
+        topology
+    }
+
+    #[cfg(not(feature = "acpi"))]
+    pub fn detect() -> Topology {
+        Topology::single()
+    }
+
+    #[cfg(feature = "acpi")]
+    fn node_for_domain(&mut self, domain: u32) -> usize {
+        match self.domains.iter().position(|&other| other == domain) {
+            Some(node) => node,
+            None => {
+                self.domains.push(domain);
+                self.domains.len() - 1
+            }
+        }
+    }
+
+    /// Number of nodes
+    pub fn nodes(&self) -> usize {
+        self.domains.len()
+    }
+
+    /// Proximity domain of a node
+    pub fn domain(&self, node: usize) -> u32 {
+        self.domains[node]
+    }
+
+    /// Node of a CPU. CPUs missing from the SRAT are put on the first node
+    pub fn cpu_node(&self, cpu_id: usize) -> usize {
+        self.cpus.get(&cpu_id).cloned().unwrap_or(0)
+    }
+
+    /// Distance between two nodes
+    pub fn distance(&self, from: usize, to: usize) -> u8 {
+        self.distances[from * self.nodes() + to]
+    }
+
+    /// Get the nodes ordered by their distance from `node`, starting with `node` itself
+    pub fn fallback(&self, node: usize) -> Vec<usize> {
+        let mut nodes: Vec<usize> = (0..self.nodes()).collect();
+        nodes.sort_by_key(|&other| (other != node, self.distance(node, other), other));
+        nodes
+    }
+
+    /// Split a range of frames at node boundaries, calling `f` with the node, first frame, and
+    /// number of frames of each part. Frames missing from the SRAT are put on the first node
+    pub fn split<F: FnMut(usize, usize, usize)>(&self, mut number: usize, count: usize, mut f: F) {
+        let end = number + count;
+        while number < end {
+            let (node, node_end) = match self.ranges.iter().find(|range| range.1 > number) {
+                Some(&(start, range_end, node)) if start <= number => (node, range_end),
+                Some(&(start, _, _)) => (0, start),
+                None => (0, end),
+            };
+
+            let part_end = cmp::min(end, node_end);
+            f(node, number, part_end - number);
+            number = part_end;
+        }
+    }
+}
@@ -28,8 +28,10 @@ pub extern "C" fn rust_begin_unwind(info: &PanicInfo) -> ! {
 
 #[lang = "oom"]
 #[no_mangle]
-pub extern fn rust_oom(_layout: Layout) -> ! {
-    panic!("kernel memory allocation failed");
+pub extern fn rust_oom(layout: Layout) -> ! {
+    // Allocations made for a process are served past the heap limit after marking it to be
+    // killed, so this is only reached by kernel contexts, or when no frames are left at all
+    panic!("kernel memory allocation of {} bytes aligned to {} failed", layout.size(), layout.align());
 }
 
 #[allow(non_snake_case)]
@@ -13,8 +13,10 @@ use crate::{
         }
     },
     common::unique::Unique,
-    context::{self, signal, Context, ContextId},
+    context::{self, signal, swap, Context, ContextId},
     event,
+    ipi::{ipi, IpiKind, IpiTarget},
+    memory,
     scheme::proc,
     sync::WaitCondition,
     syscall::{
@@ -37,6 +39,7 @@ use alloc::{
 };
 use core::{
     cmp,
+    intrinsics,
     sync::atomic::Ordering
 };
 use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
@@ -447,7 +450,22 @@ pub unsafe fn regs_for_mut(context: &mut Context) -> Option<&mut InterruptStack>
 // |_|  |_|\___|_| |_| |_|\___/|_|   \__, |
 //                                   |___/
 
-pub fn with_context_memory<F>(context: &mut Context, offset: VirtualAddress, len: usize, f: F) -> Result<()>
+/// Unmap the linear pages used by `with_context_memory` (but allow no deallocation!)
+fn unmap_linear(active_page_table: &mut ActivePageTable, start: Page, pages: usize) {
+    let mut page = start;
+    let mut flusher = MapperFlushAll::new();
+    for _ in 0..pages {
+        flusher.consume(active_page_table.unmap_return(page, true).0);
+        page = page.next();
+    }
+
+    flusher.flush(active_page_table);
+}
+
+/// Map the memory of a context into the current address space and call `f` with a pointer to
+/// it. If `write` is set, pages whose frames are shared with other address spaces, such as
+/// copy-on-write pages, are given a private copy first
+pub fn with_context_memory<F>(context: &mut Context, offset: VirtualAddress, len: usize, write: bool, f: F) -> Result<()>
 where F: FnOnce(*mut u8) -> Result<()>
 {
     // As far as I understand, mapping any regions following
@@ -458,6 +476,40 @@ where F: FnOnce(*mut u8) -> Result<()>
     // in `proc:<pid>/mem`, or return a partial read/write.
     let start = Page::containing_address(VirtualAddress::new(crate::USER_TMP_MISC_OFFSET));
 
+    // Read swapped out pages first, as the swap scheme may need the page table lock. Each slot
+    // is referenced while it is read, so that it is not reused meanwhile
+    let mut swapped = BTreeMap::new();
+    {
+        let mut slots = Vec::new();
+        {
+            let mut active_page_table = unsafe { ActivePageTable::new() };
+            let mut target_page_table = unsafe {
+                InactivePageTable::from_address(context.arch.get_page_table())
+            };
+            active_page_table.with(&mut target_page_table, &mut TemporaryPage::new(start), |mapper| {
+                let start = Page::containing_address(offset);
+                let end = Page::containing_address(VirtualAddress::new(offset.get() + len - 1));
+                for page in Page::range_inclusive(start, end) {
+                    if let Some(slot) = mapper.translate_swap_slot(page) {
+                        swap::ref_slot(slot);
+                        slots.push(slot);
+                    }
+                }
+            });
+        }
+
+        let mut result = Ok(());
+        for slot in slots {
+            if result.is_ok() {
+                let mut data = swap::SwapPage::new();
+                result = swap::read_slot(slot, &mut data);
+                swapped.insert(slot, data);
+            }
+            swap::unref_slot(slot);
+        }
+        result?;
+    }
+
     let mut active_page_table = unsafe { ActivePageTable::new() };
     let mut target_page_table = unsafe {
         InactivePageTable::from_address(context.arch.get_page_table())
@@ -465,16 +517,50 @@ where F: FnOnce(*mut u8) -> Result<()>
 
     // Find the physical frames for all pages
     let mut frames = Vec::new();
+    let mut populated = Vec::new();
+    let mut restored = Vec::new();
 
     let mut result = None;
     active_page_table.with(&mut target_page_table, &mut TemporaryPage::new(start), |mapper| {
         let mut inner = || -> Result<()> {
             let start = Page::containing_address(offset);
             let end = Page::containing_address(VirtualAddress::new(offset.get() + len - 1));
+            // Check all pages first, so that no page is given a frame that is never cleared
             for page in Page::range_inclusive(start, end) {
+                if ! mapper.is_mapped(page) {
+                    return Err(Error::new(EFAULT));
+                }
+            }
+
+            for (i, page) in Page::range_inclusive(start, end).enumerate() {
+                let mut flags = mapper.translate_page_flags(page).ok_or(Error::new(EFAULT))?;
+
+                // Give lazily allocated pages a frame, which is cleared once it is mapped below
+                if flags.contains(EntryFlags::LAZY) && ! flags.contains(EntryFlags::PRESENT) {
+                    flags.remove(EntryFlags::LAZY);
+                    let (result, _frame) = mapper.unmap_take(page, true);
+                    // This is not the active table, so the flush can be ignored
+                    unsafe { result.ignore(); }
+                    let result = mapper.map(page, flags);
+                    unsafe { result.ignore(); }
+                    populated.push(i);
+                }
+
+                // Give swapped out pages a frame, which is filled with the data read above
+                if let Some(slot) = mapper.translate_swap_slot(page) {
+                    // The page may have been swapped out again since
+                    let data = swapped.remove(&slot).ok_or(Error::new(EAGAIN))?;
+                    flags.remove(EntryFlags::SWAPPED);
+                    let (result, _frame) = mapper.unmap_take(page, true);
+                    unsafe { result.ignore(); }
+                    let result = mapper.map(page, flags);
+                    unsafe { result.ignore(); }
+                    restored.push((i, data));
+                }
+
                 frames.push((
                     mapper.translate_page(page).ok_or(Error::new(EFAULT))?,
-                    mapper.translate_page_flags(page).ok_or(Error::new(EFAULT))?
+                    flags
                 ));
             }
             Ok(())
@@ -487,26 +573,97 @@ where F: FnOnce(*mut u8) -> Result<()>
     let pages = frames.len();
     let mut page = start;
     let mut flusher = MapperFlushAll::new();
-    for (frame, mut flags) in frames {
-        flags |= EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE;
-        flusher.consume(active_page_table.map_to(page, frame, flags));
+    let mut shared = Vec::new();
+    for (i, (frame, flags)) in frames.into_iter().enumerate() {
+        // Shared grants are written through, only copy-on-write pages that are not the last
+        // reference to their frame need a copy
+        if write && flags.contains(EntryFlags::COPY_ON_WRITE) && memory::frame_refcount(&frame) > 1 {
+            shared.push((i, flags));
+        }
+        flusher.consume(active_page_table.map_to(page, frame, flags | EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE));
 
         page = page.next();
     }
 
     flusher.flush(&mut active_page_table);
 
-    let res = f((start.start_address().get() + offset.get() % PAGE_SIZE) as *mut u8);
+    for i in populated {
+        unsafe {
+            intrinsics::write_bytes((start.start_address().get() + i * PAGE_SIZE) as *mut u8, 0, PAGE_SIZE);
+        }
+    }
 
-    // Unmap all the pages (but allow no deallocation!)
-    let mut page = start;
-    let mut flusher = MapperFlushAll::new();
-    for _ in 0..pages {
-        flusher.consume(active_page_table.unmap_return(page, true).0);
-        page = page.next();
+    for (i, data) in restored {
+        unsafe {
+            intrinsics::copy(data.0.as_ptr(), (start.start_address().get() + i * PAGE_SIZE) as *mut u8, PAGE_SIZE);
+        }
     }
 
-    flusher.flush(&mut active_page_table);
+    // Give copy-on-write pages a private copy, so that writing does not change other address spaces
+    if ! shared.is_empty() {
+        // Allocate the copies first, so that running out of frames leaves the target untouched
+        let mut new_frames = Vec::with_capacity(shared.len());
+        while new_frames.len() < shared.len() {
+            match memory::allocate_frames(1) {
+                Some(frame) => new_frames.push(frame),
+                None => {
+                    for frame in new_frames {
+                        memory::deallocate_frames(frame, 1);
+                    }
+                    unmap_linear(&mut active_page_table, start, pages);
+                    return Err(Error::new(ENOMEM));
+                }
+            }
+        }
+
+        let mut copies = Vec::new();
+        for ((i, flags), frame) in shared.into_iter().zip(new_frames) {
+            let page = Page::containing_address(VirtualAddress::new(start.start_address().get() + i * PAGE_SIZE));
+
+            let mut data = vec![0u8; PAGE_SIZE].into_boxed_slice();
+            unsafe {
+                intrinsics::copy(page.start_address().get() as *const u8, data.as_mut_ptr(), PAGE_SIZE);
+            }
+
+            let (result, _frame) = active_page_table.unmap_return(page, true);
+            result.flush(&mut active_page_table);
+
+            let result = active_page_table.map_to(page, frame.clone(), flags | EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE);
+            result.flush(&mut active_page_table);
+
+            unsafe {
+                intrinsics::copy(data.as_ptr(), page.start_address().get() as *mut u8, PAGE_SIZE);
+            }
+
+            let flags = if flags.contains(EntryFlags::COPY_ON_WRITE) {
+                (flags - EntryFlags::COPY_ON_WRITE) | EntryFlags::WRITABLE
+            } else {
+                flags
+            };
+            copies.push((Page::containing_address(VirtualAddress::new(offset.get() + i * PAGE_SIZE)), frame, flags));
+        }
+
+        // The linear pages are in use, so map the target page table right after them
+        let temporary_page = Page::containing_address(VirtualAddress::new(start.start_address().get() + pages * PAGE_SIZE));
+        active_page_table.with(&mut target_page_table, &mut TemporaryPage::new(temporary_page), |mapper| {
+            for (page, frame, flags) in copies {
+                let (result, old_frame) = mapper.unmap_return(page, true);
+                // This is not the active table, so the flush can be ignored
+                unsafe { result.ignore(); }
+                memory::unref_frame(old_frame);
+
+                let result = mapper.map_to(page, frame, flags);
+                unsafe { result.ignore(); }
+            }
+        });
+
+        // The context may be running on another CPU
+        ipi(IpiKind::Tlb, IpiTarget::Other);
+    }
+
+    let res = f((start.start_address().get() + offset.get() % PAGE_SIZE) as *mut u8);
+
+    unmap_linear(&mut active_page_table, start, pages);
 
     res
 }
@@ -0,0 +1,574 @@
+//! # Asynchronous I/O
+//! Opening `aio:` gives a queue that `open`, `close`, `read`, `write` and `fsync` requests on
+//! any scheme can be submitted to, by writing `AioSubmission`s to it. The opcode of a request is
+//! the number of the matching syscall. Each request is run by an `[aio]` kernel context, which
+//! takes the identity of the submitter, so requests to different schemes do not wait on each
+//! other.
+//!
+//! Reading the queue returns an `AioCompletion` for each finished request, with the `user_data`
+//! of the submission and the result of the syscall. Data is read into and written from kernel
+//! buffers, and read data is copied to the buffer of the submission when its completion is
+//! read. Files opened by a request are added to the file table of the context reading the
+//! completion. The queue triggers `EVENT_READ` when completions are available, so it can be
+//! watched with `event:`.
+//!
+//! The kernel buffers of the requests in flight of each user are limited to `AIO_MAX_BUFFERED`
+//! bytes, and each user can have at most `AIO_MAX_QUEUES` queues open. Both are counted by
+//! effective user id, so that forking does not get around them, and one user can not use up
+//! the buffers of everyone else.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::{cmp, mem, slice};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once, RwLock};
+
+use crate::context::{self, Status};
+use crate::context::file::FileDescriptor;
+use crate::event;
+use crate::memory::PAGE_SIZE;
+use crate::scheme::{FileHandle, SchemeId, SchemeNamespace};
+use crate::sync::WaitQueue;
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, EVENT_READ, O_NONBLOCK};
+use crate::syscall::number::{SYS_CLOSE, SYS_FSYNC, SYS_OPEN, SYS_READ, SYS_WRITE};
+use crate::syscall::scheme::Scheme;
+use crate::syscall;
+
+/// Largest buffer of a single read, write or path
+pub const AIO_MAX_LEN: usize = 1024 * 1024;
+
+/// Most requests a queue can have submitted and not read the completion of
+pub const AIO_MAX_PENDING: usize = 256;
+
+/// Most `[aio]` contexts running requests
+pub const AIO_MAX_WORKERS: usize = 16;
+
+/// Most bytes of kernel buffers of the requests of a user that have not been read back yet
+pub const AIO_MAX_BUFFERED: usize = 64 * 1024 * 1024;
+
+/// Most queues a user can have open
+pub const AIO_MAX_QUEUES: usize = 16;
+
+//TODO: Move `AioSubmission` and `AioCompletion` to `syscall::data`, so that userspace shares
+// these definitions
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct AioSubmission {
+    /// Returned with the completion
+    pub user_data: u64,
+    /// `SYS_OPEN`, `SYS_CLOSE`, `SYS_READ`, `SYS_WRITE` or `SYS_FSYNC`
+    pub opcode: usize,
+    /// File to operate on, unused for `SYS_OPEN`
+    pub fd: usize,
+    /// Buffer to read into or write from, or path to open
+    pub buf: usize,
+    /// Length of the buffer or path
+    pub len: usize,
+    /// Flags for `SYS_OPEN`
+    pub flags: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct AioCompletion {
+    pub user_data: u64,
+    /// Result of the request, as returned by the syscall
+    pub result: usize,
+}
+
+/// A buffer of whole zeroed pages, so that a userspace scheme it is lent to sees nothing else
+/// of the kernel heap
+#[repr(C, align(4096))]
+struct BufferPage([u8; PAGE_SIZE]);
+
+struct Buffer {
+    pages: Vec<BufferPage>,
+    len: usize,
+    /// User the buffer is counted for
+    uid: u32,
+}
+
+/// Bytes of the `Buffer`s of each user
+static BUFFERED: Once<Mutex<BTreeMap<u32, usize>>> = Once::new();
+
+fn buffered() -> &'static Mutex<BTreeMap<u32, usize>> {
+    BUFFERED.call_once(|| Mutex::new(BTreeMap::new()))
+}
+
+impl Buffer {
+    /// Allocate a buffer for user `uid`, failing with `EAGAIN` if it would go over
+    /// `AIO_MAX_BUFFERED`
+    fn new(uid: u32, len: usize) -> Result<Buffer> {
+        let count = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+        let size = count * PAGE_SIZE;
+        {
+            let mut buffered = buffered().lock();
+            let total = buffered.entry(uid).or_insert(0);
+            if *total + size > AIO_MAX_BUFFERED {
+                if *total == 0 {
+                    buffered.remove(&uid);
+                }
+                return Err(Error::new(EAGAIN));
+            }
+            *total += size;
+        }
+
+        let mut pages = Vec::with_capacity(count);
+        for _ in 0..count {
+            pages.push(BufferPage([0; PAGE_SIZE]));
+        }
+        Ok(Buffer { pages, len, uid })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.pages.as_ptr() as *const u8, self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.pages.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let mut buffered = buffered().lock();
+        if let Some(total) = buffered.get_mut(&self.uid) {
+            *total -= self.pages.len() * PAGE_SIZE;
+            if *total == 0 {
+                buffered.remove(&self.uid);
+            }
+        }
+    }
+}
+
+/// The identity a request is run with
+struct Identity {
+    euid: u32,
+    egid: u32,
+    ens: SchemeNamespace,
+    umask: usize,
+    cwd: Arc<Mutex<Vec<u8>>>,
+}
+
+struct Job {
+    queue: Weak<AioQueue>,
+    submission: AioSubmission,
+    identity: Identity,
+    file: Option<FileDescriptor>,
+    buffer: Option<Buffer>,
+}
+
+struct Completion {
+    submission: AioSubmission,
+    result: Result<usize>,
+    /// Data read by the request
+    buffer: Option<Buffer>,
+    /// File opened by the request
+    file: Option<FileDescriptor>,
+}
+
+impl Completion {
+    /// Finish the completion in the context reading it
+    fn deliver(self) -> AioCompletion {
+        let result = match (self.result, self.submission.opcode) {
+            (Ok(count), SYS_READ) => {
+                let buffer = self.buffer.expect("aio read without buffer");
+                let count = cmp::min(count, buffer.len);
+                syscall::validate_slice_mut(self.submission.buf as *mut u8, count).map(|buf| {
+                    buf.copy_from_slice(&buffer.as_slice()[..count]);
+                    count
+                })
+            },
+            (Ok(_), SYS_OPEN) => {
+                let file = self.file.expect("aio open without file");
+                let contexts = context::contexts();
+                match contexts.current() {
+                    Some(context_lock) => {
+                        let context = context_lock.read();
+                        context.add_file(file).map(FileHandle::into).ok_or(Error::new(EMFILE))
+                    },
+                    None => {
+                        let _ = file.close();
+                        Err(Error::new(ESRCH))
+                    }
+                }
+            },
+            (result, _) => result,
+        };
+
+        AioCompletion {
+            user_data: self.submission.user_data,
+            result: Error::mux(result),
+        }
+    }
+
+    /// Drop a completion that will not be read
+    fn discard(self) {
+        if let Some(file) = self.file {
+            let _ = file.close();
+        }
+    }
+}
+
+struct AioQueue {
+    scheme_id: SchemeId,
+    id: usize,
+    flags: usize,
+    /// Requests submitted and not read back yet
+    pending: AtomicUsize,
+    completions: WaitQueue<Completion>,
+}
+
+impl AioQueue {
+    fn complete(&self, completion: Completion) {
+        self.completions.send(completion);
+        event::trigger(self.scheme_id, self.id, EVENT_READ);
+    }
+}
+
+impl Drop for AioQueue {
+    fn drop(&mut self) {
+        while let Some(completion) = self.completions.inner.lock().pop_front() {
+            completion.discard();
+        }
+    }
+}
+
+/// Requests waiting for an `[aio]` context
+static JOBS: Once<WaitQueue<Job>> = Once::new();
+/// Number of `[aio]` contexts
+static WORKERS: AtomicUsize = AtomicUsize::new(0);
+/// Number of `[aio]` contexts waiting for a request
+static IDLE: AtomicUsize = AtomicUsize::new(0);
+
+fn jobs() -> &'static WaitQueue<Job> {
+    JOBS.call_once(WaitQueue::new)
+}
+
+/// Queue a request, starting another `[aio]` context if all are busy
+fn queue_job(job: Job) -> Result<()> {
+    if IDLE.load(Ordering::SeqCst) == 0 {
+        if WORKERS.fetch_add(1, Ordering::SeqCst) < AIO_MAX_WORKERS {
+            if let Err(err) = spawn_worker() {
+                // The request can still be run once a running context is done
+                if WORKERS.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    return Err(err);
+                }
+            }
+        } else {
+            WORKERS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    jobs().send(job);
+
+    Ok(())
+}
+
+fn spawn_worker() -> Result<()> {
+    let mut contexts = context::contexts_mut();
+    // Requests are run for any process, so the worker has no user memory of its own
+    let context_lock = contexts.spawn_kernel(aio_worker)?;
+    let mut context = context_lock.write();
+    *context.name.lock() = "[aio]".as_bytes().to_vec().into_boxed_slice();
+    context.status = Status::Runnable;
+    context::run_queue::enqueue(&mut context);
+    Ok(())
+}
+
+/// Take on the identity of the submitter of a request
+fn impersonate(identity: &Identity) -> Result<()> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+    context.euid = identity.euid;
+    context.egid = identity.egid;
+    context.ens = identity.ens;
+    context.umask = identity.umask;
+    context.cwd = Arc::clone(&identity.cwd);
+    Ok(())
+}
+
+/// Open a file, taking it out of the file table of the `[aio]` context
+fn open(path: &[u8], flags: usize) -> Result<FileDescriptor> {
+    let fd = syscall::open(path, flags)?;
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    context.remove_file(fd).ok_or(Error::new(EBADF))
+}
+
+fn run(mut job: Job) {
+    let submission = job.submission;
+    let mut opened = None;
+
+    let result = impersonate(&job.identity).and_then(|()| match submission.opcode {
+        SYS_OPEN => {
+            let buffer = job.buffer.take().expect("aio open without path");
+            open(buffer.as_slice(), submission.flags).map(|file| {
+                opened = Some(file);
+                0
+            })
+        },
+        SYS_CLOSE => {
+            job.file.take().expect("aio close without file").close()
+        },
+        SYS_READ => {
+            let file = job.file.as_ref().expect("aio read without file");
+            let buffer = job.buffer.as_mut().expect("aio read without buffer").as_mut_slice();
+            syscall::file_op_desc(SYS_READ, file, buffer.as_mut_ptr() as usize, buffer.len())
+        },
+        SYS_WRITE => {
+            let file = job.file.as_ref().expect("aio write without file");
+            let buffer = job.buffer.as_ref().expect("aio write without buffer").as_slice();
+            syscall::file_op_desc(SYS_WRITE, file, buffer.as_ptr() as usize, buffer.len())
+        },
+        SYS_FSYNC => {
+            let file = job.file.as_ref().expect("aio fsync without file");
+            syscall::file_op_desc(SYS_FSYNC, file, 0, 0)
+        },
+        _ => Err(Error::new(EINVAL)),
+    });
+
+    // Closes the file if it was closed by the submitter in the meantime
+    if let Some(file) = job.file.take() {
+        let _ = file.close();
+    }
+
+    let completion = Completion {
+        submission,
+        result,
+        buffer: job.buffer.take().filter(|_| submission.opcode == SYS_READ),
+        file: opened,
+    };
+    match job.queue.upgrade() {
+        Some(queue) => queue.complete(completion),
+        None => completion.discard(),
+    }
+}
+
+extern fn aio_worker() {
+    loop {
+        IDLE.fetch_add(1, Ordering::SeqCst);
+        let job_opt = jobs().receive("aio_worker");
+        IDLE.fetch_sub(1, Ordering::SeqCst);
+
+        if let Some(job) = job_opt {
+            run(job);
+        }
+    }
+}
+
+pub struct AioScheme {
+    scheme_id: SchemeId,
+    next_id: AtomicUsize,
+    /// Queues, with the effective user id that opened them
+    handles: RwLock<BTreeMap<usize, (u32, Arc<AioQueue>)>>,
+    /// Number of queues opened by each user
+    queues: Mutex<BTreeMap<u32, usize>>,
+}
+
+impl AioScheme {
+    pub fn new(scheme_id: SchemeId) -> AioScheme {
+        AioScheme {
+            scheme_id,
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new()),
+            queues: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn queue(&self, id: usize) -> Result<Arc<AioQueue>> {
+        let handles = self.handles.read();
+        handles.get(&id).map(|(_owner, queue)| Arc::clone(queue)).ok_or(Error::new(EBADF))
+    }
+
+    /// Check a submission and copy what it needs from the submitter
+    fn prepare(queue: &Arc<AioQueue>, submission: AioSubmission) -> Result<Job> {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        if submission.len > AIO_MAX_LEN {
+            return Err(Error::new(EINVAL));
+        }
+
+        let fd = FileHandle::from(submission.fd);
+        let (file, buffer) = match submission.opcode {
+            SYS_OPEN | SYS_WRITE => {
+                let data = syscall::validate_slice(submission.buf as *const u8, submission.len)?;
+                let mut buffer = Buffer::new(context.euid, data.len())?;
+                buffer.as_mut_slice().copy_from_slice(data);
+                let file = if submission.opcode == SYS_WRITE {
+                    Some(context.get_file(fd).ok_or(Error::new(EBADF))?)
+                } else {
+                    None
+                };
+                (file, Some(buffer))
+            },
+            SYS_READ => {
+                // Checked again when the data is copied out
+                syscall::validate_slice_mut(submission.buf as *mut u8, submission.len)?;
+                (Some(context.get_file(fd).ok_or(Error::new(EBADF))?), Some(Buffer::new(context.euid, submission.len)?))
+            },
+            // The descriptor is freed at once, and the file is closed by the request
+            SYS_CLOSE => (Some(context.remove_file(fd).ok_or(Error::new(EBADF))?), None),
+            SYS_FSYNC => (Some(context.get_file(fd).ok_or(Error::new(EBADF))?), None),
+            _ => return Err(Error::new(EINVAL)),
+        };
+
+        Ok(Job {
+            queue: Arc::downgrade(queue),
+            submission,
+            identity: Identity {
+                euid: context.euid,
+                egid: context.egid,
+                ens: context.ens,
+                umask: context.umask,
+                cwd: Arc::clone(&context.cwd),
+            },
+            file,
+            buffer,
+        })
+    }
+}
+
+impl Scheme for AioScheme {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if ! path.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        {
+            let mut queues = self.queues.lock();
+            let count = queues.entry(uid).or_insert(0);
+            if *count >= AIO_MAX_QUEUES {
+                return Err(Error::new(EMFILE));
+            }
+            *count += 1;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, (uid, Arc::new(AioQueue {
+            scheme_id: self.scheme_id,
+            id,
+            flags,
+            pending: AtomicUsize::new(0),
+            completions: WaitQueue::new(),
+        })));
+
+        Ok(id)
+    }
+
+    /// Submit requests. Stops at the first one that would go over `AIO_MAX_PENDING`, and returns
+    /// `EAGAIN` if that is the first one. A request that can not be submitted, for example with
+    /// `EAGAIN` when `AIO_MAX_BUFFERED` is reached, gets its completion right away
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        let queue = self.queue(id)?;
+
+        let submissions = unsafe { slice::from_raw_parts(
+            buf.as_ptr() as *const AioSubmission,
+            buf.len() / mem::size_of::<AioSubmission>()
+        ) };
+
+        let mut i = 0;
+        while i < submissions.len() {
+            if queue.pending.fetch_add(1, Ordering::SeqCst) >= AIO_MAX_PENDING {
+                queue.pending.fetch_sub(1, Ordering::SeqCst);
+                break;
+            }
+
+            let submission = submissions[i];
+            match AioScheme::prepare(&queue, submission).and_then(queue_job) {
+                Ok(()) => (),
+                Err(err) => queue.complete(Completion {
+                    submission,
+                    result: Err(err),
+                    buffer: None,
+                    file: None,
+                }),
+            }
+            i += 1;
+        }
+
+        if i == 0 && ! submissions.is_empty() {
+            return Err(Error::new(EAGAIN));
+        }
+
+        Ok(i * mem::size_of::<AioSubmission>())
+    }
+
+    /// Read completions, waiting for one unless the queue was opened with `O_NONBLOCK`
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let queue = self.queue(id)?;
+
+        let completion_buf = unsafe { slice::from_raw_parts_mut(
+            buf.as_mut_ptr() as *mut AioCompletion,
+            buf.len() / mem::size_of::<AioCompletion>()
+        ) };
+        if completion_buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut completions = VecDeque::new();
+        if queue.flags & O_NONBLOCK != O_NONBLOCK {
+            completions.push_back(queue.completions.receive("AioScheme::read").ok_or(Error::new(EINTR))?);
+        }
+        {
+            let mut inner = queue.completions.inner.lock();
+            while completions.len() < completion_buf.len() {
+                match inner.pop_front() {
+                    Some(completion) => completions.push_back(completion),
+                    None => break,
+                }
+            }
+        }
+
+        if completions.is_empty() {
+            return Err(Error::new(EAGAIN));
+        }
+
+        let count = completions.len();
+        for (slot, completion) in completion_buf.iter_mut().zip(completions.into_iter()) {
+            *slot = completion.deliver();
+        }
+        queue.pending.fetch_sub(count, Ordering::SeqCst);
+
+        Ok(count * mem::size_of::<AioCompletion>())
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let queue = self.queue(id)?;
+        if queue.completions.is_empty() {
+            Ok(EventFlags::empty())
+        } else {
+            Ok(EVENT_READ)
+        }
+    }
+
+    fn fpath(&self, _id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        let scheme_path = b"aio:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        let (owner, _queue) = self.handles.write().remove(&id).ok_or(Error::new(EBADF))?;
+
+        let mut queues = self.queues.lock();
+        if let Some(count) = queues.get_mut(&owner) {
+            *count -= 1;
+            if *count == 0 {
+                queues.remove(&owner);
+            }
+        }
+
+        Ok(0)
+    }
+}
@@ -1,11 +1,16 @@
+use alloc::sync::Arc;
+
 use crate::context;
-use crate::context::memory::{entry_flags, Grant};
+use crate::context::{memory, swap};
 use crate::memory::{free_frames, used_frames, PAGE_SIZE};
-use crate::paging::{ActivePageTable, VirtualAddress};
 use crate::syscall::data::{Map, OldMap, StatVfs};
 use crate::syscall::error::*;
-use crate::syscall::flag::MapFlags;
+use crate::syscall::flag::{MapFlags, O_RDWR};
 use crate::syscall::scheme::Scheme;
+use crate::syscall;
+
+/// Handle of `memory:swap`, writing a path to it enables swapping to that file
+const SWAP_HANDLE: usize = 1;
 
 pub struct MemoryScheme;
 
@@ -15,8 +20,38 @@ impl MemoryScheme {
     }
 }
 impl Scheme for MemoryScheme {
-    fn open(&self, _path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
-        Ok(0)
+    fn open(&self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if path == b"swap" {
+            if uid == 0 {
+                Ok(SWAP_HANDLE)
+            } else {
+                Err(Error::new(EACCES))
+            }
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        if id != SWAP_HANDLE {
+            return Err(Error::new(EBADF));
+        }
+
+        // Open the swap file as the caller, then take it out of their file table
+        let fd = syscall::fs::open(buf, O_RDWR)?;
+        let file = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let context = context_lock.read();
+            context.remove_file(fd).ok_or(Error::new(EBADF))?
+        };
+
+        if let Err(err) = swap::swap_on(Arc::clone(&file.description)) {
+            let _ = file.close();
+            return Err(err);
+        }
+
+        Ok(buf.len())
     }
 
     fn fstatvfs(&self, _file: usize, stat: &mut StatVfs) -> Result<usize> {
@@ -32,36 +67,7 @@ impl Scheme for MemoryScheme {
     }
 
     fn fmap(&self, _id: usize, map: &Map) -> Result<usize> {
-        //TODO: Abstract with other grant creation
-        if map.size == 0 {
-            Ok(0)
-        } else {
-            let contexts = context::contexts();
-            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
-            let context = context_lock.read();
-
-            let mut grants = context.grants.lock();
-
-            let region = grants.find_free_at(VirtualAddress::new(map.address), map.size, map.flags)?.round();
-
-            {
-                // Make sure it's *absolutely* not mapped already
-                // TODO: Keep track of all allocated memory so this isn't necessary
-
-                let active_table = unsafe { ActivePageTable::new() };
-
-                for page in region.pages() {
-                    if active_table.translate_page(page).is_some() {
-                        println!("page at {:#x} was already mapped", page.start_address().get());
-                        return Err(Error::new(EEXIST))
-                    }
-                }
-            }
-
-            grants.insert(Grant::map(region.start_address(), region.size(), entry_flags(map.flags)));
-
-            Ok(region.start_address().get())
-        }
+        memory::map_anonymous(map.address, map.size, map.flags)
     }
     fn fmap_old(&self, id: usize, map: &OldMap) -> Result<usize> {
         if map.flags.contains(MapFlags::MAP_FIXED) {
@@ -80,9 +86,9 @@ impl Scheme for MemoryScheme {
         Ok(0)
     }
 
-    fn fpath(&self, _id: usize, buf: &mut [u8]) -> Result<usize> {
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
         let mut i = 0;
-        let scheme_path = b"memory:";
+        let scheme_path: &[u8] = if id == SWAP_HANDLE { b"memory:swap" } else { b"memory:" };
         while i < buf.len() && i < scheme_path.len() {
             buf[i] = scheme_path[i];
             i += 1;
@@ -19,6 +19,7 @@ use crate::syscall::scheme::Scheme;
 #[cfg(feature = "acpi")]
 use self::acpi::AcpiScheme;
 
+use self::aio::AioScheme;
 use self::debug::DebugScheme;
 use self::event::EventScheme;
 use self::initfs::InitFsScheme;
@@ -36,6 +37,9 @@ use self::time::TimeScheme;
 #[cfg(feature = "acpi")]
 pub mod acpi;
 
+/// `aio:` - allows submitting file operations on any scheme and reading their completions
+pub mod aio;
+
 /// `debug:` - provides access to serial console
 pub mod debug;
 
@@ -67,6 +71,9 @@ pub mod proc;
 /// `:` - allows the creation of userspace schemes, tightly dependent on `user`
 pub mod root;
 
+/// A ring of requests shared with userspace schemes, used by `user`
+pub mod ring;
+
 /// `serio:` - provides access to ps/2 devices
 pub mod serio;
 
@@ -129,11 +136,8 @@ impl SchemeList {
     /// Initialize the null namespace
     fn new_null(&mut self) {
         let ns = SchemeNamespace(0);
+        // No schemes are in the null namespace. Anonymous memory is mapped with fmap on no file
         self.names.insert(ns, BTreeMap::new());
-
-        //TODO: Only memory: is in the null namespace right now. It should be removed when
-        //anonymous mmap's are implemented
-        self.insert(ns, Box::new(*b"memory"), |_| Arc::new(MemoryScheme::new())).unwrap();
     }
 
     /// Initialize a new namespace
@@ -143,6 +147,7 @@ impl SchemeList {
         self.names.insert(ns, BTreeMap::new());
 
         self.insert(ns, Box::new(*b""), |scheme_id| Arc::new(RootScheme::new(ns, scheme_id))).unwrap();
+        self.insert(ns, Box::new(*b"aio"), |scheme_id| Arc::new(AioScheme::new(scheme_id))).unwrap();
         self.insert(ns, Box::new(*b"event"), |_| Arc::new(EventScheme)).unwrap();
         self.insert(ns, Box::new(*b"itimer"), |_| Arc::new(ITimerScheme::new())).unwrap();
         self.insert(ns, Box::new(*b"memory"), |_| Arc::new(MemoryScheme::new())).unwrap();
@@ -1,8 +1,10 @@
 use crate::{
-    arch::paging::VirtualAddress,
-    context::{self, Context, ContextId, Status},
+    arch::paging::{entry::EntryFlags, VirtualAddress, PAGE_SIZE},
+    context::{self, memory::MappingKind, Context, ContextId, Status, WaitReason},
+    ipi::{ipi_cpu, IpiKind},
     ptrace,
     scheme::{AtomicSchemeId, SchemeId},
+    time,
     syscall::{
         data::{FloatRegisters, IntRegisters, PtraceEvent, Stat},
         error::*,
@@ -38,6 +40,106 @@ where
     }
     callback(&context)
 }
+
+/// Format the CPU accounting of a context: time in user and kernel mode, context switches,
+/// and time spent blocked by reason. Times are in nanoseconds
+fn times(context: &Context) -> Box<[u8]> {
+    let tick_ns = crate::device::pit::RATE;
+
+    let mut string = format!("utime {}\nstime {}\nnvcsw {}\nnivcsw {}\n",
+                             context.user_ticks * tick_ns,
+                             context.kernel_ticks * tick_ns,
+                             context.nvcsw,
+                             context.nivcsw);
+
+    let mut wait_times = context.wait_times;
+    if let Some(blocked_since) = context.blocked_since {
+        let waited = time::monotonic_ns().saturating_sub(blocked_since);
+        wait_times[context.wait_reason as usize] += waited;
+    }
+    for reason in WaitReason::ALL.iter() {
+        let waited = wait_times[*reason as usize];
+        if waited > 0 {
+            string.push_str(&format!("wait {} {}\n", reason.name(), waited));
+        }
+    }
+
+    string.into_bytes().into_boxed_slice()
+}
+
+/// The CPU accounting of a context in binary form, read from `proc:<pid>/rusage` like
+/// `getrusage`. Times are in nanoseconds
+//TODO: Move to `syscall::data` next to `TimeSpec`, so that userspace shares this definition
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Rusage {
+    /// Time in user mode
+    pub utime: u64,
+    /// Time in kernel mode
+    pub stime: u64,
+    /// Number of voluntary context switches
+    pub nvcsw: u64,
+    /// Number of involuntary context switches
+    pub nivcsw: u64,
+    /// Time spent blocked or stopped
+    pub wait: u64,
+}
+
+fn rusage(context: &Context) -> Box<[u8]> {
+    let tick_ns = crate::device::pit::RATE;
+
+    let mut wait = context.wait_times.iter().sum::<u64>();
+    if let Some(blocked_since) = context.blocked_since {
+        wait += time::monotonic_ns().saturating_sub(blocked_since);
+    }
+
+    let rusage = Rusage {
+        utime: context.user_ticks * tick_ns,
+        stime: context.kernel_ticks * tick_ns,
+        nvcsw: context.nvcsw,
+        nivcsw: context.nivcsw,
+        wait,
+    };
+
+    unsafe {
+        slice::from_raw_parts(&rusage as *const Rusage as *const u8, mem::size_of::<Rusage>())
+    }.to_vec().into_boxed_slice()
+}
+
+/// Format the memory map of a context: the address range, permissions, and resident, shared,
+/// and swapped out size of each region. Sizes are in KB
+fn maps(context: &Context) -> Box<[u8]> {
+    let kb = |pages: usize| pages * PAGE_SIZE / 1024;
+
+    let mut string = format!("{:<34}{:<6}{:>10}{:>10}{:>10} TYPE\n", "RANGE", "PERM", "RSS", "SHARED", "SWAP");
+
+    let (mut resident, mut shared, mut swapped) = (0, 0, 0);
+    for mapping in context.mappings() {
+        let perm = format!("r{}{}{}",
+                           if mapping.flags.intersects(EntryFlags::WRITABLE | EntryFlags::COPY_ON_WRITE) { 'w' } else { '-' },
+                           if mapping.flags.contains(EntryFlags::NO_EXECUTE) { '-' } else { 'x' },
+                           match mapping.kind {
+                               MappingKind::Shared | MappingKind::Fmap | MappingKind::Physmap => 's',
+                               _ => 'p',
+                           });
+        string.push_str(&format!("{:016x}-{:016x} {:<6}{:>10}{:>10}{:>10} {}\n",
+                                 mapping.region.start_address().get(),
+                                 mapping.region.end_address().get(),
+                                 perm,
+                                 kb(mapping.resident),
+                                 kb(mapping.shared),
+                                 kb(mapping.swapped),
+                                 mapping.kind.name()));
+
+        resident += mapping.resident;
+        shared += mapping.shared;
+        swapped += mapping.swapped;
+    }
+
+    string.push_str(&format!("{:<34}{:<6}{:>10}{:>10}{:>10}\n", "total", "", kb(resident), kb(shared), kb(swapped)));
+
+    string.into_bytes().into_boxed_slice()
+}
 fn with_context_mut<F, T>(pid: ContextId, callback: F) -> Result<T>
 where
     F: FnOnce(&mut Context) -> Result<T>,
@@ -77,6 +179,7 @@ where
         let ret = callback(context);
 
         context.ptrace_stop = was_stopped;
+        context::run_queue::enqueue(context);
 
         ret
     })
@@ -92,6 +195,9 @@ enum Operation {
     Memory,
     Regs(RegsKind),
     Trace,
+    Priority,
+    Affinity,
+    Sched,
     Static(&'static str),
 }
 impl Operation {
@@ -100,6 +206,9 @@ impl Operation {
             Self::Memory => true,
             Self::Regs(_) => true,
             Self::Trace => true,
+            Self::Priority => false,
+            Self::Affinity => false,
+            Self::Sched => false,
             Self::Static(_) => false,
         }
     }
@@ -181,6 +290,7 @@ impl Handle {
             if let Some(context) = contexts.get(pid) {
                 let mut context = context.write();
                 context.ptrace_stop = false;
+                context::run_queue::enqueue(&mut context);
             }
         }
         Some(())
@@ -219,7 +329,13 @@ impl Scheme for ProcScheme {
             Some("regs/float") => Operation::Regs(RegsKind::Float),
             Some("regs/int") => Operation::Regs(RegsKind::Int),
             Some("trace") => Operation::Trace,
+            Some("priority") => Operation::Priority,
+            Some("affinity") => Operation::Affinity,
+            Some("sched") => Operation::Sched,
             Some("exe") => Operation::Static("exe"),
+            Some("times") => Operation::Static("times"),
+            Some("rusage") => Operation::Static("rusage"),
+            Some("maps") => Operation::Static("maps"),
             _ => return Err(Error::new(EINVAL))
         };
 
@@ -231,17 +347,20 @@ impl Scheme for ProcScheme {
         {
             let target = target.read();
 
+            if let Status::Exited(_) = target.status {
+                return Err(Error::new(ESRCH));
+            }
+
             data = match operation {
                 Operation::Memory => OperationData::Memory(MemData::default()),
                 Operation::Trace => OperationData::Trace(TraceData::default()),
+                Operation::Static("times") => OperationData::Static(StaticData::new(times(&target))),
+                Operation::Static("rusage") => OperationData::Static(StaticData::new(rusage(&target))),
+                Operation::Static("maps") => OperationData::Static(StaticData::new(maps(&target))),
                 Operation::Static(_) => OperationData::Static(StaticData::new(target.name.lock().clone())),
                 _ => OperationData::Other,
             };
 
-            if let Status::Exited(_) = target.status {
-                return Err(Error::new(ESRCH));
-            }
-
             // Unless root, check security
             if operation.needs_child_process() && uid != 0 && gid != 0 {
                 let current = contexts.current().ok_or(Error::new(ESRCH))?;
@@ -358,7 +477,7 @@ impl Scheme for ProcScheme {
                 let context = contexts.get(info.pid).ok_or(Error::new(ESRCH))?;
                 let mut context = context.write();
 
-                ptrace::with_context_memory(&mut context, data.offset, buf.len(), |ptr| {
+                ptrace::with_context_memory(&mut context, data.offset, buf.len(), false, |ptr| {
                     buf.copy_from_slice(validate::validate_slice(ptr, buf.len())?);
                     Ok(())
                 })?;
@@ -445,6 +564,35 @@ impl Scheme for ProcScheme {
 
                 // Return read events
                 Ok(read * mem::size_of::<PtraceEvent>())
+            },
+            Operation::Priority => {
+                let nice = with_context(info.pid, |context| Ok(context.nice))?;
+
+                let bytes = (nice as isize).to_ne_bytes();
+                let len = cmp::min(buf.len(), bytes.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+
+                Ok(len)
+            },
+            Operation::Affinity => {
+                let affinity = with_context(info.pid, |context| Ok(context.affinity))?;
+
+                let bytes = affinity.to_ne_bytes();
+                let len = cmp::min(buf.len(), bytes.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+
+                Ok(len)
+            },
+            Operation::Sched => {
+                let (policy, priority) = syscall::sched_getscheduler(info.pid)?;
+
+                let mut bytes = [0; 2 * mem::size_of::<usize>()];
+                bytes[..mem::size_of::<usize>()].copy_from_slice(&policy.to_ne_bytes());
+                bytes[mem::size_of::<usize>()..].copy_from_slice(&priority.to_ne_bytes());
+                let len = cmp::min(buf.len(), bytes.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+
+                Ok(len)
             }
         }
     }
@@ -470,7 +618,7 @@ impl Scheme for ProcScheme {
                 let context = contexts.get(info.pid).ok_or(Error::new(ESRCH))?;
                 let mut context = context.write();
 
-                ptrace::with_context_memory(&mut context, data.offset, buf.len(), |ptr| {
+                ptrace::with_context_memory(&mut context, data.offset, buf.len(), true, |ptr| {
                     validate::validate_slice_mut(ptr, buf.len())?.copy_from_slice(buf);
                     Ok(())
                 })?;
@@ -556,6 +704,7 @@ impl Scheme for ProcScheme {
                 // disable the ptrace_stop flag, which is used in some cases
                 with_context_mut(info.pid, |context| {
                     context.ptrace_stop = false;
+                    context::run_queue::enqueue(context);
                     Ok(())
                 })?;
 
@@ -567,6 +716,114 @@ impl Scheme for ProcScheme {
 
                 Ok(mem::size_of::<u64>())
             },
+            Operation::Priority => {
+                if buf.len() < mem::size_of::<isize>() {
+                    return Err(Error::new(EINVAL));
+                }
+
+                let mut bytes = [0; mem::size_of::<isize>()];
+                let len = bytes.len();
+                bytes.copy_from_slice(&buf[0..len]);
+                let nice = isize::from_ne_bytes(bytes);
+                if nice < context::NICE_MIN as isize || nice > context::NICE_MAX as isize {
+                    return Err(Error::new(EINVAL));
+                }
+                let nice = nice as i8;
+
+                let euid = {
+                    let contexts = context::contexts();
+                    let current = contexts.current().ok_or(Error::new(ESRCH))?;
+                    let current = current.read();
+                    current.euid
+                };
+
+                with_context_mut(info.pid, |context| {
+                    // Unless root, only allow lowering the priority of our own processes
+                    if euid != 0 {
+                        if euid != context.euid {
+                            return Err(Error::new(EPERM));
+                        }
+                        if nice < context.nice {
+                            return Err(Error::new(EACCES));
+                        }
+                    }
+
+                    context.nice = nice;
+
+                    Ok(mem::size_of::<isize>())
+                })
+            },
+            Operation::Affinity => {
+                if buf.len() < mem::size_of::<u64>() {
+                    return Err(Error::new(EINVAL));
+                }
+
+                let mut bytes = [0; mem::size_of::<u64>()];
+                let len = bytes.len();
+                bytes.copy_from_slice(&buf[0..len]);
+                let affinity = u64::from_ne_bytes(bytes);
+
+                // At least one existing CPU must be allowed
+                let cpu_count = crate::cpu_count();
+                let cpu_mask = if cpu_count >= 64 { !0 } else { (1 << cpu_count) - 1 };
+                if affinity & cpu_mask == 0 {
+                    return Err(Error::new(EINVAL));
+                }
+
+                let euid = {
+                    let contexts = context::contexts();
+                    let current = contexts.current().ok_or(Error::new(ESRCH))?;
+                    let current = current.read();
+                    current.euid
+                };
+
+                let old_cpu = with_context_mut(info.pid, |context| {
+                    // Unless root, only allow changing our own processes
+                    if euid != 0 && euid != context.euid {
+                        return Err(Error::new(EPERM));
+                    }
+
+                    context.affinity = affinity;
+
+                    // Move the context now if its CPU is no longer allowed. If it is queued, the
+                    // old CPU will queue it on the new one when it finds it
+                    if let Some(cpu_id) = context.cpu_id {
+                        if ! context.allowed_on(cpu_id) {
+                            context.cpu_id = Some(context::run_queue::select_cpu(context, cpu_id));
+                            if context.running {
+                                return Ok(Some(cpu_id));
+                            }
+                        }
+                    }
+
+                    Ok(None)
+                })?;
+
+                // Preempt the context if it is still running on a CPU that is no longer allowed
+                match old_cpu {
+                    Some(cpu_id) if cpu_id != crate::cpu_id() => ipi_cpu(IpiKind::Switch, cpu_id),
+                    Some(_) => context::preempt(),
+                    None => (),
+                }
+
+                Ok(mem::size_of::<u64>())
+            },
+            Operation::Sched => {
+                if buf.len() < 2 * mem::size_of::<usize>() {
+                    return Ok(0);
+                }
+
+                let mut bytes = [0; mem::size_of::<usize>()];
+                let len = bytes.len();
+                bytes.copy_from_slice(&buf[0..len]);
+                let policy = usize::from_ne_bytes(bytes);
+                bytes.copy_from_slice(&buf[len..2 * len]);
+                let priority = usize::from_ne_bytes(bytes);
+
+                syscall::sched_setscheduler(info.pid, policy, priority)?;
+
+                Ok(2 * mem::size_of::<usize>())
+            },
         }
     }
 
@@ -602,6 +859,9 @@ impl Scheme for ProcScheme {
             Operation::Regs(RegsKind::Float) => "regs/float",
             Operation::Regs(RegsKind::Int) => "regs/int",
             Operation::Trace => "trace",
+            Operation::Priority => "priority",
+            Operation::Affinity => "affinity",
+            Operation::Sched => "sched",
             Operation::Static(path) => path,
         });
 
@@ -647,6 +907,7 @@ impl Scheme for ProcScheme {
             if let Some(context) = contexts.get(handle.info.pid) {
                 let mut context = context.write();
                 context.ptrace_stop = false;
+                context::run_queue::enqueue(&mut context);
             }
         }
         Ok(0)
@@ -0,0 +1,165 @@
+//! A ring of requests shared between the kernel and a userspace scheme
+//!
+//! A scheme daemon gets the ring by calling `fmap` on its `:` handle. The ring starts with a
+//! `RingHeader`, followed by `entries` submission packets written by the kernel, and then by
+//! `entries` completion packets written by the daemon. The head and tail counters only ever
+//! increase, and the packet a counter refers to is the counter modulo `entries`.
+//!
+//! The kernel triggers `EVENT_READ` on the handle after submitting requests. The daemon handles
+//! every submission from `sq_head` to `sq_tail`, writes a completion packet for each, with the
+//! same `id` and the result in `a`, and calls `fsync` on the handle to have the completions
+//! reaped. Reading the handle reaps them too. Requests that do not fit in the ring are queued
+//! to be read from the handle as usual.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::vec::Vec;
+use core::{cmp, mem, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::memory;
+use crate::paging::{ActivePageTable, Page, VirtualAddress, PAGE_SIZE};
+use crate::syscall::data::Packet;
+use crate::syscall::error::*;
+
+/// Maximum number of packets in each queue of a ring
+pub const RING_MAX_ENTRIES: usize = 4096;
+
+#[repr(C)]
+pub struct RingHeader {
+    /// Number of packets in each queue, a power of two
+    pub entries: AtomicUsize,
+    /// Next submission to be handled, advanced by the daemon
+    pub sq_head: AtomicUsize,
+    /// Next submission to be written, advanced by the kernel
+    pub sq_tail: AtomicUsize,
+    /// Next completion to be reaped, advanced by the kernel
+    pub cq_head: AtomicUsize,
+    /// Next completion to be written, advanced by the daemon
+    pub cq_tail: AtomicUsize,
+}
+
+pub struct Ring {
+    address: usize,
+    size: usize,
+    entries: usize,
+    /// The kernel's counters, which the daemon can not change
+    sq_tail: usize,
+    cq_head: usize,
+}
+
+impl Ring {
+    /// Allocate a zeroed ring with as many entries as fit in `size` bytes
+    pub fn new(size: usize) -> Result<Ring> {
+        let fit = size.saturating_sub(mem::size_of::<RingHeader>()) / (2 * mem::size_of::<Packet>());
+        if fit == 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        // A power of two, so the counters can wrap around
+        let mut entries = 1;
+        while entries * 2 <= cmp::min(fit, RING_MAX_ENTRIES) {
+            entries *= 2;
+        }
+
+        let used = mem::size_of::<RingHeader>() + 2 * entries * mem::size_of::<Packet>();
+        let size = (used + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let layout = Layout::from_size_align(size, PAGE_SIZE).map_err(|_| Error::new(EINVAL))?;
+        let address = unsafe { alloc_zeroed(layout) } as usize;
+        if address == 0 {
+            return Err(Error::new(ENOMEM));
+        }
+
+        let ring = Ring {
+            address,
+            size,
+            entries,
+            sq_tail: 0,
+            cq_head: 0,
+        };
+        ring.header().entries.store(entries, Ordering::SeqCst);
+
+        Ok(ring)
+    }
+
+    /// Kernel address of the ring
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// Size of the ring, in whole pages
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.address as *const RingHeader) }
+    }
+
+    fn packet(&self, queue: usize, counter: usize) -> *mut Packet {
+        let packets = (self.address + mem::size_of::<RingHeader>()) as *mut Packet;
+        unsafe { packets.add(queue * self.entries + counter % self.entries) }
+    }
+
+    /// Submit a request, returning false if the ring is full
+    pub fn submit(&mut self, packet: Packet) -> bool {
+        let header = self.header();
+        let head = header.sq_head.load(Ordering::Acquire);
+        // A head the daemon moved past the tail also counts as full
+        if self.sq_tail.wrapping_sub(head) >= self.entries {
+            return false;
+        }
+
+        unsafe { ptr::write_volatile(self.packet(0, self.sq_tail), packet); }
+        self.sq_tail = self.sq_tail.wrapping_add(1);
+        self.header().sq_tail.store(self.sq_tail, Ordering::Release);
+
+        true
+    }
+
+    /// Take the completions written by the daemon
+    pub fn reap(&mut self) -> Vec<Packet> {
+        let tail = self.header().cq_tail.load(Ordering::Acquire);
+        let count = cmp::min(tail.wrapping_sub(self.cq_head), self.entries);
+
+        let mut packets = Vec::with_capacity(count);
+        for _ in 0..count {
+            packets.push(unsafe { ptr::read_volatile(self.packet(1, self.cq_head)) });
+            self.cq_head = self.cq_head.wrapping_add(1);
+        }
+        self.header().cq_head.store(self.cq_head, Ordering::Release);
+
+        packets
+    }
+
+    /// Drop the submissions the daemon has not handled and the completions it has not had
+    /// reaped, for when it is forcibly unmounted
+    pub fn reset(&mut self) {
+        let header = self.header();
+        header.sq_head.store(self.sq_tail, Ordering::Release);
+        self.cq_head = header.cq_tail.load(Ordering::Acquire);
+        header.cq_head.store(self.cq_head, Ordering::Release);
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // If the daemon still has the ring mapped, its pages can not be given back to the heap
+        {
+            let active_table = unsafe { ActivePageTable::new() };
+            let start_page = Page::containing_address(VirtualAddress::new(self.address));
+            let end_page = Page::containing_address(VirtualAddress::new(self.address + self.size - 1));
+            for page in Page::range_inclusive(start_page, end_page) {
+                if let Some(frame) = active_table.translate_page(page) {
+                    if memory::frame_refcount(&frame) > 1 {
+                        println!("scheme ring still mapped, leaking {} bytes", self.size);
+                        return;
+                    }
+                }
+            }
+        }
+
+        unsafe {
+            dealloc(self.address as *mut u8, Layout::from_size_align_unchecked(self.size, PAGE_SIZE));
+        }
+    }
+}
@@ -7,12 +7,23 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Mutex, RwLock};
 
 use crate::context;
-use crate::syscall::data::Stat;
+use crate::syscall::data::{Map, Stat};
 use crate::syscall::error::*;
 use crate::syscall::flag::{EventFlags, O_CREAT, MODE_FILE, MODE_DIR};
 use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
 use crate::scheme::{self, SchemeNamespace, SchemeId};
-use crate::scheme::user::{UserInner, UserScheme};
+use crate::scheme::user::{self, UserInner, UserScheme};
+
+/// `fcntl` on a root handle opened by the name of a userspace scheme, by root: give up on
+/// requests to the scheme that take longer than `arg` milliseconds, or wait forever if `arg` is
+/// zero. This replaces the timeout the scheme set with `SKMSG_TIMEOUT`, so that root can bound
+/// the requests to a scheme that never set one
+pub const F_SETTIMEOUT: usize = 0x1000;
+
+/// `fcntl` on a root handle opened by the name of a userspace scheme, by root: unmount the
+/// scheme even though it stopped responding. Every pending request fails with `ENODEV`, unlike
+/// `unlink`, which only stops new requests and lets the scheme answer the pending ones
+pub const F_FORCE_UNMOUNT: usize = 0x1001;
 
 struct FolderInner {
     data: Box<[u8]>,
@@ -66,6 +77,19 @@ impl RootScheme {
     }
 }
 
+impl RootScheme {
+    /// Find a userspace scheme created through this scheme by its name
+    fn user_inner(&self, name: &str) -> Result<Arc<UserInner>> {
+        let handles = self.handles.read();
+        handles.values().find_map(|handle| {
+            match handle {
+                Handle::Scheme(inner) if name.as_bytes() == inner.name.as_ref() => Some(inner.clone()),
+                _ => None,
+            }
+        }).ok_or(Error::new(ENOENT))
+    }
+}
+
 impl Scheme for RootScheme {
     fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
         let path_utf8 = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
@@ -77,6 +101,8 @@ impl Scheme for RootScheme {
                 let context = {
                     let contexts = context::contexts();
                     let context = contexts.current().ok_or(Error::new(ESRCH))?;
+                    // Requests to the scheme must not wait on its own pages being swapped in
+                    context.write().resident = true;
                     Arc::downgrade(&context)
                 };
 
@@ -92,6 +118,7 @@ impl Scheme for RootScheme {
                     })?;
                     inner
                 };
+                user::register(&inner);
 
                 self.handles.write().insert(id, Handle::Scheme(inner));
 
@@ -140,20 +167,7 @@ impl Scheme for RootScheme {
         let path_trimmed = path_utf8.trim_matches('/');
 
         if uid == 0 {
-            let inner = {
-                let handles = self.handles.read();
-                handles.iter().find_map(|(_id, handle)| {
-                    match handle {
-                        Handle::Scheme(inner) => {
-                            if path_trimmed.as_bytes() == inner.name.as_ref() {
-                                return Some(inner.clone());
-                            }
-                        },
-                        _ => (),
-                    }
-                    None
-                }).ok_or(Error::new(ENOENT))?
-            };
+            let inner = self.user_inner(path_trimmed)?;
 
             inner.unmount()
         } else {
@@ -221,6 +235,39 @@ impl Scheme for RootScheme {
         }
     }
 
+    fn fcntl(&self, file: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let handle = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
+        };
+
+        let name = match handle {
+            Handle::File(name) => name,
+            _ => return Err(Error::new(EBADF)),
+        };
+
+        let euid = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let context = context_lock.read();
+            context.euid
+        };
+        if euid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let inner = self.user_inner(str::from_utf8(&name).or(Err(Error::new(ENOENT)))?)?;
+        match cmd {
+            F_SETTIMEOUT => {
+                inner.set_timeout(arg);
+                Ok(0)
+            },
+            F_FORCE_UNMOUNT => inner.force_unmount(),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
     fn fevent(&self, file: usize, flags: EventFlags) -> Result<EventFlags> {
         let handle = {
             let handles = self.handles.read();
@@ -241,6 +288,26 @@ impl Scheme for RootScheme {
         }
     }
 
+    fn fmap(&self, file: usize, map: &Map) -> Result<usize> {
+        let handle = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
+        };
+
+        match handle {
+            Handle::Scheme(inner) => {
+                inner.fmap_ring(map)
+            },
+            Handle::File(_) => {
+                Err(Error::new(EBADF))
+            },
+            Handle::Folder(_) => {
+                Err(Error::new(EBADF))
+            }
+        }
+    }
+
     fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
         let handle = {
             let handles = self.handles.read();
@@ -3,10 +3,26 @@ use alloc::vec::Vec;
 use core::str;
 
 use crate::context;
+use crate::context::memory::MappingKind;
+use crate::paging::PAGE_SIZE;
 use crate::syscall::error::Result;
 
+fn format_ticks(ticks: u64) -> String {
+    if ticks >= 1000 * 1000 * 1000 * 1000 {
+        format!("{} T", ticks / 1000 / 1000 / 1000 / 1000)
+    } else if ticks >= 1000 * 1000 * 1000 {
+        format!("{} G", ticks / 1000 / 1000 / 1000)
+    } else if ticks >= 1000 * 1000 {
+        format!("{} M", ticks / 1000 / 1000)
+    } else if ticks >= 1000 {
+        format!("{} K", ticks / 1000)
+    } else {
+        format!("{}", ticks)
+    }
+}
+
 pub fn resource() -> Result<Vec<u8>> {
-    let mut string = format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<8}{:<8}{}\n",
+    let mut string = format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<8}{:<8}{:<8}{:<8}{:<8}{:<8}{}\n",
                              "PID",
                              "PGID",
                              "PPID",
@@ -19,6 +35,10 @@ pub fn resource() -> Result<Vec<u8>> {
                              "STAT",
                              "CPU",
                              "TICKS",
+                             "UTIME",
+                             "STIME",
+                             "VCSW",
+                             "IVCSW",
                              "MEM",
                              "NAME");
     {
@@ -58,43 +78,25 @@ pub fn resource() -> Result<Vec<u8>> {
                 format!("?")
             };
 
-            let ticks = context.ticks;
-            let ticks_string = if ticks >= 1000 * 1000 * 1000 * 1000 {
-                format!("{} T", ticks / 1000 / 1000 / 1000 / 1000)
-            } else if ticks >= 1000 * 1000 * 1000 {
-                format!("{} G", ticks / 1000 / 1000 / 1000)
-            } else if ticks >= 1000 * 1000 {
-                format!("{} M", ticks / 1000 / 1000)
-            } else if ticks >= 1000 {
-                format!("{} K", ticks / 1000)
-            } else {
-                format!("{}", ticks)
-            };
+            let ticks_string = format_ticks(context.ticks);
+            let user_string = format_ticks(context.user_ticks);
+            let kernel_string = format_ticks(context.kernel_ticks);
 
             let mut memory = 0;
-            if let Some(ref kfx) = context.kstack {
+            if let Some(ref kfx) = context.kfx {
                 memory += kfx.len();
             }
             if let Some(ref kstack) = context.kstack {
                 memory += kstack.len();
             }
-            for shared_mem in context.image.iter() {
-                shared_mem.with(|mem| {
-                    memory += mem.size();
-                });
-            }
-            if let Some(ref stack) = context.stack {
-                stack.with(|stack| {
-                    memory += stack.size();
-                });
-            }
-            if let Some(ref sigstack) = context.sigstack {
-                memory += sigstack.size();
-            }
-            for grant in context.grants.lock().iter() {
-                if grant.is_owned() {
-                    memory += grant.size();
-                }
+            // Count the user pages that use frames, which excludes lazy and swapped out pages
+            match context.status {
+                context::Status::Exited(_) => (),
+                _ => for mapping in context.mappings() {
+                    if mapping.kind != MappingKind::Physmap {
+                        memory += mapping.resident * PAGE_SIZE;
+                    }
+                },
             }
 
             let memory_string = if memory >= 1024 * 1024 * 1024 {
@@ -110,7 +112,7 @@ pub fn resource() -> Result<Vec<u8>> {
             let name_bytes = context.name.lock();
             let name = str::from_utf8(&name_bytes).unwrap_or("");
 
-            string.push_str(&format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<8}{:<8}{}\n",
+            string.push_str(&format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<8}{:<8}{:<8}{:<8}{:<8}{:<8}{}\n",
                                context.id.into(),
                                context.pgid.into(),
                                context.ppid.into(),
@@ -123,6 +125,10 @@ pub fn resource() -> Result<Vec<u8>> {
                                stat_string,
                                cpu_string,
                                ticks_string,
+                               user_string,
+                               kernel_string,
+                               context.nvcsw,
+                               context.nivcsw,
                                memory_string,
                                name));
         }
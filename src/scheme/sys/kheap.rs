@@ -0,0 +1,33 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::allocator;
+use crate::syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    let size = allocator::size();
+    let used = allocator::used();
+    let free = size.saturating_sub(used);
+
+    let _ = writeln!(string, "size: {} KB", size / 1024);
+    let _ = writeln!(string, "max: {} KB", allocator::max_size() / 1024);
+    let _ = writeln!(string, "used: {} KB", used / 1024);
+    let _ = writeln!(string, "free: {} KB", free / 1024);
+
+    // The share of free memory that is not in the largest free block
+    match allocator::largest_free() {
+        Some(largest) => {
+            let _ = writeln!(string, "largest free: {} KB", largest / 1024);
+            let fragmentation = if free > 0 { 100 - largest.min(free) * 100 / free } else { 0 };
+            let _ = writeln!(string, "fragmentation: {}%", fragmentation);
+        },
+        None => {
+            let _ = writeln!(string, "fragmentation: unknown");
+        }
+    }
+
+    Ok(string.into_bytes())
+}
@@ -16,9 +16,14 @@ mod context;
 mod cpu;
 mod exe;
 mod iostat;
+mod kheap;
 mod log;
+mod numa;
+mod sched;
 mod scheme;
 mod scheme_num;
+mod scheme_pending;
+mod swap;
 mod syscall;
 mod uname;
 
@@ -47,9 +52,14 @@ impl SysScheme {
         files.insert(b"cpu", Box::new(cpu::resource));
         files.insert(b"exe", Box::new(exe::resource));
         files.insert(b"iostat", Box::new(iostat::resource));
+        files.insert(b"kheap", Box::new(kheap::resource));
         files.insert(b"log", Box::new(log::resource));
+        files.insert(b"numa", Box::new(numa::resource));
+        files.insert(b"sched", Box::new(sched::resource));
         files.insert(b"scheme", Box::new(scheme::resource));
         files.insert(b"scheme_num", Box::new(scheme_num::resource));
+        files.insert(b"scheme_pending", Box::new(scheme_pending::resource));
+        files.insert(b"swap", Box::new(swap::resource));
         files.insert(b"syscall", Box::new(syscall::resource));
         files.insert(b"uname", Box::new(uname::resource));
         files.insert(b"spurious_irq", Box::new(irq::spurious_irq_resource));
@@ -0,0 +1,22 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::memory;
+use crate::syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    let nodes = memory::numa_nodes();
+    let _ = write!(string, "{:<6}{:<8}{:<12}{:<12}DISTANCES\n", "NODE", "DOMAIN", "FREE", "USED");
+    for (i, (domain, free, used, distances)) in nodes.iter().enumerate() {
+        let _ = write!(string, "{:<6}{:<8}{:<12}{:<12}", i, domain, free, used);
+        for distance in distances.iter() {
+            let _ = write!(string, "{:<4}", distance);
+        }
+        let _ = write!(string, "\n");
+    }
+
+    Ok(string.into_bytes())
+}
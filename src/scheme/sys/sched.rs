@@ -0,0 +1,37 @@
+use alloc::vec::Vec;
+
+use crate::context::run_queue::run_queue;
+use crate::syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<6}{:<8}{:<6}{:<10}{:<10}{:<10}{:<8}{}\n",
+                             "CPU",
+                             "QUEUED",
+                             "RT",
+                             "SLEEPING",
+                             "MIGR_IN",
+                             "MIGR_OUT",
+                             "STEALS",
+                             "THROTTLED");
+
+    for cpu_id in 0..crate::cpu_count() {
+        let run_queue = run_queue(cpu_id);
+        let rt_string = if run_queue.rt_throttled {
+            format!("{}!", run_queue.realtime())
+        } else {
+            format!("{}", run_queue.realtime())
+        };
+
+        string.push_str(&format!("{:<6}{:<8}{:<6}{:<10}{:<10}{:<10}{:<8}{}\n",
+                                 cpu_id,
+                                 run_queue.len(),
+                                 rt_string,
+                                 run_queue.sleeping(),
+                                 run_queue.migrations_in,
+                                 run_queue.migrations_out,
+                                 run_queue.steals,
+                                 run_queue.rt_throttles));
+    }
+
+    Ok(string.into_bytes())
+}
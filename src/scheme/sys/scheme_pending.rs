@@ -0,0 +1,47 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::Ordering;
+
+use crate::context;
+use crate::scheme::{self, user};
+use crate::syscall::error::{Error, ESRCH, Result};
+use crate::time;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let scheme_ns = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.ens
+    };
+
+    let scheme_ids: Vec<_> = {
+        let schemes = scheme::schemes();
+        schemes.iter_name(scheme_ns).map(|(_name, &scheme_id)| scheme_id).collect()
+    };
+
+    let now = time::monotonic_ns();
+    let mut string = format!("{:<24}{:<10}{:<8}{:<8}{:<12}{}\n", "SCHEME", "ID", "PID", "UID", "SYSCALL", "AGE");
+    for inner in user::user_schemes() {
+        if ! scheme_ids.contains(&inner.scheme_id.load(Ordering::SeqCst)) {
+            continue;
+        }
+
+        let name = str::from_utf8(&inner.name).unwrap_or("?");
+        for pending in inner.pending() {
+            let age = now.saturating_sub(pending.since);
+            let _ = write!(string, "{:<24}{:<10}{:<8}{:<8}{:<12}{}.{:03} s\n",
+                name,
+                pending.packet.id,
+                pending.packet.pid,
+                pending.packet.uid,
+                format!("{:#x}", pending.packet.a),
+                age / 1_000_000_000,
+                age / 1_000_000 % 1000);
+        }
+    }
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,26 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::context::swap;
+use crate::memory::PAGE_SIZE;
+use crate::syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    match swap::stats() {
+        Some((slots, used, cached)) => {
+            let _ = write!(string, "{:<12}{:<12}{:<12}\n", "TOTAL", "USED", "CACHED");
+            let _ = write!(string, "{:<12}{:<12}{:<12}\n",
+                format!("{} KB", slots * PAGE_SIZE / 1024),
+                format!("{} KB", used * PAGE_SIZE / 1024),
+                format!("{} KB", cached * PAGE_SIZE / 1024));
+        },
+        None => {
+            let _ = write!(string, "none\n");
+        }
+    }
+
+    Ok(string.into_bytes())
+}
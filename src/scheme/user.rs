@@ -1,6 +1,7 @@
 use alloc::sync::{Arc, Weak};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::{mem, slice, usize};
 use core::convert::TryFrom;
@@ -12,13 +13,77 @@ use crate::context::memory::{entry_flags, round_down_pages, Grant, Region};
 use crate::event;
 use crate::paging::{PAGE_SIZE, InactivePageTable, Page, VirtualAddress};
 use crate::paging::temporary_page::TemporaryPage;
-use crate::scheme::{AtomicSchemeId, SchemeId};
+use crate::scheme::{AtomicSchemeId, FileHandle, SchemeId};
+use crate::scheme::ring::Ring;
 use crate::sync::{WaitQueue, WaitMap};
 use crate::syscall::data::{Map, OldMap, Packet, Stat, StatVfs, TimeSpec};
 use crate::syscall::error::*;
 use crate::syscall::flag::{EventFlags, EVENT_READ, O_NONBLOCK, MapFlags, PROT_READ, PROT_WRITE};
 use crate::syscall::number::*;
+use crate::syscall::SYS_SENDFD;
 use crate::syscall::scheme::Scheme;
+use crate::time;
+
+//TODO: Move these to `syscall::flag`, so that schemes share the definitions
+
+/// Sent to the scheme, with an `id` of zero, when the caller of request `b` was interrupted. The
+/// scheme should abandon the request and stop using its buffers. A response to it is ignored
+pub const KSMSG_CANCEL: usize = usize::MAX;
+
+/// Sent by the scheme, with an `id` of zero, to give up on requests that take longer than `b`
+/// milliseconds, failing them with `ETIMEDOUT`. A `b` of zero waits forever, which is the default.
+/// Root can set the timeout of any scheme too, see `root::F_SETTIMEOUT`
+pub const SKMSG_TIMEOUT: usize = usize::MAX - 1;
+
+/// Set in `b` of the response to a `SYS_DUP` request, by a scheme answering with one of its own
+/// file descriptors in `a` instead of a new handle. The descriptor is moved to the caller
+pub const SKMSG_FRETURNFD: usize = usize::MAX - 2;
+
+/// All userspace schemes, by scheme id, for `sys:scheme_pending` and file descriptor passing
+static USER_SCHEMES: Mutex<Vec<(SchemeId, Weak<UserInner>)>> = Mutex::new(Vec::new());
+
+/// Remember a new userspace scheme
+pub fn register(inner: &Arc<UserInner>) {
+    let mut schemes = USER_SCHEMES.lock();
+    schemes.retain(|(_scheme_id, weak)| weak.strong_count() > 0);
+    schemes.push((inner.scheme_id.load(Ordering::SeqCst), Arc::downgrade(inner)));
+}
+
+/// Get the userspace schemes that are still mounted
+pub fn user_schemes() -> Vec<Arc<UserInner>> {
+    USER_SCHEMES.lock().iter().filter_map(|(_scheme_id, weak)| weak.upgrade()).collect()
+}
+
+/// Get the userspace scheme with the given id, if it is one
+pub fn user_scheme(scheme_id: SchemeId) -> Option<Arc<UserInner>> {
+    // A scheme id may be reused once its scheme is gone, so skip the stale entries
+    USER_SCHEMES.lock().iter().find_map(|(id, weak)| {
+        if *id == scheme_id { weak.upgrade() } else { None }
+    })
+}
+
+/// Take a file descriptor out of the file table of the current context
+fn take_file(fd: usize) -> Result<FileDescriptor> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    context.remove_file(FileHandle::from(fd)).ok_or(Error::new(EBADF))
+}
+
+/// A request waiting for a response
+#[derive(Clone, Copy)]
+pub struct Pending {
+    pub packet: Packet,
+    /// Monotonic time the request was made, in nanoseconds
+    pub since: u64,
+}
+
+/// A request whose caller was interrupted after the scheme read it
+struct Cancelled {
+    packet: Packet,
+    /// Buffers captured for the request, released once the scheme responds
+    deferred: Vec<usize>,
+}
 
 pub struct UserInner {
     root_id: SchemeId,
@@ -32,6 +97,16 @@ pub struct UserInner {
     fmap: Mutex<BTreeMap<u64, (Weak<RwLock<Context>>, FileDescriptor, Map)>>,
     funmap: Mutex<BTreeMap<Region, VirtualAddress>>,
     done: WaitMap<u64, usize>,
+    /// Requests being waited for
+    pending: Mutex<BTreeMap<u64, Pending>>,
+    /// File descriptors returned by the scheme, by the id of the context they are for
+    returned: Mutex<BTreeMap<usize, FileDescriptor>>,
+    /// Requests whose responses are to be dropped
+    cancelled: Mutex<BTreeMap<u64, Cancelled>>,
+    /// Ring shared with the scheme, once it has mapped one
+    ring: Mutex<Option<Ring>>,
+    /// Time to wait for a response, in nanoseconds, or zero to wait forever
+    timeout: AtomicU64,
     unmounting: AtomicBool,
 }
 
@@ -49,6 +124,11 @@ impl UserInner {
             fmap: Mutex::new(BTreeMap::new()),
             funmap: Mutex::new(BTreeMap::new()),
             done: WaitMap::new(),
+            pending: Mutex::new(BTreeMap::new()),
+            returned: Mutex::new(BTreeMap::new()),
+            cancelled: Mutex::new(BTreeMap::new()),
+            ring: Mutex::new(None),
+            timeout: AtomicU64::new(0),
             unmounting: AtomicBool::new(false),
         }
     }
@@ -67,6 +147,53 @@ impl UserInner {
         Ok(0)
     }
 
+    /// Unmount a scheme that stopped responding. Every pending request fails with `ENODEV`, and
+    /// the buffers lent to the scheme are taken back. Responses the scheme writes afterwards are
+    /// dropped, as their requests are no longer pending
+    pub fn force_unmount(&self) -> Result<usize> {
+        self.unmount()?;
+
+        // Requests the scheme has not read yet are dropped, and so are the completions it wrote
+        // to the ring, as their callers are answered below
+        self.todo.inner.lock().clear();
+        if let Some(ref mut ring) = *self.ring.lock() {
+            ring.reset();
+        }
+
+        let ids: Vec<u64> = {
+            // Held while answering, so no answer is left behind for a caller that is returning
+            let mut pending = self.pending.lock();
+            for &id in pending.keys() {
+                self.done.send(id, Error::mux(Err(Error::new(ENODEV))));
+            }
+            let ids = pending.keys().cloned().collect();
+            pending.clear();
+            ids
+        };
+        for id in ids {
+            self.forget_fmap(id);
+        }
+
+        let cancelled = mem::replace(&mut *self.cancelled.lock(), BTreeMap::new());
+        for (_id, entry) in cancelled {
+            for address in entry.deferred {
+                let _ = self.release(address);
+            }
+        }
+
+        Ok(0)
+    }
+
+    /// Give up on requests that take longer than `ms` milliseconds, or wait forever if zero
+    pub fn set_timeout(&self, ms: usize) {
+        self.timeout.store((ms as u64).saturating_mul(1_000_000), Ordering::SeqCst);
+    }
+
+    /// Get the requests waiting for a response
+    pub fn pending(&self) -> Vec<Pending> {
+        self.pending.lock().values().cloned().collect()
+    }
+
     pub fn call(&self, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
         let (pid, uid, gid) = {
             let contexts = context::contexts();
@@ -92,12 +219,133 @@ impl UserInner {
             return Err(Error::new(ENODEV));
         }
 
-        let id = packet.id;
+        let since = time::monotonic_ns();
+
+        let timeout = self.timeout.load(Ordering::SeqCst);
+        let context_lock = if timeout > 0 {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let deadline = since + timeout;
+            context_lock.write().wake = Some((deadline / 1_000_000_000, deadline % 1_000_000_000));
+            Some(Arc::clone(&context_lock))
+        } else {
+            None
+        };
+
+        self.pending.lock().insert(packet.id, Pending { packet, since });
+        self.submit(packet);
+
+        let response = self.done.receive_interruptible(&packet.id, "UserInner::call_inner");
+
+        // The wake time is cleared when it passes
+        let timed_out = context_lock.map_or(false, |context_lock| context_lock.write().wake.take().is_none());
+
+        let result = match response {
+            Some(result) => Error::demux(result),
+            None => self.cancel(packet, if timed_out { ETIMEDOUT } else { EINTR }),
+        };
+
+        // A forced unmount may have answered a request that was answered by the scheme too
+        self.pending.lock().remove(&packet.id);
+        self.done.receive_nonblock(&packet.id);
+
+        if result.is_err() {
+            if let Some(file) = self.returned.lock().remove(&packet.pid) {
+                let _ = file.close();
+            }
+        }
+
+        result
+    }
 
-        self.todo.send(packet);
+    /// Queue a packet for the scheme
+    fn submit(&self, packet: Packet) {
+        // Requests go through the ring if the scheme has one, unless it is full
+        let submitted = self.ring.lock().as_mut().map_or(false, |ring| ring.submit(packet));
+        if ! submitted {
+            self.todo.send(packet);
+        }
         event::trigger(self.root_id, self.handle_id, EVENT_READ);
+    }
+
+    /// Give up on a request after its caller was interrupted or timed out, returning `errno`
+    /// unless the scheme responded in the meantime
+    fn cancel(&self, packet: Packet, errno: i32) -> Result<usize> {
+        {
+            let mut todo = self.todo.inner.lock();
+            if let Some(i) = todo.iter().position(|queued| queued.id == packet.id) {
+                // The scheme never saw the request
+                todo.remove(i);
+                drop(todo);
+                self.forget_fmap(packet.id);
+                return Err(Error::new(errno));
+            }
+        }
+
+        {
+            let mut cancelled = self.cancelled.lock();
+            if let Some(result) = self.done.receive_nonblock(&packet.id) {
+                return Error::demux(result);
+            }
+            cancelled.insert(packet.id, Cancelled {
+                packet,
+                deferred: Vec::new(),
+            });
+        }
+        self.forget_fmap(packet.id);
+
+        self.submit(Packet {
+            id: 0,
+            pid: packet.pid,
+            uid: packet.uid,
+            gid: packet.gid,
+            a: KSMSG_CANCEL,
+            b: packet.id as usize,
+            c: 0,
+            d: 0
+        });
+
+        Err(Error::new(errno))
+    }
+
+    /// Drop the mapping an interrupted `fmap` was going to make
+    fn forget_fmap(&self, id: u64) {
+        if let Some((_context_weak, desc, _map)) = self.fmap.lock().remove(&id) {
+            let _ = desc.close();
+        }
+    }
+
+    /// Handle a late response to a cancelled request
+    fn respond_cancelled(&self, cancelled: Cancelled, response: Packet) {
+        for address in cancelled.deferred {
+            let _ = self.release(address);
+        }
 
-        Error::demux(self.done.receive(&id, "UserInner::call_inner"))
+        // Close a file that was opened for no one
+        if let (SYS_DUP, Ok(fd)) = (cancelled.packet.a, Error::demux(response.a)) {
+            if response.b == SKMSG_FRETURNFD {
+                if let Ok(file) = take_file(fd) {
+                    let _ = file.close();
+                }
+                return;
+            }
+        }
+        if let (SYS_OPEN, Ok(file)) | (SYS_DUP, Ok(file)) = (cancelled.packet.a, Error::demux(response.a)) {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            let packet = Packet {
+                id,
+                a: SYS_CLOSE,
+                b: file,
+                c: 0,
+                d: 0,
+                ..cancelled.packet
+            };
+            self.cancelled.lock().insert(id, Cancelled {
+                packet,
+                deferred: Vec::new(),
+            });
+            self.submit(packet);
+        }
     }
 
     /// Map a readable structure to the scheme's userspace and return the
@@ -147,10 +395,50 @@ impl UserInner {
         Ok(VirtualAddress::new(to_region.start_address().get() + offset))
     }
 
+    /// Create the request ring and map it into the calling context, returning its address
+    pub fn fmap_ring(&self, map: &Map) -> Result<usize> {
+        let context_weak = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            Arc::downgrade(&context_lock)
+        };
+
+        let mut ring_opt = self.ring.lock();
+        if ring_opt.is_some() {
+            return Err(Error::new(EEXIST));
+        }
+
+        let ring = Ring::new(map.size)?;
+        let address = UserInner::capture_inner(&context_weak, map.address, ring.address(), ring.size(), PROT_READ | PROT_WRITE, None)?;
+        *ring_opt = Some(ring);
+
+        Ok(address.get())
+    }
+
+    /// Handle the completions the scheme has written to the ring
+    fn reap(&self) {
+        let packets = match *self.ring.lock() {
+            Some(ref mut ring) => ring.reap(),
+            None => return,
+        };
+
+        for packet in packets {
+            self.respond(packet);
+        }
+    }
+
     pub fn release(&self, address: usize) -> Result<()> {
         if address == 0 {
             Ok(())
         } else {
+            // The scheme may still be using the buffers of a cancelled request
+            for cancelled in self.cancelled.lock().values_mut() {
+                if cancelled.packet.b == address || cancelled.packet.c == address {
+                    cancelled.deferred.push(address);
+                    return Ok(());
+                }
+            }
+
             let context_lock = self.context.upgrade().ok_or(Error::new(ESRCH))?;
             let mut context = context_lock.write();
 
@@ -169,6 +457,8 @@ impl UserInner {
     }
 
     pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        self.reap();
+
         let packet_buf = unsafe { slice::from_raw_parts_mut(
             buf.as_mut_ptr() as *mut Packet,
             buf.len()/mem::size_of::<Packet>())
@@ -206,41 +496,115 @@ impl UserInner {
         let len = buf.len()/packet_size;
         let mut i = 0;
         while i < len {
-            let mut packet = unsafe { *(buf.as_ptr() as *const Packet).add(i) };
-            if packet.id == 0 {
-                match packet.a {
-                    SYS_FEVENT => event::trigger(self.scheme_id.load(Ordering::SeqCst), packet.b, EventFlags::from_bits_truncate(packet.c)),
-                    _ => println!("Unknown scheme -> kernel message {}", packet.a)
+            let packet = unsafe { *(buf.as_ptr() as *const Packet).add(i) };
+            self.respond(packet);
+            i += 1;
+        }
+
+        Ok(i * packet_size)
+    }
+
+    /// Handle a response, or a message with an `id` of zero, from the scheme
+    fn respond(&self, packet: Packet) {
+        if packet.id == 0 {
+            match packet.a {
+                SYS_FEVENT => event::trigger(self.scheme_id.load(Ordering::SeqCst), packet.b, EventFlags::from_bits_truncate(packet.c)),
+                SKMSG_TIMEOUT => self.set_timeout(packet.b),
+                _ => println!("Unknown scheme -> kernel message {}", packet.a)
+            }
+        } else {
+            let entry = {
+                // Held while responding, so an interrupted caller either gets the response or
+                // cancels the request before it is handled
+                let mut cancelled = self.cancelled.lock();
+                let entry = cancelled.remove(&packet.id);
+                if entry.is_none() {
+                    self.respond_request(packet);
                 }
-            } else {
-                if let Some((context_weak, desc, map)) = self.fmap.lock().remove(&packet.id) {
-                    if let Ok(address) = Error::demux(packet.a) {
-                        if address % PAGE_SIZE > 0 {
-                            println!("scheme returned unaligned address, causing extra frame to be allocated");
-                        }
-                        let res = UserInner::capture_inner(&context_weak, map.address, address, map.size, map.flags, Some(desc));
-                        if let Ok(grant_address) = res {
-                            self.funmap.lock().insert(Region::new(grant_address, map.size), VirtualAddress::new(address));
-                        }
-                        packet.a = Error::mux(res.map(|addr| addr.get()));
-                    } else {
-                        let _ = desc.close();
-                    }
+                entry
+            };
+            if let Some(entry) = entry {
+                self.respond_cancelled(entry, packet);
+            }
+        }
+    }
+
+    /// Handle the response to a request that is being waited for. Responses to requests that
+    /// are not pending, for example after a forced unmount answered them, are dropped
+    fn respond_request(&self, mut packet: Packet) {
+        let request = match self.pending.lock().get(&packet.id) {
+            Some(pending) => pending.packet,
+            None => return,
+        };
+
+        if packet.b == SKMSG_FRETURNFD && request.a == SYS_DUP {
+            if let Ok(fd) = Error::demux(packet.a) {
+                match take_file(fd) {
+                    Ok(file) => if let Some(old) = self.returned.lock().insert(request.pid, file) {
+                        let _ = old.close();
+                    },
+                    Err(err) => packet.a = Error::mux(Err(err)),
                 }
+            }
+        }
 
-                self.done.send(packet.id, packet.a);
+        if let Some((context_weak, desc, map)) = self.fmap.lock().remove(&packet.id) {
+            if let Ok(address) = Error::demux(packet.a) {
+                if address % PAGE_SIZE > 0 {
+                    println!("scheme returned unaligned address, causing extra frame to be allocated");
+                }
+                let res = UserInner::capture_inner(&context_weak, map.address, address, map.size, map.flags, Some(desc));
+                if let Ok(grant_address) = res {
+                    self.funmap.lock().insert(Region::new(grant_address, map.size), VirtualAddress::new(address));
+                }
+                packet.a = Error::mux(res.map(|addr| addr.get()));
+            } else {
+                let _ = desc.close();
             }
-            i += 1;
         }
 
-        Ok(i * packet_size)
+        // Held while answering, so no answer is left behind for a caller that already returned
+        let pending = self.pending.lock();
+        if pending.contains_key(&packet.id) {
+            self.done.send(packet.id, packet.a);
+        }
     }
 
     pub fn fevent(&self, _flags: EventFlags) -> Result<EventFlags> {
         Ok(EventFlags::empty())
     }
 
+    /// Give a file descriptor to the scheme, as a `SYS_SENDFD` request on `file`. The descriptor
+    /// is added to the file table of the scheme, which owns it from then on, even if it fails
+    /// the request
+    pub fn sendfd(&self, file: usize, desc: FileDescriptor, flags: usize) -> Result<usize> {
+        if self.unmounting.load(Ordering::SeqCst) {
+            let _ = desc.close();
+            return Err(Error::new(ENODEV));
+        }
+
+        let fd = {
+            let context_lock = self.context.upgrade().ok_or(Error::new(ESRCH))?;
+            let context = context_lock.read();
+            context.add_file(desc).ok_or(Error::new(EMFILE))?
+        };
+
+        self.call(SYS_SENDFD, file, fd.into(), flags)
+    }
+
+    /// Take the file descriptor the scheme returned to the current context, if any
+    pub fn take_returned(&self) -> Option<FileDescriptor> {
+        let pid = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current()?;
+            let context = context_lock.read();
+            context.id.into()
+        };
+        self.returned.lock().remove(&pid)
+    }
+
     pub fn fsync(&self) -> Result<usize> {
+        self.reap();
         Ok(0)
     }
 }
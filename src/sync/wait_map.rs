@@ -33,6 +33,19 @@ impl<K, V> WaitMap<K, V> where K: Clone + Ord {
         }
     }
 
+    /// Wait for a value, returning None if woken by a signal before it was sent
+    pub fn receive_interruptible(&self, key: &K, reason: &'static str) -> Option<V> {
+        loop {
+            let mut inner = self.inner.lock();
+            if let Some(value) = inner.remove(key) {
+                return Some(value);
+            }
+            if ! self.condition.wait(inner, reason) {
+                return self.inner.lock().remove(key);
+            }
+        }
+    }
+
     pub fn receive_any_nonblock(&self) -> Option<(K, V)> {
         let mut inner = self.inner.lock();
         if let Some(key) = inner.keys().next().cloned() {
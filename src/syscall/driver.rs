@@ -1,6 +1,6 @@
 use crate::interrupt::InterruptStack;
-use crate::memory::{allocate_frames_complex, deallocate_frames, Frame};
-use crate::paging::{ActivePageTable, PhysicalAddress, VirtualAddress};
+use crate::memory::{allocate_frames_complex, deallocate_frames, descriptor, Frame, FrameFlags};
+use crate::paging::{ActivePageTable, PageSize, PhysicalAddress, VirtualAddress};
 use crate::paging::entry::EntryFlags;
 use crate::context;
 use crate::context::memory::{Grant, Region};
@@ -35,7 +35,17 @@ pub fn inner_physalloc(size: usize, flags: PhysallocFlags, strategy: Option<Part
         return Err(Error::new(EINVAL));
     }
     let space32 = flags.contains(PhysallocFlags::SPACE_32);
-    allocate_frames_complex((size + 4095) / 4096, flags, strategy, (min + 4095) / 4096).ok_or(Error::new(ENOMEM)).map(|(frame, count)| (frame.start_address().get(), count * 4096))
+    let (frame, count) = allocate_frames_complex((size + 4095) / 4096, flags, strategy, (min + 4095) / 4096).ok_or(Error::new(ENOMEM))?;
+    let base = frame.start_address().get();
+
+    // Drivers give the physical address to devices, so the frames must stay where they are
+    for i in 0..count {
+        if let Some(descriptor) = descriptor(&Frame::containing_address(PhysicalAddress::new(base + i * 4096))) {
+            descriptor.insert_flags(FrameFlags::PINNED);
+        }
+    }
+
+    Ok((base, count * 4096))
 }
 pub fn physalloc(size: usize) -> Result<usize> {
     enforce_root()?;
@@ -55,9 +65,20 @@ pub fn physalloc3(size: usize, flags_raw: usize, min: &mut usize) -> Result<usiz
 }
 
 pub fn inner_physfree(physical_address: usize, size: usize) -> Result<usize> {
-    deallocate_frames(Frame::containing_address(PhysicalAddress::new(physical_address)), (size + 4095)/4096);
+    let count = (size + 4095)/4096;
+
+    // Refuse to free frames that are already free, shared, or not memory at all
+    for i in 0..count {
+        let frame = Frame::containing_address(PhysicalAddress::new(physical_address + i * 4096));
+        if let Some(descriptor) = descriptor(&frame) {
+            if descriptor.refcount() != 1 || descriptor.flags().contains(FrameFlags::RESERVED) {
+                return Err(Error::new(EINVAL));
+            }
+        }
+    }
+
+    deallocate_frames(Frame::containing_address(PhysicalAddress::new(physical_address)), count);
 
-    //TODO: Check that no double free occured
     Ok(0)
 }
 pub fn physfree(physical_address: usize, size: usize) -> Result<usize> {
@@ -93,6 +114,11 @@ pub fn inner_physmap(physical_address: usize, size: usize, flags: PhysmapFlags)
             entry_flags |= EntryFlags::NO_CACHE;
         }
 
+        // Align the mapping like the physical memory, so that it can use huge pages
+        let page_size = PageSize::largest(0, from_address, full_size);
+        let align_up = |address: usize| (address + page_size.size() - 1) / page_size.size() * page_size.size();
+        to_address = align_up(to_address);
+
         // TODO: Make this faster than Sonic himself by using le superpowers of BTreeSet
 
         for grant in grants.iter() {
@@ -103,7 +129,7 @@ pub fn inner_physmap(physical_address: usize, size: usize, flags: PhysmapFlags)
 
             let pages = (grant.size() + 4095) / 4096;
             let end = start + pages * 4096;
-            to_address = end;
+            to_address = align_up(end);
         }
 
         grants.insert(Grant::physmap(
@@ -1,6 +1,7 @@
 //! Filesystem syscalls
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::{mem, ptr};
 use core::sync::atomic::Ordering;
 use spin::RwLock;
 
@@ -8,13 +9,17 @@ use crate::context::file::{FileDescriptor, FileDescription};
 use crate::context::memory::Region;
 use crate::context;
 use crate::memory::PAGE_SIZE;
-use crate::paging::VirtualAddress;
-use crate::scheme::{self, FileHandle};
-use crate::syscall::data::{Packet, Stat};
+use crate::paging::{ActivePageTable, Page, VirtualAddress};
+use crate::scheme::{self, user, FileHandle};
+use crate::syscall::data::{Map, Packet, Stat};
 use crate::syscall::error::*;
 use crate::syscall::flag::*;
 use crate::syscall;
 
+//TODO: Move to `syscall::number`, so that userspace shares this definition
+/// Give a file descriptor to a userspace scheme
+pub const SYS_SENDFD: usize = syscall::number::SYS_CLASS_FILE | 34;
+
 pub fn file_op(a: usize, fd: FileHandle, c: usize, d: usize) -> Result<usize> {
     let (file, pid, uid, gid) = {
         let contexts = context::contexts();
@@ -24,6 +29,22 @@ pub fn file_op(a: usize, fd: FileHandle, c: usize, d: usize) -> Result<usize> {
         (file, context.id, context.euid, context.egid)
     };
 
+    file_op_inner(a, &file, pid, uid, gid, c, d)
+}
+
+/// Like `file_op`, on a file that does not have to be in the file table of the current context
+pub fn file_op_desc(a: usize, file: &FileDescriptor, c: usize, d: usize) -> Result<usize> {
+    let (pid, uid, gid) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.id, context.euid, context.egid)
+    };
+
+    file_op_inner(a, file, pid, uid, gid, c, d)
+}
+
+fn file_op_inner(a: usize, file: &FileDescriptor, pid: context::ContextId, uid: u32, gid: u32, c: usize, d: usize) -> Result<usize> {
     let scheme = {
         let schemes = scheme::schemes();
         let scheme = schemes.get(file.description.read().scheme).ok_or(Error::new(EBADF))?;
@@ -298,6 +319,14 @@ fn duplicate_file(fd: FileHandle, buf: &[u8]) -> Result<FileDescriptor> {
             scheme.dup(description.number, buf)?
         };
 
+        // A userspace scheme may answer with one of its own file descriptors instead
+        if let Some(returned) = user::user_scheme(description.scheme).and_then(|inner| inner.take_returned()) {
+            return Ok(FileDescriptor {
+                description: returned.description,
+                cloexec: false,
+            });
+        }
+
         Ok(FileDescriptor {
             description: Arc::new(RwLock::new(FileDescription {
                 namespace: description.namespace,
@@ -337,6 +366,26 @@ pub fn dup2(fd: FileHandle, new_fd: FileHandle, buf: &[u8]) -> Result<FileHandle
     }
 }
 
+/// Move a file descriptor to the userspace scheme `socket` was opened from
+pub fn sendfd(socket: FileHandle, fd: FileHandle, flags: usize) -> Result<usize> {
+    let (inner, number, file) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        let (scheme_id, number) = {
+            let socket_file = context.get_file(socket).ok_or(Error::new(EBADF))?;
+            let description = socket_file.description.read();
+            (description.scheme, description.number)
+        };
+        let inner = user::user_scheme(scheme_id).ok_or(Error::new(EOPNOTSUPP))?;
+
+        (inner, number, context.remove_file(fd).ok_or(Error::new(EBADF))?)
+    };
+
+    inner.sendfd(number, file, flags)
+}
+
 /// File descriptor controls
 pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
     let file = {
@@ -486,6 +535,16 @@ pub fn funmap_old(virtual_address: usize) -> Result<usize> {
     }
 }
 
+/// Map anonymous memory, for `fmap` on no file. `buf` holds the `Map`
+pub fn fmap_anonymous(buf: &[u8]) -> Result<usize> {
+    if buf.len() < mem::size_of::<Map>() {
+        return Err(Error::new(EINVAL));
+    }
+    let map = unsafe { ptr::read_unaligned(buf.as_ptr() as *const Map) };
+
+    context::memory::map_anonymous(map.address, map.size, map.flags)
+}
+
 pub fn funmap(virtual_address: usize, length: usize) -> Result<usize> {
     if virtual_address == 0 || length == 0 {
         return Ok(0);
@@ -505,6 +564,14 @@ pub fn funmap(virtual_address: usize, length: usize) -> Result<usize> {
 
         let mut grants = context.grants.lock();
 
+        // Physmaps may use huge pages, which have to be split where only part of one is unmapped
+        {
+            let rounded = requested.round();
+            let mut active_table = unsafe { ActivePageTable::new() };
+            active_table.split_huge(Page::containing_address(rounded.start_address()))?;
+            active_table.split_huge(Page::containing_address(rounded.final_address()))?;
+        }
+
         let conflicting: Vec<Region> = grants.conflicts(requested).map(Region::from).collect();
 
         for conflict in conflicting {
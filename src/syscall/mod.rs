@@ -4,6 +4,9 @@
 
 extern crate syscall;
 
+use alloc::vec::Vec;
+use core::mem;
+
 pub use self::syscall::{data, error, flag, io, number, ptrace_event, scheme};
 
 pub use self::driver::*;
@@ -57,13 +60,18 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, bp: u
             SYS_CLASS_FILE => {
                 let fd = FileHandle::from(b);
                 match a & SYS_ARG {
-                    SYS_ARG_SLICE => file_op_slice(a, fd, validate_slice(c as *const u8, d)?),
+                    SYS_ARG_SLICE => match a {
+                        // fmap on no file maps anonymous memory
+                        SYS_FMAP if b == !0 => fmap_anonymous(validate_slice(c as *const u8, d)?),
+                        _ => file_op_slice(a, fd, validate_slice(c as *const u8, d)?),
+                    },
                     SYS_ARG_MSLICE => file_op_mut_slice(a, fd, validate_slice_mut(c as *mut u8, d)?),
                     _ => match a {
                         SYS_CLOSE => close(fd),
                         SYS_DUP => dup(fd, validate_slice(c as *const u8, d)?).map(FileHandle::into),
                         SYS_DUP2 => dup2(fd, FileHandle::from(c), validate_slice(d as *const u8, e)?).map(FileHandle::into),
                         SYS_FCNTL => fcntl(fd, c, d),
+                        SYS_SENDFD => sendfd(fd, FileHandle::from(c), d),
                         SYS_FEXEC => fexec(fd, validate_slice(c as *const [usize; 2], d)?, validate_slice(e as *const [usize; 2], f)?),
                         SYS_FRENAME => frename(fd, validate_slice(c as *const u8, d)?),
                         SYS_FUNMAP => funmap(b, c),
@@ -231,13 +239,17 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, bp: u
 
     let result = inner(a, b, c, d, e, f, bp, stack);
 
-    {
+    let pinned = {
         let contexts = crate::context::contexts();
         if let Some(context_lock) = contexts.current() {
             let mut context = context_lock.write();
             context.syscall = None;
+            mem::replace(&mut context.pinned, Vec::new())
+        } else {
+            Vec::new()
         }
-    }
+    };
+    unpin(pinned);
 
     /*
     if debug {
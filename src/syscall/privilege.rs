@@ -1,6 +1,7 @@
 use alloc::vec::Vec;
 
-use crate::context;
+use crate::context::{self, ContextId, SchedPolicy};
+use crate::ipi::{ipi_cpu, IpiKind};
 use crate::scheme::{self, SchemeNamespace};
 use crate::syscall::error::*;
 use crate::syscall::validate::validate_slice;
@@ -235,3 +236,54 @@ pub fn setreuid(ruid: u32, euid: u32) -> Result<usize> {
 
     Ok(0)
 }
+
+/// Get the scheduling policy and real-time priority of a context
+pub fn sched_getscheduler(pid: ContextId) -> Result<(usize, usize)> {
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    Ok(context.policy.to_raw())
+}
+
+/// Set the scheduling policy and real-time priority of a context. Only root may use real-time
+/// policies, other users may only move their own contexts back to the normal policy
+pub fn sched_setscheduler(pid: ContextId, policy: usize, priority: usize) -> Result<usize> {
+    let policy = SchedPolicy::from_raw(policy, priority).ok_or(Error::new(EINVAL))?;
+
+    let euid = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.euid
+    };
+
+    if euid != 0 && policy != SchedPolicy::Normal {
+        return Err(Error::new(EPERM));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+
+    if euid != 0 && euid != context.euid {
+        return Err(Error::new(EPERM));
+    }
+
+    // A queued context is moved to the set of its new policy, which may preempt the running
+    // context. A running context is switched away from so that its CPU picks again
+    if context.queued {
+        context::run_queue::dequeue(&mut context);
+        context.policy = policy;
+        context::run_queue::enqueue(&mut context);
+    } else {
+        context.policy = policy;
+        if context.running {
+            match context.cpu_id {
+                Some(cpu_id) if cpu_id != crate::cpu_id() => ipi_cpu(IpiKind::Switch, cpu_id),
+                _ => context::preempt(),
+            }
+        }
+    }
+
+    Ok(0)
+}
@@ -15,7 +15,7 @@ use crate::context;
 use crate::elf::{self, program_header};
 use crate::interrupt;
 use crate::ipi::{ipi, IpiKind, IpiTarget};
-use crate::memory::allocate_frames;
+use crate::memory::{allocate_frames, deallocate_frames};
 use crate::paging::entry::EntryFlags;
 use crate::paging::mapper::MapperFlushAll;
 use crate::paging::temporary_page::TemporaryPage;
@@ -29,7 +29,7 @@ use crate::syscall::flag::{wifcontinued, wifstopped, AT_ENTRY, AT_NULL, AT_PHDR,
                            CLONE_FILES, CLONE_FS, CLONE_SIGHAND, CLONE_STACK, CLONE_VFORK, CLONE_VM,
                            MapFlags, PROT_EXEC, PROT_READ, PROT_WRITE, PTRACE_EVENT_CLONE,
                            PTRACE_STOP_EXIT, SigActionFlags, SIG_BLOCK, SIG_DFL, SIG_SETMASK, SIG_UNBLOCK,
-                           SIGCONT, SIGTERM, WaitFlags, WCONTINUED, WNOHANG, WUNTRACED};
+                           SIGCONT, SIGKILL, SIGTERM, WaitFlags, WCONTINUED, WNOHANG, WUNTRACED};
 use crate::syscall::ptrace_event;
 use crate::syscall::validate::{validate_slice, validate_slice_mut};
 
@@ -46,6 +46,10 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
         let ens;
         let umask;
         let sigmask;
+        let nice;
+        let vruntime;
+        let affinity;
+        let policy;
         let cpu_id_opt = None;
         let arch;
         let vfork;
@@ -78,6 +82,10 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
             ens = context.ens;
             sigmask = context.sigmask;
             umask = context.umask;
+            nice = context.nice;
+            vruntime = context.vruntime;
+            affinity = context.affinity;
+            policy = context.policy;
 
             // Uncomment to disable threads on different CPUs
             // if flags.contains(CLONE_VM) {
@@ -126,20 +134,9 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
             } else {
                 for memory_shared in context.image.iter() {
                     memory_shared.with(|memory| {
-                        let mut new_memory = context::memory::Memory::new(
-                            VirtualAddress::new(memory.start_address().get() + crate::USER_TMP_OFFSET),
-                            memory.size(),
-                            EntryFlags::PRESENT | EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE,
-                            false
+                        let new_memory = memory.cow_clone(
+                            VirtualAddress::new(memory.start_address().get() + crate::USER_TMP_OFFSET)
                         );
-
-                        unsafe {
-                            intrinsics::copy(memory.start_address().get() as *const u8,
-                                            new_memory.start_address().get() as *mut u8,
-                                            memory.size());
-                        }
-
-                        new_memory.remap(memory.flags());
                         image.push(new_memory.to_shared());
                     });
                 }
@@ -150,70 +147,45 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
                     stack_opt = Some(stack_shared.clone());
                 } else {
                     stack_shared.with(|stack| {
-                        let mut new_stack = context::memory::Memory::new(
-                            VirtualAddress::new(crate::USER_TMP_STACK_OFFSET),
-                            stack.size(),
-                            EntryFlags::PRESENT | EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE,
-                            false
-                        );
-
-                        unsafe {
-                            intrinsics::copy(stack.start_address().get() as *const u8,
-                                            new_stack.start_address().get() as *mut u8,
-                                            stack.size());
-                        }
-
-                        new_stack.remap(stack.flags());
+                        let new_stack = stack.cow_clone(VirtualAddress::new(crate::USER_TMP_STACK_OFFSET));
                         stack_opt = Some(new_stack.to_shared());
                     });
                 }
             }
 
             if let Some(ref sigstack) = context.sigstack {
-                let mut new_sigstack = context::memory::Memory::new(
-                    VirtualAddress::new(crate::USER_TMP_SIGSTACK_OFFSET),
-                    sigstack.size(),
-                    EntryFlags::PRESENT | EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE,
-                    false
-                );
-
-                unsafe {
-                    intrinsics::copy(sigstack.start_address().get() as *const u8,
-                                    new_sigstack.start_address().get() as *mut u8,
-                                    sigstack.size());
-                }
-
-                new_sigstack.remap(sigstack.flags());
-                sigstack_opt = Some(new_sigstack);
+                sigstack_opt = Some(sigstack.cow_clone(VirtualAddress::new(crate::USER_TMP_SIGSTACK_OFFSET)));
             }
 
             if let Some(ref tls) = context.tls {
-                let mut new_tls = context::memory::Tls {
-                    master: tls.master,
-                    file_size: tls.file_size,
-                    mem: context::memory::Memory::new(
-                        VirtualAddress::new(crate::USER_TMP_TLS_OFFSET),
-                        tls.mem.size(),
-                        EntryFlags::PRESENT | EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE,
-                        true
-                    ),
-                    offset: tls.offset,
-                };
-
+                let new_tls = if flags.contains(CLONE_VM) {
+                    let mut new_tls = context::memory::Tls {
+                        master: tls.master,
+                        file_size: tls.file_size,
+                        mem: context::memory::Memory::new(
+                            VirtualAddress::new(crate::USER_TMP_TLS_OFFSET),
+                            tls.mem.size(),
+                            EntryFlags::PRESENT | EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE,
+                            true
+                        )?,
+                        offset: tls.offset,
+                    };
 
-                if flags.contains(CLONE_VM) {
                     unsafe {
                         new_tls.load();
                     }
+
+                    new_tls.mem.remap(tls.mem.flags());
+                    new_tls
                 } else {
-                    unsafe {
-                        intrinsics::copy(tls.mem.start_address().get() as *const u8,
-                                        new_tls.mem.start_address().get() as *mut u8,
-                                        tls.mem.size());
+                    context::memory::Tls {
+                        master: tls.master,
+                        file_size: tls.file_size,
+                        mem: tls.mem.cow_clone(VirtualAddress::new(crate::USER_TMP_TLS_OFFSET)),
+                        offset: tls.offset,
                     }
-                }
+                };
 
-                new_tls.mem.remap(tls.mem.flags());
                 tls_opt = Some(new_tls);
             }
 
@@ -223,7 +195,19 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
                 let mut grants_set = UserGrants::default();
                 for grant in context.grants.lock().iter() {
                     let start = VirtualAddress::new(grant.start_address().get() + crate::USER_TMP_GRANT_OFFSET - crate::USER_GRANT_OFFSET);
-                    grants_set.insert(grant.secret_clone(start));
+                    match grant.secret_clone(start) {
+                        Ok(new_grant) => {
+                            grants_set.insert(new_grant);
+                        },
+                        Err(err) => {
+                            // The copies made so far are unmapped, and the other parts of the
+                            // new process are dropped
+                            for new_grant in mem::replace(&mut grants_set, UserGrants::default()).inner.into_iter() {
+                                new_grant.unmap();
+                            }
+                            return Err(err);
+                        }
+                    }
                 }
                 grants = Arc::new(Mutex::new(grants_set));
             }
@@ -293,22 +277,56 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
             }
         }
 
+        // Allocate the page table and TCB of the new process before anything depends on them,
+        // so that running out of memory fails the clone cleanly. The TCB is mapped at an address
+        // of the parent's own, as threads sharing its address space may clone at the same time.
+        // If the clone fails, the TCB is unmapped when dropped, and the frame has to be freed
+        let new_table_frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+        let mut tcb = match context::memory::Memory::new(
+            VirtualAddress::new(crate::USER_TMP_HEAP_OFFSET + ppid.into() * PAGE_SIZE),
+            PAGE_SIZE,
+            EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE,
+            true
+        ) {
+            Ok(tcb) => tcb,
+            Err(err) => {
+                deallocate_frames(new_table_frame, 1);
+                return Err(err);
+            }
+        };
+
         // If vfork, block the current process
         // This has to be done after the operations that may require context switches
-        if flags.contains(CLONE_VFORK) {
+        let vfork_parent = if flags.contains(CLONE_VFORK) {
             let contexts = context::contexts();
-            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
-            let mut context = context_lock.write();
-            context.block("vfork");
+            let context_lock = match contexts.current() {
+                Some(context_lock) => Arc::clone(context_lock),
+                None => {
+                    deallocate_frames(new_table_frame, 1);
+                    return Err(Error::new(ESRCH));
+                }
+            };
+            context_lock.write().block("vfork");
             vfork = true;
+            Some(context_lock)
         } else {
             vfork = false;
-        }
+            None
+        };
 
         // Set up new process
         {
             let mut contexts = context::contexts_mut();
-            let context_lock = contexts.new_context()?;
+            let context_lock = match contexts.new_context() {
+                Ok(context_lock) => context_lock,
+                Err(err) => {
+                    deallocate_frames(new_table_frame, 1);
+                    if let Some(parent) = vfork_parent {
+                        parent.write().unblock();
+                    }
+                    return Err(err);
+                }
+            };
             let mut context = context_lock.write();
 
             pid = context.id;
@@ -323,12 +341,15 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
             context.ens = ens;
             context.sigmask = sigmask;
             context.umask = umask;
+            context.nice = nice;
+            context.vruntime = vruntime;
+            context.affinity = affinity;
+            context.policy = policy;
 
-            //TODO: Better CPU balancing
             if let Some(cpu_id) = cpu_id_opt {
                 context.cpu_id = Some(cpu_id);
             } else {
-                context.cpu_id = Some(pid.into() % crate::cpu_count());
+                context.cpu_id = Some(context::run_queue::select_cpu(&context, pid.into()));
             }
 
             context.status = context::Status::Runnable;
@@ -341,10 +362,7 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
 
             let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(crate::USER_TMP_MISC_OFFSET)));
 
-            let mut new_table = {
-                let frame = allocate_frames(1).expect("no more frames in syscall::clone new_table");
-                InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
-            };
+            let mut new_table = InactivePageTable::new(new_table_frame, &mut active_table, &mut temporary_page);
 
             context.arch.set_page_table(unsafe { new_table.address() });
 
@@ -366,6 +384,14 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
                 });
             }
 
+            // Copy frame descriptor mapping
+            if let Some(frame) = active_table.p4()[crate::KERNEL_FRAMES_PML4].pointed_frame() {
+                let flags = active_table.p4()[crate::KERNEL_FRAMES_PML4].flags();
+                active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+                    mapper.p4_mut()[crate::KERNEL_FRAMES_PML4].set(frame, flags);
+                });
+            }
+
             if let Some(fx) = kfx_opt.take() {
                 context.arch.set_fx(fx.as_ptr() as usize);
                 context.kfx = Some(fx);
@@ -474,12 +500,6 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
 
             // Set up TCB
             let tcb_addr = crate::USER_TCB_OFFSET + context.id.into() * PAGE_SIZE;
-            let mut tcb = context::memory::Memory::new(
-                VirtualAddress::new(tcb_addr),
-                PAGE_SIZE,
-                EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE,
-                true
-            );
 
             // Setup user TLS
             if let Some(mut tls) = tls_opt {
@@ -497,7 +517,7 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
                 //println!("{}: Copy TLS: address 0x{:x}, size 0x{:x}", context.id.into(), tls_addr, tls.mem.size());
                 tls.mem.move_to(VirtualAddress::new(tls_addr), &mut new_table, &mut temporary_page);
                 unsafe {
-                    *(tcb_addr as *mut usize) = tls.mem.start_address().get() + tls.mem.size();
+                    *(tcb.start_address().get() as *mut usize) = tls.mem.start_address().get() + tls.mem.size();
                 }
                 context.tls = Some(tls);
             } else {
@@ -505,7 +525,7 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
                 let parent_tcb_addr = crate::USER_TCB_OFFSET + ppid.into() * PAGE_SIZE;
                 unsafe {
                     intrinsics::copy(parent_tcb_addr as *const u8,
-                                    tcb_addr as *mut u8,
+                                    tcb.start_address().get() as *mut u8,
                                     tcb.size());
                 }
             }
@@ -520,6 +540,8 @@ pub fn clone(flags: CloneFlags, stack_base: usize) -> Result<ContextId> {
             context.files = files;
 
             context.actions = actions;
+
+            context::run_queue::enqueue(&mut context);
         }
     }
 
@@ -555,6 +577,9 @@ fn empty(context: &mut context::Context, reaping: bool) {
         drop(context.tls.take());
     }
 
+    // Exec and exit do not return to the syscall handler, which would release these
+    syscall::unpin(mem::replace(&mut context.pinned, Vec::new()));
+
     let mut grants = context.grants.lock();
     if Arc::strong_count(&context.grants) == 1 {
         let grants = mem::replace(&mut *grants, UserGrants::default());
@@ -593,6 +618,8 @@ fn fexec_noreturn(
     let entry;
     let singlestep;
     let mut sp = crate::USER_STACK_OFFSET + crate::USER_STACK_SIZE - 256;
+    // The old image is already gone, so if the new one can not be mapped the process is killed
+    let mut oom = false;
 
     {
         let (vfork, ppid, files) = {
@@ -630,19 +657,30 @@ fn fexec_noreturn(
                     EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE,
                     true
                 );
+                oom |= tcb_mem.is_err();
 
                 for segment in elf.segments() {
+                    if oom {
+                        break;
+                    }
+
                     match segment.p_type {
                         program_header::PT_LOAD => {
                             let voff = segment.p_vaddr as usize % PAGE_SIZE;
                             let vaddr = segment.p_vaddr as usize - voff;
 
-                            let mut memory = context::memory::Memory::new(
+                            let mut memory = match context::memory::Memory::new(
                                 VirtualAddress::new(vaddr),
                                 segment.p_memsz as usize + voff,
                                 EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE,
                                 true
-                            );
+                            ) {
+                                Ok(memory) => memory,
+                                Err(_) => {
+                                    oom = true;
+                                    break;
+                                }
+                            };
 
                             unsafe {
                                 // Copy file data
@@ -682,12 +720,18 @@ fn fexec_noreturn(
                             let tls = context::memory::Tls {
                                 master: VirtualAddress::new(segment.p_vaddr as usize),
                                 file_size: segment.p_filesz as usize,
-                                mem: context::memory::Memory::new(
+                                mem: match context::memory::Memory::new(
                                     VirtualAddress::new(tls_addr),
                                     rounded_size as usize,
                                     EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE,
                                     true
-                                ),
+                                ) {
+                                    Ok(mem) => mem,
+                                    Err(_) => {
+                                        oom = true;
+                                        break;
+                                    }
+                                },
                                 offset: rounded_offset as usize,
                             };
 
@@ -701,27 +745,37 @@ fn fexec_noreturn(
                     }
                 }
 
-                context.image.push(tcb_mem.to_shared());
+                if let Ok(tcb_mem) = tcb_mem {
+                    context.image.push(tcb_mem.to_shared());
+                }
             }
 
             // Data no longer required, can deallocate
             drop(data);
 
             // Map stack
-            context.stack = Some(context::memory::Memory::new(
-                VirtualAddress::new(crate::USER_STACK_OFFSET),
-                crate::USER_STACK_SIZE,
-                EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE,
-                true
-            ).to_shared());
+            if ! oom {
+                match context::memory::Memory::new_lazy(
+                    VirtualAddress::new(crate::USER_STACK_OFFSET),
+                    crate::USER_STACK_SIZE,
+                    EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE
+                ) {
+                    Ok(stack) => context.stack = Some(stack.to_shared()),
+                    Err(_) => oom = true,
+                }
+            }
 
             // Map stack
-            context.sigstack = Some(context::memory::Memory::new(
-                VirtualAddress::new(crate::USER_SIGSTACK_OFFSET),
-                crate::USER_SIGSTACK_SIZE,
-                EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE,
-                true
-            ));
+            if ! oom {
+                match context::memory::Memory::new_lazy(
+                    VirtualAddress::new(crate::USER_SIGSTACK_OFFSET),
+                    crate::USER_SIGSTACK_SIZE,
+                    EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE
+                ) {
+                    Ok(sigstack) => context.sigstack = Some(sigstack),
+                    Err(_) => oom = true,
+                }
+            }
 
             // Map TLS
             if let Some(mut tls) = tls_opt {
@@ -732,66 +786,71 @@ fn fexec_noreturn(
                 context.tls = Some(tls);
             }
 
-            let mut push = |arg| {
-                sp -= mem::size_of::<usize>();
-                unsafe { *(sp as *mut usize) = arg; }
-            };
+            if ! oom {
+                let mut push = |arg| {
+                    sp -= mem::size_of::<usize>();
+                    unsafe { *(sp as *mut usize) = arg; }
+                };
 
-            // Push auxiliary vector
-            push(AT_NULL);
-            for &arg in auxv.iter().rev() {
-                push(arg);
-            }
+                // Push auxiliary vector
+                push(AT_NULL);
+                for &arg in auxv.iter().rev() {
+                    push(arg);
+                }
 
-            drop(auxv); // no longer required
+                drop(auxv); // no longer required
 
-            let mut arg_size = 0;
+                let mut arg_size = 0;
 
-            // Push environment variables and arguments
-            for iter in &[&vars, &args] {
-                // Push null-terminator
-                push(0);
+                // Push environment variables and arguments
+                for iter in &[&vars, &args] {
+                    // Push null-terminator
+                    push(0);
 
-                // Push pointer to content
-                for arg in iter.iter().rev() {
-                    push(crate::USER_ARG_OFFSET + arg_size);
-                    arg_size += arg.len() + 1;
+                    // Push pointer to content
+                    for arg in iter.iter().rev() {
+                        push(crate::USER_ARG_OFFSET + arg_size);
+                        arg_size += arg.len() + 1;
+                    }
                 }
-            }
 
-            // For some reason, Linux pushes the argument count here (in
-            // addition to being null-terminated), but not the environment
-            // variable count.
-            // TODO: Push more counts? Less? Stop having null-termination?
-            push(args.len());
-
-            // Write environment and argument pointers to USER_ARG_OFFSET
-            if arg_size > 0 {
-                let mut memory = context::memory::Memory::new(
-                    VirtualAddress::new(crate::USER_ARG_OFFSET),
-                    arg_size,
-                    EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE,
-                    true
-                );
+                // For some reason, Linux pushes the argument count here (in
+                // addition to being null-terminated), but not the environment
+                // variable count.
+                // TODO: Push more counts? Less? Stop having null-termination?
+                push(args.len());
+
+                // Write environment and argument pointers to USER_ARG_OFFSET
+                if arg_size > 0 {
+                    match context::memory::Memory::new(
+                        VirtualAddress::new(crate::USER_ARG_OFFSET),
+                        arg_size,
+                        EntryFlags::NO_EXECUTE | EntryFlags::WRITABLE,
+                        true
+                    ) {
+                        Ok(mut memory) => {
+                            let mut arg_offset = 0;
+                            for arg in vars.iter().rev().chain(args.iter().rev()) {
+                                unsafe {
+                                    intrinsics::copy(arg.as_ptr(),
+                                           (crate::USER_ARG_OFFSET + arg_offset) as *mut u8,
+                                           arg.len());
+                                }
+                                arg_offset += arg.len();
+
+                                unsafe {
+                                    *((crate::USER_ARG_OFFSET + arg_offset) as *mut u8) = 0;
+                                }
+                                arg_offset += 1;
+                            }
 
-                let mut arg_offset = 0;
-                for arg in vars.iter().rev().chain(args.iter().rev()) {
-                    unsafe {
-                        intrinsics::copy(arg.as_ptr(),
-                               (crate::USER_ARG_OFFSET + arg_offset) as *mut u8,
-                               arg.len());
-                    }
-                    arg_offset += arg.len();
+                            memory.remap(EntryFlags::NO_EXECUTE | EntryFlags::USER_ACCESSIBLE);
 
-                    unsafe {
-                        *((crate::USER_ARG_OFFSET + arg_offset) as *mut u8) = 0;
+                            context.image.push(memory.to_shared());
+                        },
+                        Err(_) => oom = true,
                     }
-                    arg_offset += 1;
                 }
-
-                memory.remap(EntryFlags::NO_EXECUTE | EntryFlags::USER_ACCESSIBLE);
-
-                context.image.push(memory.to_shared());
             }
 
             // Args and vars no longer required, can deallocate
@@ -841,6 +900,11 @@ fn fexec_noreturn(
         }
     }
 
+    if oom {
+        println!("exec: out of memory mapping the new image");
+        exit(SIGKILL);
+    }
+
     // Go to usermode
     unsafe { usermode(entry, sp, 0, singlestep) }
 }
@@ -941,6 +1005,9 @@ pub fn fexec_kernel(fd: FileHandle, args: Box<[Box<[u8]>]>, vars: Box<[Box<[u8]>
         auxv
     };
 
+    // Frames needed by the new image, at least the TCB and one page of arguments
+    let mut frames = 2;
+
     // We check the validity of all loadable sections here
     for segment in elf.segments() {
         match segment.p_type {
@@ -994,11 +1061,21 @@ pub fn fexec_kernel(fd: FileHandle, args: Box<[Box<[u8]>]>, vars: Box<[Box<[u8]>
                     println!("exec: invalid section address {:X}", segment.p_vaddr);
                     return Err(Error::new(ENOEXEC));
                 }
+
+                frames += (segment.p_memsz as usize + voff + PAGE_SIZE - 1) / PAGE_SIZE;
+            },
+            program_header::PT_TLS => {
+                frames += (segment.p_memsz as usize + PAGE_SIZE - 1) / PAGE_SIZE + 1;
             },
             _ => (),
         }
     }
 
+    // Fail while there is still a process to return to
+    if frames > crate::memory::free_frames() {
+        return Err(Error::new(ENOMEM));
+    }
+
     // This is the point of no return, quite literaly. Any checks for validity need
     // to be done before, and appropriate errors returned. Otherwise, we have nothing
     // to return to.
@@ -1187,11 +1264,13 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
                     if sig != 0 {
                         //TODO: sigprocmask
                         context.pending.push_back(sig as u8);
-                        // Convert stopped processes to blocked if sending SIGCONT
+                        // Continue stopped processes if sending SIGCONT
                         if sig == SIGCONT {
-                            if let context::Status::Stopped(_sig) = context.status {
-                                context.status = context::Status::Blocked;
-                            }
+                            context.cont();
+                        }
+                        // Unblock to handle the pending signal
+                        if context.status == context::Status::Blocked {
+                            context.unblock();
                         }
                     }
                     true
@@ -1281,7 +1360,7 @@ pub fn mprotect(address: usize, size: usize, flags: MapFlags) -> Result<usize> {
             flush_all.flush(&mut active_table);
             return Err(Error::new(EFAULT));
         };
-        if !page_flags.contains(EntryFlags::PRESENT) {
+        if !page_flags.contains(EntryFlags::PRESENT) && !page_flags.contains(EntryFlags::LAZY) {
             flush_all.flush(&mut active_table);
             return Err(Error::new(EFAULT));
         }
@@ -1295,7 +1374,8 @@ pub fn mprotect(address: usize, size: usize, flags: MapFlags) -> Result<usize> {
         if flags.contains(PROT_WRITE) {
             //TODO: Not allowing gain of write privileges
         } else {
-            page_flags.remove(EntryFlags::WRITABLE);
+            // A copy-on-write page would otherwise become writable on the next write fault
+            page_flags.remove(EntryFlags::WRITABLE | EntryFlags::COPY_ON_WRITE);
         }
 
         if flags.contains(PROT_READ) {
@@ -1304,8 +1384,14 @@ pub fn mprotect(address: usize, size: usize, flags: MapFlags) -> Result<usize> {
             //TODO: No flags for readable pages
         }
 
-        let flush = active_table.remap(page, page_flags);
-        flush_all.consume(flush);
+        // Physmaps may use huge pages, which have to be split to change part of them
+        match active_table.try_remap(page, page_flags) {
+            Ok(flush) => flush_all.consume(flush),
+            Err(err) => {
+                flush_all.flush(&mut active_table);
+                return Err(err);
+            }
+        }
     }
 
     flush_all.flush(&mut active_table);
@@ -1,32 +1,104 @@
+use alloc::vec::Vec;
 use core::{mem, slice};
 
+use crate::context;
+use crate::context::memory::{cow_fault, lazy_fault, swap_fault};
+use crate::memory::{self, Frame, FrameFlags};
 use crate::paging::{ActivePageTable, Page, VirtualAddress};
 use crate::paging::entry::EntryFlags;
 use crate::syscall::error::*;
 
+/// Check that `page` is mapped with `flags`, resolving lazy, swapped out, and copy-on-write
+/// pages first, and take a reference to its frame. Returns `None` for pages without a frame,
+/// such as physmaps of device memory
+fn pin(page: Page, flags: EntryFlags) -> Result<Option<Frame>> {
+    loop {
+        let (swapped, lazy) = {
+            let active_table = unsafe { ActivePageTable::new() };
+
+            let mut page_flags = active_table.translate_page_flags(page).ok_or(Error::new(EFAULT))?;
+            let cow = page_flags.contains(EntryFlags::COPY_ON_WRITE) && flags.contains(EntryFlags::WRITABLE);
+            let swapped = page_flags.contains(EntryFlags::SWAPPED);
+            let lazy = page_flags.contains(EntryFlags::LAZY) && ! page_flags.contains(EntryFlags::PRESENT);
+            if cow {
+                page_flags |= EntryFlags::WRITABLE;
+            }
+            if swapped || lazy {
+                page_flags |= EntryFlags::PRESENT;
+            }
+            if ! page_flags.contains(flags) {
+                //println!("{:X}: Not {:?}", page.start_address().get(), flags);
+                return Err(Error::new(EFAULT));
+            }
+
+            if ! (cow || swapped || lazy) {
+                // The page table lock is held, so the page can not be evicted before this
+                let frame = active_table.translate_page(page).filter(|frame| {
+                    memory::descriptor(frame).map_or(false, |descriptor| ! descriptor.flags().contains(FrameFlags::RESERVED))
+                });
+                if let Some(ref frame) = frame {
+                    memory::ref_frame(frame);
+                }
+                return Ok(frame);
+            }
+
+            (swapped, lazy)
+        };
+
+        // The swap scheme has to run to read the page, so the page table lock is released first
+        let address = page.start_address();
+        let resolved = if swapped {
+            swap_fault(address)
+        } else if lazy {
+            lazy_fault(address)
+        } else {
+            cow_fault(address)
+        };
+        if ! resolved {
+            return Err(Error::new(EFAULT));
+        }
+    }
+}
+
+/// Check that a range of user memory is mapped with `flags`. Its frames are pinned until the
+/// syscall returns, so that they are not swapped out while the kernel accesses them
 fn validate(address: usize, size: usize, flags: EntryFlags) -> Result<()> {
     let end_offset = size.checked_sub(1).ok_or(Error::new(EFAULT))?;
     let end_address = address.checked_add(end_offset).ok_or(Error::new(EFAULT))?;
 
-    let active_table = unsafe { ActivePageTable::new() };
+    let mut pinned = Vec::new();
 
     let start_page = Page::containing_address(VirtualAddress::new(address));
     let end_page = Page::containing_address(VirtualAddress::new(end_address));
     for page in Page::range_inclusive(start_page, end_page) {
-        if let Some(page_flags) = active_table.translate_page_flags(page) {
-            if ! page_flags.contains(flags) {
-                //println!("{:X}: Not {:?}", page.start_address().get(), flags);
-                return Err(Error::new(EFAULT));
+        match pin(page, flags) {
+            Ok(Some(frame)) => pinned.push(frame),
+            Ok(None) => (),
+            Err(err) => {
+                unpin(pinned);
+                return Err(err);
             }
-        } else {
-            //println!("{:X}: Not found", page.start_address().get());
-            return Err(Error::new(EFAULT));
+        }
+    }
+
+    if ! pinned.is_empty() {
+        let contexts = context::contexts();
+        match contexts.current() {
+            Some(context_lock) => context_lock.write().pinned.extend(pinned),
+            None => unpin(pinned),
         }
     }
 
     Ok(())
 }
 
+/// Release the frames pinned by `validate`
+pub fn unpin(frames: Vec<Frame>) {
+    for frame in frames {
+        memory::unref_frame(frame);
+    }
+}
+
 /// Convert a pointer and length to slice, if valid
 //TODO: Mark unsafe
 pub fn validate_slice<T>(ptr: *const T, len: usize) -> Result<&'static [T]> {
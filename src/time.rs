@@ -1,14 +1,69 @@
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 
 /// Kernel start time, measured in (seconds, nanoseconds) since Unix epoch
 pub static START: Mutex<(u64, u64)> = Mutex::new((0, 0));
 /// Kernel up time, measured in (seconds, nanoseconds) since `START_TIME`
 pub static OFFSET: Mutex<(u64, u64)> = Mutex::new((0, 0));
+/// Clock source used for high resolution time, if one was installed
+static CLOCK_SOURCE: RwLock<Option<ClockSource>> = RwLock::new(None);
+
+/// A free running counter with a known frequency, used to read the time between timer ticks
+pub struct ClockSource {
+    /// Read the counter
+    read: fn() -> u64,
+    /// Nanoseconds per count, as a 32.32 fixed point number
+    mult: u64,
+    /// Counter value when the clock source was installed
+    base_count: u64,
+    /// Kernel up time when the clock source was installed, in nanoseconds
+    base_ns: u64,
+}
+
+impl ClockSource {
+    fn nanoseconds(&self) -> u64 {
+        let delta = (self.read)().wrapping_sub(self.base_count);
+        self.base_ns + ((u128::from(delta) * u128::from(self.mult)) >> 32) as u64
+    }
+}
+
+/// Use a counter running at `frequency` Hz for `monotonic` and `realtime`. The counter takes
+/// over from the tick count in `OFFSET` at the time of the call, so the clock does not jump
+pub fn set_clock_source(read: fn() -> u64, frequency: u64) {
+    let mut clock_source = CLOCK_SOURCE.write();
+
+    let offset = *OFFSET.lock();
+    let base_ns = offset.0 * 1_000_000_000 + offset.1;
+    let mult = ((1_000_000_000u128 << 32) / u128::from(frequency)) as u64;
+
+    *clock_source = Some(ClockSource {
+        read,
+        mult,
+        base_count: read(),
+        base_ns,
+    });
+}
+
+/// Returns true if time is read from a clock source, so that it no longer depends on the timer
+/// ticks counted in `OFFSET`
+pub fn has_clock_source() -> bool {
+    CLOCK_SOURCE.read().is_some()
+}
 
 pub fn monotonic() -> (u64, u64) {
+    if let Some(ref clock_source) = *CLOCK_SOURCE.read() {
+        let ns = clock_source.nanoseconds();
+        return (ns / 1_000_000_000, ns % 1_000_000_000);
+    }
+
     *OFFSET.lock()
 }
 
+/// Monotonic time in nanoseconds
+pub fn monotonic_ns() -> u64 {
+    let (seconds, nanoseconds) = monotonic();
+    seconds * 1_000_000_000 + nanoseconds
+}
+
 pub fn realtime() -> (u64, u64) {
     let offset = monotonic();
     let start = *START.lock();